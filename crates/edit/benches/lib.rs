@@ -3,12 +3,13 @@
 
 use std::hint::black_box;
 use std::io::Cursor;
+use std::mem::MaybeUninit;
 use std::{mem, vec};
 
 use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
 use edit::helpers::*;
 use edit::simd::MemsetSafe;
-use edit::{buffer, hash, oklab, simd, unicode};
+use edit::{buffer, hash, icu, oklab, simd, unicode};
 use serde::Deserialize;
 use stdext::arena;
 
@@ -126,6 +127,62 @@ fn bench_hash(c: &mut Criterion) {
         });
 }
 
+fn bench_icu(c: &mut Criterion) {
+    let reference = "Lorem ipsum dolor sit amet, 月明かりが静かに照らし出し 🦀\n".repeat(64 * KIBI);
+    let le: Vec<u8> = reference.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let be: Vec<u8> = reference.encode_utf16().flat_map(u16::to_be_bytes).collect();
+    // Offsetting the input by one byte makes it misaligned, which forces the pivot buffer path.
+    let le_unaligned = [&[0][..], &le].concat();
+
+    let mut pivot = vec![MaybeUninit::<u16>::uninit(); 4 * KIBI];
+    let mut output = vec![MaybeUninit::<u8>::uninit(); 4 * KIBI];
+    let mut convert = |encoding: &str, input: &[u8]| {
+        let mut c = icu::Converter::new(&mut pivot, encoding, "UTF-8").unwrap();
+        let mut read = 0;
+        while read < input.len() {
+            let (r, _) = c.convert(black_box(&input[read..]), &mut output).unwrap();
+            read += r;
+        }
+    };
+
+    c.benchmark_group("icu::Converter")
+        .throughput(Throughput::Bytes(le.len() as u64))
+        .bench_function("UTF-16LE", |b| b.iter(|| convert("UTF-16LE", &le)))
+        .bench_function("UTF-16BE", |b| b.iter(|| convert("UTF-16BE", &be)))
+        .bench_function("UTF-16LE unaligned", |b| {
+            b.iter(|| convert("UTF-16LE", &le_unaligned[1..]))
+        });
+
+    let mut tb = buffer::TextBuffer::new(false).unwrap();
+    tb.write_raw(reference.as_bytes());
+    let text = icu::Text::from_buffer(&tb);
+    let count_matches = |pattern: &str| {
+        let regex = icu::Regex::new(black_box(pattern), 0, &text).unwrap();
+        black_box(regex.count_matches())
+    };
+
+    c.benchmark_group("icu::Regex")
+        .throughput(Throughput::Bytes(reference.len() as u64))
+        .bench_function("literal", |b| b.iter(|| count_matches("dolor sit")))
+        // The first byte is common, but the literal never matches.
+        .bench_function("literal, common prefix", |b| b.iter(|| count_matches(" ipsum dolor!")))
+        .bench_function("regex", |b| b.iter(|| count_matches("dolor s[aeiou]t")));
+
+    let identifiers: Vec<_> = (0..4 * KIBI).map(|i| format!("SomeLongIdentifier_{i}")).collect();
+    let identifiers_len: usize = identifiers.iter().map(String::len).sum();
+
+    c.benchmark_group("icu::fold_case")
+        .throughput(Throughput::Bytes(identifiers_len as u64))
+        .bench_function("ASCII identifiers", |b| {
+            b.iter(|| {
+                let scratch = arena::scratch_arena(None);
+                for id in &identifiers {
+                    black_box(icu::fold_case(&scratch, black_box(id)));
+                }
+            })
+        });
+}
+
 fn bench_oklab(c: &mut Criterion) {
     c.benchmark_group("oklab")
         .bench_function("StraightRgba::as_oklab", |b| {
@@ -232,6 +289,7 @@ fn bench(c: &mut Criterion) {
 
     bench_buffer(c);
     bench_hash(c);
+    bench_icu(c);
     bench_oklab(c);
     bench_simd_lines_fwd(c);
     bench_simd_memchr2(c);
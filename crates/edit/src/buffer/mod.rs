@@ -113,9 +113,10 @@ struct ActiveSearch {
     pattern: String,
     /// The search options.
     options: SearchOptions,
-    /// The ICU `UText` object.
-    text: icu::Text,
-    /// The ICU `URegularExpression` object.
+    /// The searched snapshot of the buffer contents. It's detached from the buffer
+    /// in between searches, since it can't borrow the buffer that owns it.
+    text: icu::Text<'static>,
+    /// The compiled search pattern.
     regex: icu::Regex,
     /// [`GapBuffer::generation`] when the search was created.
     /// This is used to detect if we need to refresh the
@@ -888,7 +889,8 @@ impl TextBuffer {
             // Remove the BOM from the file, if this is the first chunk.
             // Our caller ensures to only call us once the BOM has been identified,
             // which means that if there's a BOM it must be wholly contained in this chunk.
-            if off == 0 {
+            // The converter already takes care of this for "UTF-8 BOM".
+            if off == 0 && self.encoding != "UTF-8 BOM" {
                 let written = &mut gap[..output_advance];
                 if written.starts_with(b"\xEF\xBB\xBF") {
                     written.copy_within(3.., 0);
@@ -935,10 +937,8 @@ impl TextBuffer {
     pub fn write_file(&mut self, file: &mut File) -> apperr::Result<()> {
         let mut offset = 0;
 
-        if self.encoding.starts_with("UTF-8") {
-            if self.encoding == "UTF-8 BOM" {
-                file.write_all(b"\xEF\xBB\xBF")?;
-            }
+        // "UTF-8 BOM" goes through the converter, which takes care of emitting the BOM.
+        if self.encoding == "UTF-8" {
             loop {
                 let chunk = self.read_forward(offset);
                 if chunk.is_empty() {
@@ -1195,58 +1195,32 @@ impl TextBuffer {
         options: SearchOptions,
     ) -> apperr::Result<ActiveSearch> {
         if pattern.is_empty() {
-            return Err(apperr::Error::Icu(1)); // U_ILLEGAL_ARGUMENT_ERROR
-        }
-
-        let sanitized_pattern = if options.whole_word && options.use_regex {
-            Cow::Owned(format!(r"\b(?:{pattern})\b"))
-        } else if options.whole_word {
-            let mut p = String::with_capacity(pattern.len() + 16);
-            p.push_str(r"\b");
-
-            // Escape regex special characters.
-            let b = unsafe { p.as_mut_vec() };
-            for &byte in pattern.as_bytes() {
-                match byte {
-                    b'*' | b'?' | b'+' | b'[' | b'(' | b')' | b'{' | b'}' | b'^' | b'$' | b'|'
-                    | b'\\' | b'.' => {
-                        b.push(b'\\');
-                        b.push(byte);
-                    }
-                    _ => b.push(byte),
-                }
-            }
+            return Err(apperr::Error::new_icu(icu::IcuError::InvalidPattern.code()));
+        }
 
-            p.push_str(r"\b");
-            Cow::Owned(p)
-        } else {
-            Cow::Borrowed(pattern)
+        let regex_options = icu::RegexOptions {
+            case_insensitive: !options.match_case,
+            multi_line: true,
+            literal: !options.use_regex,
+            whole_word: options.whole_word,
+            crlf: self.newlines_are_crlf,
+            ..Default::default()
         };
 
-        let mut flags = icu::Regex::MULTILINE;
-        if !options.match_case {
-            flags |= icu::Regex::CASE_INSENSITIVE;
-        }
-        if !options.use_regex && !options.whole_word {
-            flags |= icu::Regex::LITERAL;
-        }
-
         // Move the start of the search to the start of the selection,
         // or otherwise to the current cursor position.
 
-        let text = unsafe { icu::Text::new(self)? };
-        let mut regex = unsafe { icu::Regex::new(&sanitized_pattern, flags, &text)? };
+        let text = icu::Text::from_buffer(self);
+        let mut regex = icu::Regex::with_options(pattern, &regex_options, &text)?;
 
         let mut matches = Vec::new();
         regex.reset(0);
-        while let Some(range) = regex.next() {
-            matches.push(range);
-        }
+        matches.extend(&mut regex);
 
         Ok(ActiveSearch {
             pattern: pattern.to_string(),
             options,
-            text,
+            text: text.detach(),
             regex,
             buffer_generation: self.buffer.generation(),
             selection_generation: 0,
@@ -1257,14 +1231,14 @@ impl TextBuffer {
 
     fn find_select_next(&mut self, search: &mut ActiveSearch, offset: usize, wrap: bool) {
         if search.buffer_generation != self.buffer.generation() {
-            unsafe { search.regex.set_text(&mut search.text, offset) };
+            let mut text = mem::take(&mut search.text).attach(self);
+            search.regex.set_text(&mut text, offset);
+            search.text = text.detach();
             search.buffer_generation = self.buffer.generation();
             
             search.matches.clear();
             search.regex.reset(0);
-            while let Some(range) = search.regex.next() {
-                search.matches.push(range);
-            }
+            search.matches.extend(&mut search.regex);
             search.current_match_index = None;
         } 
         
@@ -2871,6 +2845,16 @@ impl TextBuffer {
     }
 }
 
+impl ReadableDocument for TextBuffer {
+    fn read_forward(&self, off: usize) -> &[u8] {
+        self.buffer.read_forward(off)
+    }
+
+    fn read_backward(&self, off: usize) -> &[u8] {
+        self.buffer.read_backward(off)
+    }
+}
+
 pub enum Bom {
     None,
     UTF8,
@@ -13,6 +13,40 @@ use crate::icu;
 
 const NO_MATCH: i32 = 0;
 
+/// The result of a successful [`fuzzy_match`]. Higher scores are better matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FuzzyScore {
+    pub score: i32,
+}
+
+/// Checks whether the chars of `needle` appear in order in `haystack`, ignoring case,
+/// such that "gtd" matches "GoToDefinition". Meant for command palettes and file pickers.
+///
+/// The score is the one of [`score_fuzzy`]. The byte offsets of the matched chars in `haystack`
+/// are appended to `offsets`, for highlighting them. Nothing is appended if there's no match.
+pub fn fuzzy_match(needle: &str, haystack: &str, offsets: &mut Vec<usize>) -> Option<FuzzyScore> {
+    if needle.is_empty() {
+        return Some(FuzzyScore { score: 0 });
+    }
+
+    let scratch = scratch_arena(None);
+    let (score, positions) = score_fuzzy(&scratch, haystack, needle, true);
+    if score == NO_MATCH {
+        return None;
+    }
+
+    // `positions` are ascending char indices.
+    let mut chars = haystack.char_indices();
+    let mut next = 0;
+    for &pos in &positions {
+        let (offset, _) = chars.nth(pos - next)?;
+        offsets.push(offset);
+        next = pos + 1;
+    }
+
+    Some(FuzzyScore { score })
+}
+
 pub fn score_fuzzy<'a>(
     arena: &'a Arena,
     haystack: &str,
@@ -33,10 +67,10 @@ pub fn score_fuzzy<'a>(
         return (NO_MATCH, Vec::new_in(arena));
     }
 
-    let target_lower = icu::fold_case(&scratch, haystack);
-    let query_lower = icu::fold_case(&scratch, needle);
-    let target_lower = map_chars(&scratch, &target_lower);
-    let query_lower = map_chars(&scratch, &query_lower);
+    // The folded chars are indexed in parallel with the original ones,
+    // so this must not use a folding that changes the length, like "ß" to "ss".
+    let target_lower = map_chars_folded(&scratch, &target);
+    let query_lower = map_chars_folded(&scratch, &query);
 
     let area = query.len() * target.len();
     let mut scores = vec::from_elem_in(0, area, &*scratch);
@@ -220,3 +254,64 @@ fn map_chars<'a>(arena: &'a Arena, s: &str) -> Vec<char, &'a Arena> {
     chars.shrink_to_fit();
     chars
 }
+
+fn map_chars_folded<'a>(arena: &'a Arena, chars: &[char]) -> Vec<char, &'a Arena> {
+    let mut folded = Vec::with_capacity_in(chars.len(), arena);
+    folded.extend(chars.iter().map(|&ch| icu::fold_char_simple(ch)));
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use stdext::arena;
+
+    use super::*;
+
+    #[test]
+    fn test_fuzzy() {
+        // This is the only test that needs the scratch arenas,
+        // which can't be used from several threads at once.
+        arena::init(16 * 1024 * 1024).unwrap();
+        let arena = Arena::new(64 * 1024).unwrap();
+
+        let (score, positions) = score_fuzzy(&arena, "foo_bar", "fb", true);
+        assert!(score > 0);
+        assert_eq!(positions, [0, 4]);
+
+        // Chars whose full case folding is longer, like "ß" to "ss",
+        // must not shift the positions of the chars after them.
+        let (score, positions) = score_fuzzy(&arena, "straße", "E", true);
+        assert!(score > 0);
+        assert_eq!(positions, [5]);
+        let (score, positions) = score_fuzzy(&arena, "İstanbul", "bul", false);
+        assert!(score > 0);
+        assert_eq!(positions, [5, 6, 7]);
+
+        let mut offsets = Vec::new();
+        assert!(fuzzy_match("gtd", "GoToDefinition", &mut offsets).is_some());
+        assert_eq!(offsets, [0, 2, 4]);
+
+        // Like in VS Code, the start of the text outweighs a consecutive match.
+        offsets.clear();
+        assert!(fuzzy_match("ab", "a_xab", &mut offsets).is_some());
+        assert_eq!(offsets, [0, 4]);
+
+        // Non-ASCII chars are matched case-insensitively and reported by their byte offset.
+        offsets.clear();
+        assert!(fuzzy_match("öf", "Größe Öffnen", &mut offsets).is_some());
+        assert_eq!(offsets, [8, 10]);
+
+        offsets.clear();
+        assert_eq!(fuzzy_match("dtg", "GoToDefinition", &mut offsets), None);
+        assert!(offsets.is_empty());
+        assert_eq!(fuzzy_match("x", "", &mut offsets), None);
+        assert_eq!(fuzzy_match("", "abc", &mut offsets), Some(FuzzyScore { score: 0 }));
+        assert!(offsets.is_empty());
+
+        // Consecutive matches beat scattered ones, word starts beat the middle of words.
+        let score = |haystack| fuzzy_match("foo", haystack, &mut Vec::new()).unwrap();
+        assert!(score("foobar") > score("f_o_o_bar"));
+        assert!(score("x_foo") > score("xfoo"));
+        assert!(score("foo") > score("xfoo"));
+    }
+}
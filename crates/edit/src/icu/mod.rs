@@ -0,0 +1,6875 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Replacement for ICU library bindings using native Rust.
+//! Includes a "Full" mode using the regex crate, and a "Lite" mode using standard string search.
+
+mod tables;
+
+use std::borrow::Cow;
+use std::cell::{OnceCell, RefCell};
+use std::cmp::Ordering;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::OnceLock;
+
+use stdext::arena::{Arena, ArenaString};
+
+use crate::apperr;
+use crate::buffer::TextBuffer;
+use crate::document::ReadableDocument;
+use crate::simd::{lines_fwd, memchr2};
+use crate::unicode::{MeasurementConfig, Utf8Chars};
+
+#[derive(Clone, Copy)]
+pub struct Encoding {
+    pub label: &'static str,
+    pub canonical: &'static str,
+}
+
+pub struct Encodings {
+    pub preferred: &'static [Encoding],
+    pub all: &'static [Encoding],
+}
+
+static ENCODINGS: Encodings = Encodings {
+    preferred: &[
+        Encoding { label: "UTF-8", canonical: "UTF-8" },
+        Encoding { label: "UTF-8 BOM", canonical: "UTF-8 BOM" },
+        Encoding { label: "UTF-16 LE", canonical: "UTF-16LE" },
+        Encoding { label: "UTF-16 BE", canonical: "UTF-16BE" },
+    ],
+    all: &[
+        Encoding { label: "UTF-8", canonical: "UTF-8" },
+        Encoding { label: "UTF-8 BOM", canonical: "UTF-8 BOM" },
+        Encoding { label: "UTF-16 LE", canonical: "UTF-16LE" },
+        Encoding { label: "UTF-16 BE", canonical: "UTF-16BE" },
+        Encoding { label: "UTF-32 LE", canonical: "UTF-32LE" },
+        Encoding { label: "UTF-32 BE", canonical: "UTF-32BE" },
+        Encoding { label: "Windows-1252", canonical: "windows-1252" },
+        Encoding { label: "GB18030", canonical: "gb18030" },
+        Encoding { label: "Shift-JIS", canonical: "shift_jis" },
+        Encoding { label: "EUC-KR", canonical: "euc-kr" },
+    ],
+};
+
+pub fn get_available_encodings() -> &'static Encodings {
+    &ENCODINGS
+}
+
+/// Finds an encoding by its canonical name or label, ignoring case.
+///
+/// Returns `None` for unknown names, in which case the caller should fall back to a default.
+pub fn encoding_from_canonical(name: &str) -> Option<&'static Encoding> {
+    let all = ENCODINGS.all;
+    all.iter()
+        .find(|enc| enc.canonical.eq_ignore_ascii_case(name))
+        .or_else(|| all.iter().find(|enc| enc.label.eq_ignore_ascii_case(name)))
+}
+
+/// Returns the maximum number of bytes a single character can take up in `encoding`,
+/// for sizing the output buffers passed to [`Converter::convert`].
+///
+/// Unknown encodings get a conservative 4 bytes, the most any supported encoding needs.
+pub fn max_bytes_per_char(encoding: &Encoding) -> usize {
+    Charset::from_name(encoding.canonical).map_or(4, Charset::max_bytes_per_char)
+}
+
+/// Checks whether `text` can be saved in `encoding` without losing any characters,
+/// for instance to warn about it before saving a file as Windows-1252.
+///
+/// Returns the byte offsets and characters that `encoding` can't represent otherwise.
+/// The Unicode encodings and GB18030 can represent everything and always return `Ok`.
+pub fn can_encode_losslessly(text: &str, encoding: &Encoding) -> Result<(), Vec<(usize, char)>> {
+    let Some(charset) = Charset::from_name(encoding.canonical) else {
+        return Ok(());
+    };
+    let lost: Vec<_> = text.char_indices().filter(|&(_, ch)| !charset.can_represent(ch)).collect();
+    if lost.is_empty() { Ok(()) } else { Err(lost) }
+}
+
+/// The groups of [`encodings_for_menu`], in the order they're listed.
+const ENCODING_GROUPS: [&str; 4] = ["Unicode", "Western", "East Asian", "Other"];
+
+fn encoding_group(enc: &Encoding) -> &'static str {
+    match enc.canonical {
+        c if c.starts_with("UTF-") => "Unicode",
+        "windows-1252" => "Western",
+        "gb18030" | "shift_jis" | "euc-kr" => "East Asian",
+        _ => "Other",
+    }
+}
+
+/// Returns the encodings for an encoding picker as a list of `(group, encodings)`.
+///
+/// The first group is "Preferred" and contains [`Encodings::preferred`] in their original order.
+/// The remaining encodings are grouped by family and sorted by label. Empty groups are omitted.
+pub fn encodings_for_menu() -> Vec<(&'static str, Vec<&'static Encoding>)> {
+    let mut groups = Vec::with_capacity(1 + ENCODING_GROUPS.len());
+    groups.push(("Preferred", ENCODINGS.preferred.iter().collect()));
+
+    for group in ENCODING_GROUPS {
+        let mut list: Vec<_> = ENCODINGS
+            .all
+            .iter()
+            .filter(|enc| encoding_group(enc) == group)
+            .filter(|enc| !ENCODINGS.preferred.iter().any(|p| p.canonical == enc.canonical))
+            .collect();
+        if !list.is_empty() {
+            list.sort_by_key(|enc| enc.label);
+            groups.push((group, list));
+        }
+    }
+
+    groups
+}
+
+/// Detects the encoding of `bytes` based on its byte order mark.
+///
+/// Returns `None` if there's no BOM, in which case the caller should fall back to a default.
+pub fn detect_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    let canonical = if bytes.starts_with(b"\xEF\xBB\xBF") {
+        "UTF-8 BOM"
+    } else if bytes.starts_with(b"\x00\x00\xFE\xFF") {
+        "UTF-32BE"
+    } else if bytes.starts_with(b"\xFF\xFE\x00\x00") {
+        // Could also be UTF-16LE text starting with U+0000, which is far less likely.
+        "UTF-32LE"
+    } else if bytes.starts_with(b"\xFF\xFE") {
+        "UTF-16LE"
+    } else if bytes.starts_with(b"\xFE\xFF") {
+        "UTF-16BE"
+    } else {
+        return None;
+    };
+    ENCODINGS.all.iter().find(|enc| enc.canonical == canonical)
+}
+
+/// How much of the start of a file [`sniff_encoding`] looks at.
+const SNIFF_LEN: usize = 64 * 1024;
+
+/// Guesses the encoding of `bytes`, for instance to open a file without a BOM.
+///
+/// The BOM is checked first, see [`detect_encoding`]. Without one, the first 64 KiB are examined:
+/// * Regularly placed zero bytes are taken as UTF-16 or UTF-32, which only works
+///   for text that is mostly ASCII. UTF-16 text without any ASCII in it isn't recognized.
+/// * Valid UTF-8 is taken as UTF-8, which includes plain ASCII.
+/// * Otherwise, the legacy encodings are scored by how typical the decoded text is:
+///   Accented letters next to ASCII letters for Windows-1252, and kana, Hangul or Han
+///   characters for Shift-JIS, EUC-KR and GB18030, respectively, if it decodes without errors.
+///   Short texts can be ambiguous, as these encodings share many byte sequences.
+///   Windows-1252 wins ties and is the fallback, as it can decode any byte.
+///
+/// This is merely a best guess and can be wrong, so the user should be able to override it.
+pub fn sniff_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some(enc) = detect_encoding(bytes) {
+        return enc;
+    }
+
+    let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+    let canonical = sniff_utf16_utf32(sample)
+        .or_else(|| match str::from_utf8(sample) {
+            // The sample may end in the middle of a character.
+            Err(err) if err.error_len().is_some() => None,
+            _ => Some("UTF-8"),
+        })
+        .or_else(|| sniff_legacy(sample))
+        .unwrap_or("windows-1252");
+    ENCODINGS.all.iter().find(|enc| enc.canonical == canonical).unwrap()
+}
+
+/// Looks for the zero bytes that mostly ASCII text has in UTF-16 and UTF-32.
+fn sniff_utf16_utf32(sample: &[u8]) -> Option<&'static str> {
+    let units = sample.len() / 4;
+    let count = |f: fn(&[u8; 4]) -> bool| sample.as_chunks::<4>().0.iter().filter(|u| f(u)).count();
+    if units > 0 {
+        if count(|u| u[2] == 0 && u[3] == 0 && u[..2] != [0, 0]) * 4 >= units * 3 {
+            return Some("UTF-32LE");
+        }
+        if count(|u| u[0] == 0 && u[1] == 0 && u[2..] != [0, 0]) * 4 >= units * 3 {
+            return Some("UTF-32BE");
+        }
+    }
+
+    let pairs = sample.len() / 2;
+    let (mut even, mut odd) = (0, 0);
+    for pair in sample.as_chunks::<2>().0 {
+        match pair {
+            [0, 0] => {}
+            [0, _] => even += 1,
+            [_, 0] => odd += 1,
+            _ => {}
+        }
+    }
+    if odd * 4 >= pairs && odd > even * 4 {
+        Some("UTF-16LE")
+    } else if even * 4 >= pairs && even > odd * 4 {
+        Some("UTF-16BE")
+    } else {
+        None
+    }
+}
+
+/// Picks a legacy encoding for text that isn't valid UTF-8, see [`sniff_encoding`].
+fn sniff_legacy(sample: &[u8]) -> Option<&'static str> {
+    fn is_han(c: u32) -> bool {
+        (0x4E00..=0x9FFF).contains(&c) || (0x3400..=0x4DBF).contains(&c)
+    }
+    // Common Hangul and Han characters are in the original EUC range of both encodings.
+    // The extensions around it mostly contain rare ones, but they overlap with Shift-JIS.
+    fn is_euc(seq: &[u8]) -> bool {
+        seq.iter().all(|&b| b >= 0xA1)
+    }
+    type Candidate = (&'static str, fn(&[u8]) -> Decoded, fn(u32, &[u8]) -> bool);
+    let candidates: [Candidate; 3] = [
+        ("shift_jis", decode_shift_jis, |c, _| (0x3041..=0x30FF).contains(&c) || is_han(c)),
+        ("euc-kr", decode_euc_kr, |c, seq| (0xAC00..=0xD7A3).contains(&c) && is_euc(seq)),
+        ("gb18030", decode_gb18030, |c, seq| is_han(c) && is_euc(seq)),
+    ];
+
+    // Accented letters in Western text are almost always next to an ASCII letter,
+    // while CJK characters mostly come in runs. Both are scored per byte, but such
+    // a letter is stronger evidence than a CJK character, so it's counted twice.
+    // Curly quotes and dashes are common enough to count anywhere.
+    let is_letter = |i: usize| sample.get(i).is_some_and(u8::is_ascii_alphabetic);
+    let mut best = None;
+    let mut best_score = (0..sample.len())
+        .map(|i| match sample[i] {
+            0x91..=0x97 => 1,
+            0xD7 | 0xF7 => 0,
+            0xC0..=0xFF if is_letter(i + 1) || i.checked_sub(1).is_some_and(is_letter) => 2,
+            _ => 0,
+        })
+        .sum();
+
+    for (canonical, decode, is_typical) in candidates {
+        if let Some(score) = sniff_score(sample, decode, is_typical)
+            && score > best_score
+        {
+            best = Some(canonical);
+            best_score = score;
+        }
+    }
+    best
+}
+
+/// Counts the bytes of the typical characters of an encoding in `sample`,
+/// or returns `None` if it contains sequences that are invalid in it.
+fn sniff_score(
+    sample: &[u8],
+    decode: fn(&[u8]) -> Decoded,
+    is_typical: fn(u32, &[u8]) -> bool,
+) -> Option<usize> {
+    let mut score = 0;
+    let mut rest = sample;
+    while !rest.is_empty() {
+        match decode(rest) {
+            Decoded::Char(ch, len) => {
+                if is_typical(ch as u32, &rest[..len]) {
+                    score += len;
+                }
+                rest = &rest[len..];
+            }
+            Decoded::Invalid(_) => return None,
+            // The sample may end in the middle of a character.
+            Decoded::Incomplete => break,
+        }
+    }
+    Some(score)
+}
+
+/// The errors this module returns via [`apperr::Error::new_icu`].
+/// Their codes match the corresponding `UErrorCode` values of ICU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcuError {
+    /// `U_ILLEGAL_ARGUMENT_ERROR`: An invalid search pattern.
+    InvalidPattern,
+    /// `U_BUFFER_OVERFLOW_ERROR`: The output is too small for even a single character.
+    BufferOverflow,
+    /// `U_UNSUPPORTED_ERROR`: A conversion between unsupported encodings.
+    UnsupportedEncoding,
+    /// `U_INVALID_CHAR_FOUND`: A character that can't be represented in the target encoding.
+    InvalidChar,
+    /// `U_REGEX_PROPERTY_SYNTAX`: A search pattern with an unknown `\p{...}` Unicode property.
+    UnknownProperty,
+}
+
+impl IcuError {
+    const ALL: [Self; 5] = [
+        Self::InvalidPattern,
+        Self::BufferOverflow,
+        Self::UnsupportedEncoding,
+        Self::InvalidChar,
+        Self::UnknownProperty,
+    ];
+
+    pub const fn code(self) -> u32 {
+        match self {
+            Self::InvalidPattern => 1,
+            Self::BufferOverflow => 15,
+            Self::UnsupportedEncoding => 16,
+            Self::InvalidChar => 10,
+            Self::UnknownProperty => 0x10304,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.code() == code)
+    }
+}
+
+pub fn apperr_format(f: &mut std::fmt::Formatter<'_>, code: u32) -> std::fmt::Result {
+    match IcuError::from_code(code) {
+        Some(IcuError::InvalidPattern) => f.write_str("Invalid search pattern"),
+        Some(IcuError::BufferOverflow) => f.write_str("Conversion output buffer too small"),
+        Some(IcuError::UnsupportedEncoding) => f.write_str("Unsupported encoding conversion"),
+        Some(IcuError::InvalidChar) => f.write_str("Character not supported by the encoding"),
+        Some(IcuError::UnknownProperty) => f.write_str("Unknown Unicode property"),
+        None => write!(f, "ICU Error {code:#08x}"),
+    }
+}
+
+pub fn init() -> apperr::Result<()> {
+    Ok(())
+}
+
+/// Whether this build has the "Full" mode [`Regex`] with support for regular expressions
+/// and capture groups. The "Lite" mode only searches for literal text, so the UI should
+/// hide or disable the options that need a regex engine.
+pub const fn is_full_mode() -> bool {
+    cfg!(feature = "regex")
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Compares two encoding names, ignoring case and anything but ASCII letters and digits,
+/// so that "utf8", "UTF-8" and "UTF_8" are all considered equal.
+fn encoding_names_eq(a: &str, b: &str) -> bool {
+    fn normalize(s: &str) -> impl Iterator<Item = u8> + '_ {
+        s.bytes().filter(u8::is_ascii_alphanumeric).map(|b| b.to_ascii_uppercase())
+    }
+    normalize(a).eq(normalize(b))
+}
+
+/// The character sets a [`Converter`] can transcode between.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Charset {
+    Utf8,
+    Utf16LE,
+    Utf16BE,
+    Utf32LE,
+    Utf32BE,
+    Windows1252,
+    Gb18030,
+    ShiftJis,
+    EucKr,
+}
+
+impl Charset {
+    const NAMES: [(&str, Self); 10] = [
+        ("UTF-8", Self::Utf8),
+        ("UTF-8 BOM", Self::Utf8),
+        ("UTF-16LE", Self::Utf16LE),
+        ("UTF-16BE", Self::Utf16BE),
+        ("UTF-32LE", Self::Utf32LE),
+        ("UTF-32BE", Self::Utf32BE),
+        ("windows-1252", Self::Windows1252),
+        ("gb18030", Self::Gb18030),
+        ("shift_jis", Self::ShiftJis),
+        ("euc-kr", Self::EucKr),
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().find(|(n, _)| encoding_names_eq(n, name)).map(|&(_, charset)| charset)
+    }
+
+    /// Whether text can be converted into this character set. Some are decode-only for now.
+    fn can_encode(self) -> bool {
+        !matches!(
+            self,
+            Self::Utf32LE | Self::Utf32BE | Self::Gb18030 | Self::ShiftJis | Self::EucKr
+        )
+    }
+
+    /// Whether `ch` has a representation in this character set.
+    fn can_represent(self, ch: char) -> bool {
+        let c = ch as u32;
+        match self {
+            Self::Utf8 | Self::Utf16LE | Self::Utf16BE | Self::Utf32LE | Self::Utf32BE => true,
+            Self::Gb18030 => true,
+            Self::Windows1252 => unicode_to_windows1252(ch).is_some(),
+            Self::ShiftJis => {
+                static CHARS: OnceLock<Vec<u16>> = OnceLock::new();
+                c <= 0x80
+                    || (0xFF61..=0xFF9F).contains(&c)
+                    || table_contains(&CHARS, &tables::SHIFT_JIS_TWO_BYTE, c)
+            }
+            Self::EucKr => {
+                static CHARS: OnceLock<Vec<u16>> = OnceLock::new();
+                ch.is_ascii() || table_contains(&CHARS, &tables::EUC_KR_TWO_BYTE, c)
+            }
+        }
+    }
+
+    /// The maximum number of bytes a single character can take up in this character set.
+    fn max_bytes_per_char(self) -> usize {
+        match self {
+            Self::Utf8 | Self::Utf16LE | Self::Utf16BE | Self::Gb18030 => 4,
+            Self::Utf32LE | Self::Utf32BE => 4,
+            Self::Windows1252 => 1,
+            Self::ShiftJis | Self::EucKr => 2,
+        }
+    }
+
+    /// The maximum number of bytes a single UTF-16 code unit in the pivot
+    /// buffer can turn into when written out in this character set.
+    fn max_bytes_per_unit(self) -> usize {
+        match self {
+            Self::Utf8 => 3,
+            Self::Utf16LE | Self::Utf16BE => 2,
+            Self::Utf32LE | Self::Utf32BE => 4,
+            Self::Windows1252 => 1,
+            Self::Gb18030 => 4,
+            Self::ShiftJis | Self::EucKr => 2,
+        }
+    }
+}
+
+/// Whether `table` maps any code to `c`, using a sorted copy of it that's built on first use.
+fn table_contains(sorted: &OnceLock<Vec<u16>>, table: &[u16], c: u32) -> bool {
+    let sorted = sorted.get_or_init(|| {
+        let mut sorted = table.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        sorted
+    });
+    u16::try_from(c).is_ok_and(|c| sorted.binary_search(&c).is_ok())
+}
+
+/// The line ending style of a document, see [`Converter::line_ending`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Only `\n`. Also used for text without any line breaks.
+    #[default]
+    Lf,
+    /// Only `\r\n`.
+    CrLf,
+    /// Only a lone `\r`, as used by classic Mac OS.
+    Cr,
+    /// More than one of the above.
+    Mixed,
+}
+
+/// Tallies the line endings of text that's fed to it in chunks.
+#[derive(Default)]
+struct LineEndingStats {
+    lf: usize,
+    crlf: usize,
+    cr: usize,
+    /// The previous chunk ended in a `\r`, which may be the first half of a `\r\n`.
+    pending_cr: bool,
+}
+
+impl LineEndingStats {
+    fn update(&mut self, bytes: &[u8]) {
+        let mut off = 0;
+
+        if self.pending_cr && !bytes.is_empty() {
+            self.pending_cr = false;
+            if bytes[0] == b'\n' {
+                self.crlf += 1;
+                off = 1;
+            } else {
+                self.cr += 1;
+            }
+        }
+
+        loop {
+            off = memchr2(b'\r', b'\n', bytes, off);
+            if off >= bytes.len() {
+                break;
+            }
+            if bytes[off] == b'\n' {
+                self.lf += 1;
+                off += 1;
+            } else if off + 1 == bytes.len() {
+                self.pending_cr = true;
+                off += 1;
+            } else if bytes[off + 1] == b'\n' {
+                self.crlf += 1;
+                off += 2;
+            } else {
+                self.cr += 1;
+                off += 1;
+            }
+        }
+    }
+
+    fn line_ending(&self) -> LineEnding {
+        let cr = self.cr + self.pending_cr as usize;
+        match (self.lf, self.crlf, cr) {
+            (_, 0, 0) => LineEnding::Lf,
+            (0, _, 0) => LineEnding::CrLf,
+            (0, 0, _) => LineEnding::Cr,
+            _ => LineEnding::Mixed,
+        }
+    }
+}
+
+/// The size of the pivot buffer that [`Converter::without_pivot`] brings along.
+const INLINE_PIVOT_LEN: usize = 64;
+
+/// The pivot buffer of a [`Converter`]: Either provided by the caller, or a small built-in one.
+enum Pivot<'a> {
+    Borrowed(&'a mut [MaybeUninit<u16>]),
+    Inline([MaybeUninit<u16>; INLINE_PIVOT_LEN]),
+}
+
+impl std::ops::Deref for Pivot<'_> {
+    type Target = [MaybeUninit<u16>];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Borrowed(buf) => buf,
+            Self::Inline(buf) => buf,
+        }
+    }
+}
+
+impl std::ops::DerefMut for Pivot<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::Borrowed(buf) => buf,
+            Self::Inline(buf) => buf,
+        }
+    }
+}
+
+/// Transcodes text between two character sets.
+///
+/// Just like ICU's `ucnv_convertEx`, the input is first decoded into UTF-16 code units
+/// inside the pivot buffer, which are then encoded into the target character set.
+/// All state required to continue a conversion across chunk boundaries (incomplete
+/// sequences at the end of the input, pivot contents that didn't fit into the output,
+/// BOM handling, statistics) is kept inside the `Converter`. The same instance must thus
+/// be used for an entire file, and [`Converter::reset`] before it's used for another one.
+pub struct Converter<'pivot> {
+    pivot: Pivot<'pivot>,
+    source: Charset,
+    target: Charset,
+    /// Whether the source is "UTF-8 BOM", whose BOM is stripped from the input.
+    source_bom: bool,
+    /// Whether the target is "UTF-8 BOM", whose BOM is added to the output.
+    target_bom: bool,
+    /// What invalid input is replaced with, see [`Converter::set_replacement`].
+    replacement: char,
+    /// See [`Converter::set_transliterate`].
+    transliterate: bool,
+    state: ConvState,
+}
+
+/// The state of a [`Converter`] that carries over from one [`Converter::convert`] call
+/// to the next, and which [`Converter::reset`] discards before converting another stream.
+struct ConvState {
+    /// Code units in `pivot[pivot_beg..pivot_end]` are yet to be written to the output.
+    pivot_beg: usize,
+    pivot_end: usize,
+    /// A high surrogate at the end of the previous UTF-16 input, waiting for its low half.
+    pending_surrogate: Option<u16>,
+    /// The first half of a UTF-16 code unit that was cut off at the end of the previous input.
+    pending_byte: Option<u8>,
+    /// An incomplete UTF-8 or other multi-byte sequence at the end of the previous input.
+    /// While `strip_bom` is set, this holds the part of the BOM that has been matched so far.
+    pending_seq: [u8; 4],
+    pending_seq_len: usize,
+    /// Set for a "UTF-8 BOM" source, until the start of the stream has been checked for a BOM.
+    strip_bom: bool,
+    /// Set for a "UTF-8 BOM" target, until the BOM has been written.
+    emit_bom: bool,
+    /// Number of invalid sequences in the input that were replaced with U+FFFD.
+    replacements: usize,
+    /// Number of characters the target can't represent that were replaced.
+    unmappable: usize,
+    /// The line endings written to the output so far, if the target is UTF-8.
+    line_endings: LineEndingStats,
+}
+
+impl ConvState {
+    fn new(strip_bom: bool, emit_bom: bool) -> Self {
+        Self {
+            pivot_beg: 0,
+            pivot_end: 0,
+            pending_surrogate: None,
+            pending_byte: None,
+            pending_seq: [0; 4],
+            pending_seq_len: 0,
+            strip_bom,
+            emit_bom,
+            replacements: 0,
+            unmappable: 0,
+            line_endings: LineEndingStats::default(),
+        }
+    }
+}
+
+impl<'pivot> Converter<'pivot> {
+    pub fn new(
+        pivot_buffer: &'pivot mut [MaybeUninit<u16>],
+        source_encoding: &str,
+        target_encoding: &str,
+    ) -> apperr::Result<Self> {
+        debug_assert!(pivot_buffer.len() >= 2);
+        Self::with_pivot(Pivot::Borrowed(pivot_buffer), source_encoding, target_encoding)
+    }
+
+    /// Like [`Converter::new`], but with a small built-in pivot buffer, so that callers
+    /// don't need to allocate one. That's plenty for UTF-8 and single-byte encodings,
+    /// where the pivot buffer is either bypassed or holds a few characters at a time.
+    /// UTF-16 and UTF-32 are supported as well, but may be slower than with a larger buffer.
+    pub fn without_pivot(source_encoding: &str, target_encoding: &str) -> apperr::Result<Self> {
+        let pivot = Pivot::Inline([MaybeUninit::uninit(); INLINE_PIVOT_LEN]);
+        Self::with_pivot(pivot, source_encoding, target_encoding)
+    }
+
+    fn with_pivot(
+        pivot: Pivot<'pivot>,
+        source_encoding: &str,
+        target_encoding: &str,
+    ) -> apperr::Result<Self> {
+        match (Charset::from_name(source_encoding), Charset::from_name(target_encoding)) {
+            (Some(source), Some(target)) if target.can_encode() => {
+                let source_bom = encoding_names_eq(source_encoding, "UTF-8 BOM");
+                let target_bom = encoding_names_eq(target_encoding, "UTF-8 BOM");
+                Ok(Self {
+                    pivot,
+                    source,
+                    target,
+                    source_bom,
+                    target_bom,
+                    replacement: char::REPLACEMENT_CHARACTER,
+                    transliterate: false,
+                    state: ConvState::new(source_bom, target_bom),
+                })
+            }
+            _ => Err(apperr::Error::new_icu(IcuError::UnsupportedEncoding.code())),
+        }
+    }
+
+    /// Converts `input` and writes the result into `output`.
+    ///
+    /// Returns the number of bytes read from `input` and written to `output`.
+    /// Incomplete sequences at the end of `input` are consumed and buffered internally.
+    /// Pass an empty `input` to flush them once the end of the stream has been reached.
+    ///
+    /// Fails with [`IcuError::BufferOverflow`] if `output` is smaller than [`max_bytes_per_char`]
+    /// of the target encoding, as it then may not be able to hold even a single character.
+    pub fn convert(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+    ) -> apperr::Result<(usize, usize)> {
+        let (read, written) = self.convert_impl(input, output)?;
+        if self.target == Charset::Utf8 {
+            self.state.line_endings.update(unsafe { output[..written].assume_init_ref() });
+        }
+        Ok((read, written))
+    }
+
+    fn convert_impl(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+    ) -> apperr::Result<(usize, usize)> {
+        let flush = input.is_empty();
+        let mut read = 0;
+        let mut written = 0;
+
+        if output.len() < self.target.max_bytes_per_char() {
+            return Err(apperr::Error::new_icu(IcuError::BufferOverflow.code()));
+        }
+
+        // The check above ensures that the BOM fits.
+        if self.state.emit_bom {
+            for (dst, &b) in output.iter_mut().zip(UTF8_BOM) {
+                dst.write(b);
+            }
+            written = UTF8_BOM.len();
+            self.state.emit_bom = false;
+        }
+
+        if self.state.strip_bom {
+            read = self.strip_utf8_bom(input, flush);
+            if self.state.strip_bom {
+                return Ok((read, written));
+            }
+        }
+
+        written += self.pivot_to_output(&mut output[written..]);
+
+        if self.target == Charset::Utf8
+            && self.state.pivot_beg == self.state.pivot_end
+            && self.state.pending_surrogate.is_none()
+            && self.state.pending_byte.is_none()
+        {
+            let (src, dst) = (&input[read..], &mut output[written..]);
+            let (r, w) = match self.source {
+                Charset::Utf16LE => self.utf16_to_utf8::<false>(src, dst),
+                Charset::Utf16BE => self.utf16_to_utf8::<true>(src, dst),
+                _ => (0, 0),
+            };
+            read += r;
+            written += w;
+        }
+
+        // Only decode more input once the pivot buffer has been fully written out.
+        while self.state.pivot_beg == self.state.pivot_end && written < output.len() {
+            // Limit the amount of code units we decode to what will (likely) fit into the
+            // output. This avoids leaving a partially written pivot buffer behind in most cases.
+            let mut limit = ((output.len() - written) / self.target.max_bytes_per_unit())
+                .clamp(2, self.pivot.len());
+
+            if self.source == Charset::Utf8 && self.target == Charset::Utf8 {
+                // Valid UTF-8 is copied as-is. Invalid and incomplete sequences, including
+                // the bytes held back while looking for the BOM, go through the pivot buffer
+                // one character at a time, so that they get replaced and counted.
+                if self.state.pending_seq_len == 0 {
+                    let len = copy_valid_utf8(&input[read..], &mut output[written..]);
+                    read += len;
+                    written += len;
+                    if written == output.len() {
+                        break;
+                    }
+                }
+                limit = 2;
+            }
+
+            let (r, n) = self.input_to_pivot(&input[read..], limit, flush);
+            read += r;
+            self.state.pivot_beg = 0;
+            self.state.pivot_end = n;
+            written += self.pivot_to_output(&mut output[written..]);
+
+            if r == 0 && n == 0 {
+                break;
+            }
+        }
+
+        Ok((read, written))
+    }
+
+    /// Converts all of `input` into a string allocated in `arena`, including the final flush.
+    /// A convenience over [`Converter::convert`] for when the entire input is available at once.
+    ///
+    /// The target must be UTF-8.
+    pub fn convert_all<'a>(
+        &mut self,
+        input: &[u8],
+        arena: &'a Arena,
+    ) -> apperr::Result<ArenaString<'a>> {
+        if self.target != Charset::Utf8 {
+            return Err(apperr::Error::new_icu(IcuError::UnsupportedEncoding.code()));
+        }
+
+        let mut output = Vec::new_in(arena);
+        let mut read = 0;
+
+        loop {
+            // Anything that grows by more than this gets more room in the next iteration.
+            output.reserve(input.len() - read + 64);
+            let (r, w) = self.convert(&input[read..], output.spare_capacity_mut())?;
+            unsafe { output.set_len(output.len() + w) };
+            read += r;
+
+            // Once the input is exhausted, `convert` flushes until there's nothing left.
+            if read == input.len() && r == 0 && w == 0 {
+                break;
+            }
+        }
+
+        Ok(ArenaString::from_utf8_lossy_owned(output))
+    }
+
+    /// Returns the line ending style of the text converted so far, without a second pass over it.
+    /// For instance, to preserve the line endings of a file when saving it.
+    ///
+    /// Line endings are only tallied when converting into UTF-8, that is, when loading a file.
+    /// Otherwise, this always returns [`LineEnding::Lf`].
+    pub fn line_ending(&self) -> LineEnding {
+        self.state.line_endings.line_ending()
+    }
+
+    /// Discards all state of the current stream, including incomplete sequences and statistics,
+    /// so that the converter can be reused for another one, for instance the next file.
+    pub fn reset(&mut self) {
+        self.state = ConvState::new(self.source_bom, self.target_bom);
+    }
+
+    /// Sets the character that invalid input is replaced with, U+FFFD by default.
+    /// For instance, a plain `?` survives saving the text in any other encoding.
+    /// For single-byte targets, it also replaces the characters they can't represent,
+    /// which otherwise turn into `?`.
+    ///
+    /// Fails with [`IcuError::InvalidChar`] if `ch` can't be represented in the target encoding,
+    /// or if it's outside the Basic Multilingual Plane.
+    pub fn set_replacement(&mut self, ch: char) -> apperr::Result<()> {
+        let representable = match self.target {
+            Charset::Windows1252 => unicode_to_windows1252(ch).is_some(),
+            _ => true,
+        };
+        if !representable || ch.len_utf16() != 1 {
+            return Err(apperr::Error::new_icu(IcuError::InvalidChar.code()));
+        }
+        self.replacement = ch;
+        Ok(())
+    }
+
+    /// Makes single-byte targets write characters they can't represent as a similar ASCII
+    /// character, if there's one: "ő" turns into "o" and "‐" into "-", for instance.
+    /// These are glibc's transliterations into a single ASCII character. Everything else is still
+    /// replaced, see [`Converter::set_replacement`]. Transliterations aren't replacements
+    /// and aren't counted by [`Converter::unmappable_count`].
+    pub fn set_transliterate(&mut self, transliterate: bool) {
+        self.transliterate = transliterate;
+    }
+
+    /// Returns the number of invalid sequences that were replaced with U+FFFD so far.
+    pub fn replacement_count(&self) -> usize {
+        self.state.replacements
+    }
+
+    /// Returns the number of characters the target can't represent that were replaced so far.
+    /// Invalid input isn't included, see [`Converter::replacement_count`].
+    pub fn unmappable_count(&self) -> usize {
+        self.state.unmappable
+    }
+
+    /// Consumes the UTF-8 BOM at the start of the stream, even if it's split across calls.
+    /// Returns the number of bytes read.
+    fn strip_utf8_bom(&mut self, input: &[u8], flush: bool) -> usize {
+        let matched = self.state.pending_seq_len;
+        let len = input.len().min(UTF8_BOM.len() - matched);
+
+        if input[..len] != UTF8_BOM[matched..matched + len] {
+            // Not a BOM after all. The bytes we held back so far
+            // remain in `pending_seq` and get converted as usual.
+            self.state.strip_bom = false;
+            return 0;
+        }
+
+        self.state.pending_seq[matched..matched + len].copy_from_slice(&input[..len]);
+        self.state.pending_seq_len += len;
+
+        if self.state.pending_seq_len == UTF8_BOM.len() {
+            self.state.pending_seq_len = 0;
+            self.state.strip_bom = false;
+        } else if flush {
+            self.state.strip_bom = false;
+        }
+
+        len
+    }
+
+    /// Decodes `input` into at most `limit` UTF-16 code units in the pivot buffer.
+    /// Returns the number of bytes read and code units written.
+    fn input_to_pivot(&mut self, input: &[u8], limit: usize, flush: bool) -> (usize, usize) {
+        match self.source {
+            Charset::Utf8 => self.utf8_to_pivot(input, limit, flush),
+            Charset::Utf16LE => self.utf16_to_pivot::<false>(input, limit, flush),
+            Charset::Utf16BE => self.utf16_to_pivot::<true>(input, limit, flush),
+            Charset::Utf32LE => self.multibyte_to_pivot(input, limit, flush, decode_utf32::<false>),
+            Charset::Utf32BE => self.multibyte_to_pivot(input, limit, flush, decode_utf32::<true>),
+            Charset::Windows1252 => self.windows1252_to_pivot(input, limit),
+            Charset::Gb18030 => self.multibyte_to_pivot(input, limit, flush, decode_gb18030),
+            Charset::ShiftJis => self.multibyte_to_pivot(input, limit, flush, decode_shift_jis),
+            Charset::EucKr => self.multibyte_to_pivot(input, limit, flush, decode_euc_kr),
+        }
+    }
+
+    fn utf8_to_pivot(&mut self, input: &[u8], limit: usize, flush: bool) -> (usize, usize) {
+        let mut read = 0;
+        let mut n = 0;
+
+        // Complete the sequence that was cut off at the end of the previous input.
+        if self.state.pending_seq_len != 0 {
+            let pending_len = self.state.pending_seq_len;
+            let seq_len = utf8_sequence_len(self.state.pending_seq[0]);
+            let take = (seq_len - pending_len).min(input.len());
+
+            self.state.pending_seq[pending_len..pending_len + take].copy_from_slice(&input[..take]);
+            self.state.pending_seq_len += take;
+
+            if self.state.pending_seq_len < seq_len && !flush {
+                return (take, 0);
+            }
+
+            // If the sequence turned out to be invalid, this may yield multiple U+FFFD
+            // and may not need all of the bytes we took from the input.
+            let buf = self.state.pending_seq;
+            let mut chars = Utf8Chars::new(&buf[..self.state.pending_seq_len], 0);
+            while chars.offset() < pending_len {
+                n += self.push_pivot_utf8(n, &mut chars).unwrap();
+            }
+
+            read = chars.offset() - pending_len;
+            self.state.pending_seq_len = 0;
+        }
+
+        let end = if flush { input.len() } else { input.len() - utf8_incomplete_tail_len(input) };
+        let mut chars = Utf8Chars::new(&input[..end], read);
+
+        while n + 2 <= limit
+            && let Some(len) = self.push_pivot_utf8(n, &mut chars)
+        {
+            n += len;
+        }
+
+        read = chars.offset();
+
+        // Buffer the incomplete sequence at the end of the input for the next call.
+        if read == end && end < input.len() {
+            let tail = &input[end..];
+            self.state.pending_seq[..tail.len()].copy_from_slice(tail);
+            self.state.pending_seq_len = tail.len();
+            read = input.len();
+        }
+
+        (read, n)
+    }
+
+    fn utf16_to_pivot<const BE: bool>(
+        &mut self,
+        input: &[u8],
+        limit: usize,
+        flush: bool,
+    ) -> (usize, usize) {
+        let mut read = 0;
+        let mut n = 0;
+
+        while n + 2 <= limit {
+            let bytes = if let Some(b) = self.state.pending_byte {
+                let Some(&next) = input.get(read) else {
+                    break;
+                };
+                self.state.pending_byte = None;
+                read += 1;
+                [b, next]
+            } else if read + 2 <= input.len() {
+                read += 2;
+                [input[read - 2], input[read - 1]]
+            } else {
+                break;
+            };
+            let unit = if BE { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) };
+
+            if let Some(high) = self.state.pending_surrogate.take() {
+                if is_low_surrogate(unit) {
+                    self.pivot[n].write(high);
+                    self.pivot[n + 1].write(unit);
+                    n += 2;
+                    continue;
+                }
+                self.pivot[n].write(self.replacement as u16);
+                self.state.replacements += 1;
+                n += 1;
+            }
+
+            if is_high_surrogate(unit) {
+                self.state.pending_surrogate = Some(unit);
+            } else if is_low_surrogate(unit) {
+                self.pivot[n].write(self.replacement as u16);
+                self.state.replacements += 1;
+                n += 1;
+            } else {
+                self.pivot[n].write(unit);
+                n += 1;
+            }
+        }
+
+        // Carry a lone trailing byte over to the next call.
+        if !flush && self.state.pending_byte.is_none() && read + 1 == input.len() {
+            self.state.pending_byte = Some(input[read]);
+            read += 1;
+        }
+
+        // A high surrogate or half a code unit at the very end of the stream will never be completed.
+        if flush && n < limit && self.state.pending_surrogate.take().is_some() {
+            self.pivot[n].write(self.replacement as u16);
+            self.state.replacements += 1;
+            n += 1;
+        }
+        if flush && n < limit && self.state.pending_byte.take().is_some() {
+            self.pivot[n].write(self.replacement as u16);
+            self.state.replacements += 1;
+            n += 1;
+        }
+
+        (read, n)
+    }
+
+    /// Fast path for UTF-16 to UTF-8 which bypasses the pivot buffer.
+    /// The input is reinterpreted as `u16` and only byte swapped if the endianness differs.
+    ///
+    /// Returns the number of bytes read and written. Leaves misaligned input, a trailing odd byte,
+    /// a trailing high surrogate, and characters that don't fit into `output` to the regular path.
+    fn utf16_to_utf8<const BE: bool>(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+    ) -> (usize, usize) {
+        let (prefix, units, _) = unsafe { input.align_to::<u16>() };
+        if !prefix.is_empty() {
+            return (0, 0);
+        }
+
+        let unit_at = |i: usize| if BE { u16::from_be(units[i]) } else { u16::from_le(units[i]) };
+        let mut i = 0;
+        let mut written = 0;
+
+        while i < units.len() {
+            let unit = unit_at(i);
+
+            if unit < 0x80 {
+                let Some(dst) = output.get_mut(written) else {
+                    break;
+                };
+                dst.write(unit as u8);
+                i += 1;
+                written += 1;
+                continue;
+            }
+
+            let (ch, len) = if is_high_surrogate(unit) {
+                if i + 1 >= units.len() {
+                    break;
+                }
+                let low = unit_at(i + 1);
+                if is_low_surrogate(low) {
+                    let c = 0x10000 + (((unit as u32) - 0xD800) << 10) + ((low as u32) - 0xDC00);
+                    (char::from_u32(c), 2)
+                } else {
+                    (None, 1)
+                }
+            } else {
+                (char::from_u32(unit as u32), 1)
+            };
+
+            let ch_len = ch.unwrap_or(self.replacement).len_utf8();
+            if written + ch_len > output.len() {
+                break;
+            }
+            let ch = ch.unwrap_or_else(|| {
+                self.state.replacements += 1;
+                self.replacement
+            });
+
+            let mut buf = [0; 4];
+            ch.encode_utf8(&mut buf);
+            for (dst, &b) in output[written..].iter_mut().zip(&buf[..ch_len]) {
+                dst.write(b);
+            }
+            i += len;
+            written += ch_len;
+        }
+
+        (i * 2, written)
+    }
+
+    fn windows1252_to_pivot(&mut self, input: &[u8], limit: usize) -> (usize, usize) {
+        let len = input.len().min(limit);
+        for (dst, &b) in self.pivot.iter_mut().zip(&input[..len]) {
+            dst.write(windows1252_to_unicode(b));
+        }
+        (len, len)
+    }
+
+    /// Decodes a stateless multi-byte encoding, one character at a time, using `decode`.
+    fn multibyte_to_pivot(
+        &mut self,
+        input: &[u8],
+        limit: usize,
+        flush: bool,
+        decode: fn(&[u8]) -> Decoded,
+    ) -> (usize, usize) {
+        let mut read = 0;
+        let mut n = 0;
+
+        // Complete the sequence that was cut off at the end of the previous input.
+        // If it turns out to be invalid, the remaining bytes are decoded anew, one after another.
+        while self.state.pending_seq_len != 0 && n + 2 <= limit {
+            let pending_len = self.state.pending_seq_len;
+            let take = (self.state.pending_seq.len() - pending_len).min(input.len() - read);
+            let mut buf = self.state.pending_seq;
+            buf[pending_len..pending_len + take].copy_from_slice(&input[read..read + take]);
+
+            let (ch, len) = match decode(&buf[..pending_len + take]) {
+                Decoded::Char(ch, len) => (Some(ch), len),
+                Decoded::Invalid(len) => (None, len),
+                Decoded::Incomplete if !flush => {
+                    // `decode` only ever needs more input once `input` is exhausted.
+                    self.state.pending_seq = buf;
+                    self.state.pending_seq_len += take;
+                    return (read + take, n);
+                }
+                Decoded::Incomplete => (None, pending_len),
+            };
+
+            n += self.push_pivot_decoded(n, ch);
+            if len >= pending_len {
+                read += len - pending_len;
+                self.state.pending_seq_len = 0;
+            } else {
+                self.state.pending_seq.copy_within(len..pending_len, 0);
+                self.state.pending_seq_len -= len;
+            }
+        }
+
+        while self.state.pending_seq_len == 0 && n + 2 <= limit && read < input.len() {
+            match decode(&input[read..]) {
+                Decoded::Char(ch, len) => {
+                    n += self.push_pivot(n, ch);
+                    read += len;
+                }
+                Decoded::Invalid(len) => {
+                    n += self.push_pivot_decoded(n, None);
+                    read += len;
+                }
+                Decoded::Incomplete => {
+                    let tail = &input[read..];
+                    self.state.pending_seq[..tail.len()].copy_from_slice(tail);
+                    self.state.pending_seq_len = tail.len();
+                    read = input.len();
+                }
+            }
+        }
+
+        (read, n)
+    }
+
+    /// Writes `ch` into the pivot buffer at `n`, or the replacement character for `None`.
+    /// Returns the number of code units written.
+    fn push_pivot_decoded(&mut self, n: usize, ch: Option<char>) -> usize {
+        let ch = ch.unwrap_or_else(|| {
+            self.state.replacements += 1;
+            self.replacement
+        });
+        self.push_pivot(n, ch)
+    }
+
+    /// Decodes the next character from `chars` into the pivot buffer at `n`.
+    /// Returns the number of code units written, or `None` if `chars` is exhausted.
+    fn push_pivot_utf8(&mut self, n: usize, chars: &mut Utf8Chars) -> Option<usize> {
+        let beg = chars.offset();
+        let mut ch = chars.next()?;
+        // `Utf8Chars` turns invalid sequences into U+FFFD. Don't count literal U+FFFD in the input.
+        if ch == char::REPLACEMENT_CHARACTER
+            && &chars.source()[beg..chars.offset()] != "\u{FFFD}".as_bytes()
+        {
+            self.state.replacements += 1;
+            ch = self.replacement;
+        }
+        Some(self.push_pivot(n, ch))
+    }
+
+    /// Writes `ch` into the pivot buffer at `n` and returns the number of code units written.
+    fn push_pivot(&mut self, n: usize, ch: char) -> usize {
+        let mut buf = [0; 2];
+        let units = ch.encode_utf16(&mut buf);
+        for (dst, &unit) in self.pivot[n..].iter_mut().zip(units.iter()) {
+            dst.write(unit);
+        }
+        units.len()
+    }
+
+    /// Writes as much of the pending pivot buffer contents into `output` as possible.
+    /// Returns the number of bytes written.
+    fn pivot_to_output(&mut self, output: &mut [MaybeUninit<u8>]) -> usize {
+        let pending = self.state.pivot_beg..self.state.pivot_end;
+        let units = unsafe { self.pivot[pending].assume_init_ref() };
+        let mut read = 0;
+        let mut written = 0;
+
+        match self.target {
+            Charset::Utf8 => {
+                // The pivot buffer only ever contains valid UTF-16.
+                for ch in char::decode_utf16(units.iter().copied()) {
+                    let ch = ch.unwrap_or(char::REPLACEMENT_CHARACTER);
+                    let len = ch.len_utf8();
+                    if written + len > output.len() {
+                        break;
+                    }
+                    let mut buf = [0; 4];
+                    ch.encode_utf8(&mut buf);
+                    for (dst, &b) in output[written..].iter_mut().zip(&buf[..len]) {
+                        dst.write(b);
+                    }
+                    read += ch.len_utf16();
+                    written += len;
+                }
+            }
+            Charset::Utf16LE | Charset::Utf16BE => {
+                let be = self.target == Charset::Utf16BE;
+                // Surrogate pairs are written as a whole, so that the output never ends in
+                // an unpaired surrogate. The second half stays in the pivot buffer otherwise.
+                while read < units.len() {
+                    let len = if is_high_surrogate(units[read]) { 2 } else { 1 };
+                    if written + 2 * len > output.len() {
+                        break;
+                    }
+                    for &unit in &units[read..read + len] {
+                        let bytes = if be { unit.to_be_bytes() } else { unit.to_le_bytes() };
+                        output[written].write(bytes[0]);
+                        output[written + 1].write(bytes[1]);
+                        written += 2;
+                    }
+                    read += len;
+                }
+            }
+            Charset::Windows1252 => {
+                for ch in char::decode_utf16(units.iter().copied()) {
+                    if written >= output.len() {
+                        break;
+                    }
+                    let ch = ch.unwrap_or(char::REPLACEMENT_CHARACTER);
+                    let b = unicode_to_windows1252(ch)
+                        .or_else(|| self.transliterate.then(|| transliterate(ch)).flatten())
+                        .unwrap_or_else(|| {
+                            self.state.unmappable += 1;
+                            unicode_to_windows1252(self.replacement).unwrap_or(b'?')
+                        });
+                    output[written].write(b);
+                    read += ch.len_utf16();
+                    written += 1;
+                }
+            }
+            Charset::Utf32LE
+            | Charset::Utf32BE
+            | Charset::Gb18030
+            | Charset::ShiftJis
+            | Charset::EucKr => unreachable!(),
+        }
+
+        self.state.pivot_beg += read;
+        written
+    }
+}
+
+/// Maps 0x80-0x9F in Windows-1252 to Unicode. The rest is identical to ISO-8859-1.
+/// The 5 undefined bytes map to the corresponding C1 control characters, just like in WHATWG.
+const WINDOWS1252_80_9F: [u16; 32] = [
+    0x20AC, 0x0081, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021, //
+    0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0x008D, 0x017D, 0x008F, //
+    0x0090, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014, //
+    0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x009D, 0x017E, 0x0178, //
+];
+
+fn windows1252_to_unicode(b: u8) -> u16 {
+    match b {
+        0x80..=0x9F => WINDOWS1252_80_9F[b as usize - 0x80],
+        _ => b as u16,
+    }
+}
+
+fn unicode_to_windows1252(ch: char) -> Option<u8> {
+    match ch as u32 {
+        0x00..=0x7F | 0xA0..=0xFF => Some(ch as u8),
+        c => WINDOWS1252_80_9F.iter().position(|&u| u as u32 == c).map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Returns the ASCII character that's closest to `ch`, see [`Converter::set_transliterate`].
+fn transliterate(ch: char) -> Option<u8> {
+    let i = tables::TRANSLITERATIONS.binary_search_by_key(&ch, |&(c, _)| c).ok()?;
+    Some(tables::TRANSLITERATIONS[i].1)
+}
+
+/// The result of decoding a single character of a multi-byte encoding.
+enum Decoded {
+    /// A character and the number of bytes it was encoded with.
+    Char(char, usize),
+    /// An invalid or unmapped sequence, to be replaced with U+FFFD, and the number of bytes to skip.
+    Invalid(usize),
+    /// The input ends in the middle of a sequence.
+    Incomplete,
+}
+
+impl Decoded {
+    fn new(ch: Option<char>, len: usize) -> Self {
+        match ch {
+            Some(ch) => Self::Char(ch, len),
+            None => Self::Invalid(len),
+        }
+    }
+}
+
+/// Decodes the first code point in `bytes`. Surrogates and values above U+10FFFF are invalid.
+fn decode_utf32<const BE: bool>(bytes: &[u8]) -> Decoded {
+    let Some(&unit) = bytes.first_chunk::<4>() else {
+        return Decoded::Incomplete;
+    };
+    let c = if BE { u32::from_be_bytes(unit) } else { u32::from_le_bytes(unit) };
+    Decoded::new(char::from_u32(c), 4)
+}
+
+/// Decodes the first character in `bytes`, which must not be empty, with the mappings
+/// from glibc's GB18030 charmap. Errors are handled like in WHATWG's decoder.
+fn decode_gb18030(bytes: &[u8]) -> Decoded {
+    let b0 = bytes[0];
+    match b0 {
+        0x00..=0x7F => return Decoded::Char(b0 as char, 1),
+        0x80 => return Decoded::Char('€', 1),
+        0xFF => return Decoded::Invalid(1),
+        _ => {}
+    }
+
+    let Some(&b1) = bytes.get(1) else {
+        return Decoded::Incomplete;
+    };
+    let trail_offset = match b1 {
+        0x30..=0x39 => return decode_gb18030_four_byte(bytes),
+        0x40..=0x7E => 0x40,
+        0x80..=0xFE => 0x41,
+        // ASCII isn't consumed, so that it isn't lost.
+        0x00..=0x7F => return Decoded::Invalid(1),
+        _ => return Decoded::Invalid(2),
+    };
+
+    let pointer = (b0 - 0x81) as usize * 190 + (b1 - trail_offset) as usize;
+    Decoded::new(char::from_u32(tables::GB18030_TWO_BYTE[pointer] as u32), 2)
+}
+
+fn decode_gb18030_four_byte(bytes: &[u8]) -> Decoded {
+    let Some(&b2) = bytes.get(2) else {
+        return Decoded::Incomplete;
+    };
+    if !(0x81..=0xFE).contains(&b2) {
+        return Decoded::Invalid(1);
+    }
+    let Some(&b3) = bytes.get(3) else {
+        return Decoded::Incomplete;
+    };
+    if !(0x30..=0x39).contains(&b3) {
+        return Decoded::Invalid(1);
+    }
+
+    let pointer = (bytes[0] - 0x81) as u32 * 12600
+        + (bytes[1] - 0x30) as u32 * 1260
+        + (b2 - 0x81) as u32 * 10
+        + (b3 - 0x30) as u32;
+
+    let ch = match pointer {
+        // Everything outside the BMP is mapped linearly.
+        189000..=1237575 => char::from_u32(0x10000 + pointer - 189000),
+        0..=39419 => {
+            let i = tables::GB18030_RANGES.partition_point(|&(p, _)| p <= pointer) - 1;
+            let (p, c) = tables::GB18030_RANGES[i];
+            char::from_u32(c as u32 + pointer - p)
+        }
+        _ => None,
+    };
+    Decoded::new(ch, 4)
+}
+
+/// Decodes the first character in `bytes`, which must not be empty, with the mappings
+/// from glibc's WINDOWS-31J charmap. Errors are handled like in WHATWG's decoder.
+fn decode_shift_jis(bytes: &[u8]) -> Decoded {
+    let b0 = bytes[0];
+    let lead_offset = match b0 {
+        0x00..=0x80 => return Decoded::Char(b0 as char, 1),
+        // Half-width katakana.
+        0xA1..=0xDF => return Decoded::new(char::from_u32(0xFF61 - 0xA1 + b0 as u32), 1),
+        0x81..=0x9F => 0x81,
+        0xE0..=0xFC => 0xC1,
+        _ => return Decoded::Invalid(1),
+    };
+
+    let Some(&b1) = bytes.get(1) else {
+        return Decoded::Incomplete;
+    };
+    let trail_offset = match b1 {
+        0x40..=0x7E => 0x40,
+        0x80..=0xFC => 0x41,
+        // ASCII isn't consumed, so that it isn't lost.
+        0x00..=0x7F => return Decoded::Invalid(1),
+        _ => return Decoded::Invalid(2),
+    };
+
+    let pointer = (b0 - lead_offset) as usize * 188 + (b1 - trail_offset) as usize;
+    match tables::SHIFT_JIS_TWO_BYTE[pointer] {
+        0 if b1.is_ascii() => Decoded::Invalid(1),
+        0 => Decoded::Invalid(2),
+        c => Decoded::new(char::from_u32(c as u32), 2),
+    }
+}
+
+/// Decodes the first character in `bytes`, which must not be empty, with the mappings
+/// from glibc's CP949 charmap. Errors are handled like in WHATWG's decoder.
+fn decode_euc_kr(bytes: &[u8]) -> Decoded {
+    let b0 = bytes[0];
+    match b0 {
+        0x00..=0x7F => return Decoded::Char(b0 as char, 1),
+        0x81..=0xFE => {}
+        _ => return Decoded::Invalid(1),
+    }
+
+    let Some(&b1) = bytes.get(1) else {
+        return Decoded::Incomplete;
+    };
+    let c = match b1 {
+        0x41..=0xFE => tables::EUC_KR_TWO_BYTE[(b0 - 0x81) as usize * 190 + (b1 - 0x41) as usize],
+        _ => 0,
+    };
+    match c {
+        // ASCII isn't consumed, so that it isn't lost.
+        0 if b1.is_ascii() => Decoded::Invalid(1),
+        0 => Decoded::Invalid(2),
+        c => Decoded::new(char::from_u32(c as u32), 2),
+    }
+}
+
+fn is_high_surrogate(unit: u16) -> bool {
+    (0xD800..0xDC00).contains(&unit)
+}
+
+fn is_low_surrogate(unit: u16) -> bool {
+    (0xDC00..0xE000).contains(&unit)
+}
+
+/// Returns the offset of the first `\n` in `haystack` at or after `offset`, or `haystack.len()`.
+fn find_newline(haystack: &[u8], offset: usize) -> usize {
+    match lines_fwd(haystack, offset, 0, 1) {
+        (off, 1) => off - 1,
+        _ => haystack.len(),
+    }
+}
+
+/// Returns the length of the UTF-8 sequence started by the given lead byte.
+/// Invalid lead bytes are treated as 1 byte long.
+fn utf8_sequence_len(lead: u8) -> usize {
+    match lead {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 1,
+    }
+}
+
+/// Copies the longest valid UTF-8 prefix of `input` that fits into `output`.
+/// Returns its length.
+fn copy_valid_utf8(input: &[u8], output: &mut [MaybeUninit<u8>]) -> usize {
+    let input = &input[..input.len().min(output.len())];
+    let len = match std::str::from_utf8(input) {
+        Ok(s) => s.len(),
+        Err(err) => err.valid_up_to(),
+    };
+    unsafe { std::ptr::copy_nonoverlapping(input.as_ptr(), output.as_mut_ptr() as *mut u8, len) };
+    len
+}
+
+/// Returns the length of an incomplete UTF-8 sequence at the end of `bytes`, if any.
+fn utf8_incomplete_tail_len(bytes: &[u8]) -> usize {
+    for len in 1..=bytes.len().min(3) {
+        let b = bytes[bytes.len() - len];
+        if b & 0xC0 != 0x80 {
+            return if utf8_sequence_len(b) > len { len } else { 0 };
+        }
+    }
+    0
+}
+
+/// The options for [`compare_strings_with`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompareOptions {
+    /// Ignores the case of ASCII letters. Non-ASCII bytes are compared as-is.
+    pub case_insensitive: bool,
+    /// Compares runs of ASCII digits by their numeric value, see [`compare_strings_natural`].
+    pub numeric: bool,
+    /// Compares the NFC normalization of both strings, see [`normalize_nfc`],
+    /// so that canonically equivalent strings compare equal. Invalid UTF-8 is replaced with U+FFFD.
+    pub normalize: bool,
+}
+
+/// Compares `a` and `b` according to `opts`. Both options may be combined.
+pub fn compare_strings_with(a: &[u8], b: &[u8], opts: CompareOptions) -> Ordering {
+    if opts.normalize {
+        let a = String::from_utf8_lossy(a);
+        let b = String::from_utf8_lossy(b);
+        let opts = CompareOptions { normalize: false, ..opts };
+        return compare_strings_with(
+            normalize_nfc(&a).as_bytes(),
+            normalize_nfc(&b).as_bytes(),
+            opts,
+        );
+    }
+
+    if !opts.case_insensitive && !opts.numeric {
+        return a.cmp(b);
+    }
+
+    let fold = if opts.case_insensitive { u8::to_ascii_lowercase } else { |c: &u8| *c };
+    if opts.numeric {
+        compare_natural(a, b, fold)
+    } else {
+        a.iter().map(fold).cmp(b.iter().map(fold))
+    }
+}
+
+/// Compares `a` and `b` byte by byte. For text, prefer [`compare_str`], which makes the intent clear.
+/// Bytes in other encodings than UTF-8, like Latin-1, don't necessarily sort by their characters.
+pub fn compare_strings(a: &[u8], b: &[u8]) -> Ordering {
+    a.cmp(b)
+}
+
+/// Compares `a` and `b` by Unicode scalar value. For UTF-8 this is the same as [`compare_strings`],
+/// but unlike with UTF-16 code units, characters outside the BMP sort after all others.
+pub fn compare_str(a: &str, b: &str) -> Ordering {
+    a.chars().cmp(b.chars())
+}
+
+/// Like [`compare_strings`], but ignores the case of ASCII letters.
+/// Non-ASCII bytes are compared as-is.
+pub fn compare_strings_ci(a: &[u8], b: &[u8]) -> Ordering {
+    compare_strings_with(a, b, CompareOptions { case_insensitive: true, ..Default::default() })
+}
+
+/// Like [`compare_strings`], but compares runs of ASCII digits by their numeric value,
+/// such that "file2" sorts before "file10". Digit runs of any length are supported.
+///
+/// Runs with the same value but a different number of leading zeros ("7" and "007")
+/// compare equal, unless the strings are otherwise identical: Then the shorter run goes first.
+pub fn compare_strings_natural(a: &[u8], b: &[u8]) -> Ordering {
+    compare_strings_with(a, b, CompareOptions { numeric: true, ..Default::default() })
+}
+
+/// Compares two lines for a diff, optionally ignoring trailing spaces, tabs and CRs
+/// (which also makes CRLF and LF lines compare equal) and the case of ASCII letters.
+pub fn compare_lines(
+    a: &[u8],
+    b: &[u8],
+    ignore_trailing_ws: bool,
+    case_insensitive: bool,
+) -> Ordering {
+    let trim = |s: &[u8]| -> usize {
+        if ignore_trailing_ws {
+            s.iter().rposition(|c| !matches!(c, b' ' | b'\t' | b'\r')).map_or(0, |i| i + 1)
+        } else {
+            s.len()
+        }
+    };
+    let opts = CompareOptions { case_insensitive, ..Default::default() };
+    compare_strings_with(&a[..trim(a)], &b[..trim(b)], opts)
+}
+
+/// Implements [`compare_strings_natural`], comparing all other bytes after applying `fold`.
+fn compare_natural(a: &[u8], b: &[u8], fold: fn(&u8) -> u8) -> Ordering {
+    let mut tie_break = Ordering::Equal;
+    let mut i = 0;
+    let mut j = 0;
+
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let a_end = i + a[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let b_end = j + b[j..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let a_digits = trim_leading_zeros(&a[i..a_end]);
+            let b_digits = trim_leading_zeros(&b[j..b_end]);
+
+            // Without leading zeros, a longer run of digits is a larger number.
+            let ord = a_digits.len().cmp(&b_digits.len()).then_with(|| a_digits.cmp(b_digits));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            if tie_break == Ordering::Equal {
+                tie_break = (a_end - i).cmp(&(b_end - j));
+            }
+
+            i = a_end;
+            j = b_end;
+        } else {
+            let ord = fold(&a[i]).cmp(&fold(&b[j]));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+
+    (a.len() - i).cmp(&(b.len() - j)).then(tie_break)
+}
+
+fn trim_leading_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+    &digits[zeros..]
+}
+
+/// Locales with case folding rules that differ from the default.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Locale {
+    #[default]
+    Default,
+    /// Turkish and Azeri: "I" folds to "ı" and "İ" to "i".
+    Turkish,
+}
+
+/// Applies the Unicode default case folding (C + F in `CaseFolding.txt`) to `input`.
+///
+/// Unlike lowercasing, this turns "ß" into "ss" and "ς" into "σ", so that all case variants
+/// of a string fold to the same result. It's locale-independent and doesn't apply
+/// the Turkish dotless i mappings. See [`fold_case_locale`] for those.
+pub fn fold_case<'a>(arena: &'a Arena, input: &str) -> ArenaString<'a> {
+    fold_case_locale(arena, input, Locale::Default)
+}
+
+/// Like [`fold_case`], but writes the result into `out`, replacing its contents.
+/// Reusing one buffer for many inputs, for instance when sorting, avoids an allocation per input.
+pub fn fold_case_into(out: &mut ArenaString<'_>, input: &str) {
+    out.clear();
+    push_folded(out, input, Locale::Default);
+}
+
+/// Like [`fold_case`], but applies the special mappings of the given `locale` (T in `CaseFolding.txt`).
+pub fn fold_case_locale<'a>(arena: &'a Arena, input: &str, locale: Locale) -> ArenaString<'a> {
+    let mut result = ArenaString::with_capacity_in(input.len(), arena);
+    push_folded(&mut result, input, locale);
+    result
+}
+
+/// Appends the case folding of `input` to `out`.
+fn push_folded(out: &mut ArenaString<'_>, input: &str, locale: Locale) {
+    // Pure ASCII is by far the most common input and doesn't need the Unicode tables.
+    // The Turkish "I" folds to the non-ASCII "ı", however.
+    if input.is_ascii() && (locale == Locale::Default || !input.contains('I')) {
+        let start = out.len();
+        out.push_str(input);
+        out.as_mut_str()[start..].make_ascii_lowercase();
+        return;
+    }
+
+    for ch in input.chars() {
+        match (locale, ch) {
+            (Locale::Turkish, 'I') => out.push('ı'),
+            (Locale::Turkish, 'İ') => out.push('i'),
+            _ => out.extend(fold_char(ch)),
+        }
+    }
+}
+
+/// Returns the case folding of `ch` if it's a single character, and otherwise its lowercase
+/// or `ch` itself. Unlike [`fold_case`] this keeps "ß" as is, but one char maps to one char,
+/// which keeps char indices intact, for instance for highlighting the matched characters.
+pub fn fold_char_simple(ch: char) -> char {
+    fn single(mut it: impl Iterator<Item = char>) -> Option<char> {
+        match (it.next(), it.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    }
+    single(fold_char(ch)).or_else(|| single(ch.to_lowercase())).unwrap_or(ch)
+}
+
+/// Returns whether `a` and `b` are equal after [`fold_case`], for instance to look up
+/// INI section names. Unlike comparing the results of `fold_case`, this doesn't allocate
+/// and stops at the first difference. Length-changing foldings like "ß" to "ss"
+/// are handled by folding both strings char by char as they're compared.
+pub fn eq_ignore_case(a: &str, b: &str) -> bool {
+    // Pure ASCII folds to the same length, which is checked first.
+    if a.is_ascii() && b.is_ascii() {
+        return a.eq_ignore_ascii_case(b);
+    }
+    a.chars().flat_map(fold_char).eq(b.chars().flat_map(fold_char))
+}
+
+/// The case folding of a single character, see [`fold_char`].
+enum FoldedChar {
+    Char(std::option::IntoIter<char>),
+    Str(std::str::Chars<'static>),
+    Lower(std::char::ToLowercase),
+}
+
+impl Iterator for FoldedChar {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        match self {
+            Self::Char(it) => it.next(),
+            Self::Str(it) => it.next(),
+            Self::Lower(it) => it.next(),
+        }
+    }
+}
+
+/// Returns the default case folding of `ch`, which may be several characters long.
+fn fold_char(ch: char) -> FoldedChar {
+    let c = ch as u32;
+    let single = |c| FoldedChar::Char(char::from_u32(c).into_iter());
+    match c {
+        // Cherokee is the only script that folds to uppercase, for stability reasons.
+        0x13A0..=0x13F5 => single(c),
+        0x13F8..=0x13FD => single(c - 0x13F8 + 0x13F0),
+        0xAB70..=0xABBF => single(c - 0xAB70 + 0x13A0),
+        _ => match tables::CASE_FOLDING_EXCEPTIONS.binary_search_by_key(&ch, |&(c, _)| c) {
+            Ok(i) => FoldedChar::Str(tables::CASE_FOLDING_EXCEPTIONS[i].1.chars()),
+            Err(_) => FoldedChar::Lower(ch.to_lowercase()),
+        },
+    }
+}
+
+const HANGUL_S_BASE: u32 = 0xAC00;
+const HANGUL_L_BASE: u32 = 0x1100;
+const HANGUL_V_BASE: u32 = 0x1161;
+const HANGUL_T_BASE: u32 = 0x11A7;
+const HANGUL_L_COUNT: u32 = 19;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+const HANGUL_N_COUNT: u32 = HANGUL_V_COUNT * HANGUL_T_COUNT;
+const HANGUL_S_COUNT: u32 = HANGUL_L_COUNT * HANGUL_N_COUNT;
+
+/// Returns the NFC normalization of `input`, so that for instance "é" as a single char (U+00E9)
+/// and "e" followed by a combining acute accent (U+0301) become the same.
+/// Returns `input` as-is if it's normalized already, which is the case for most text.
+pub fn normalize_nfc(input: &str) -> Cow<'_, str> {
+    match nfc(input) {
+        Some((normalized, _)) => Cow::Owned(normalized),
+        None => Cow::Borrowed(input),
+    }
+}
+
+/// A span of text that was replaced with one of a different length, for instance by
+/// normalization. Used to map offsets in the result back to the original text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChangedSpan {
+    /// The range in the resulting text.
+    new: Range<usize>,
+    /// The range in the original text.
+    old: Range<usize>,
+}
+
+/// Maps `offset` in a text with the given changed `spans` (in ascending order) back to the
+/// original text. Offsets within a span are mapped to its start, or its end if `round_up` is set.
+fn map_changed_offset(spans: &[ChangedSpan], offset: usize, round_up: bool) -> usize {
+    // The text between the spans is unchanged, but shifted.
+    let i = spans.partition_point(|s| s.new.start < offset);
+    match i.checked_sub(1).map(|i| &spans[i]) {
+        Some(s) if offset < s.new.end && round_up => s.old.end,
+        Some(s) if offset < s.new.end => s.old.start,
+        Some(s) => s.old.end + (offset - s.new.end),
+        None => offset,
+    }
+}
+
+/// Normalizes `input` to NFC. Returns `None` if it's normalized already,
+/// and otherwise the normalized text along with the spans that changed, in ascending order.
+fn nfc(input: &str) -> Option<(String, Vec<ChangedSpan>)> {
+    let mut out = String::new();
+    let mut spans = Vec::new();
+    let mut chars = Vec::new();
+    // `input[..copied]` has been written to `out` already.
+    let mut copied = 0;
+    // The current segment consists of a starter and the chars that may interact with it.
+    let mut seg_start = 0;
+    // Chars below U+0300 are normalized and never interact with the preceding char.
+    // Segments that only consist of those can be skipped.
+    let mut seg_simple = true;
+
+    for (offset, ch) in input.char_indices().chain([(input.len(), '\0')]) {
+        if offset > seg_start && (offset == input.len() || nfc_is_segment_start(ch)) {
+            if !seg_simple {
+                let seg = &input[seg_start..offset];
+                nfc_segment(seg, &mut chars);
+                if !chars.iter().copied().eq(seg.chars()) {
+                    out.push_str(&input[copied..seg_start]);
+                    let norm_start = out.len();
+                    out.extend(&chars);
+                    spans.push(ChangedSpan { new: norm_start..out.len(), old: seg_start..offset });
+                    copied = offset;
+                }
+            }
+            seg_start = offset;
+            seg_simple = true;
+        }
+        seg_simple &= (ch as u32) < 0x300;
+    }
+
+    if spans.is_empty() {
+        return None;
+    }
+    out.push_str(&input[copied..]);
+    Some((out, spans))
+}
+
+/// Decomposes, reorders and recomposes `seg` into `chars`.
+fn nfc_segment(seg: &str, chars: &mut Vec<char>) {
+    chars.clear();
+    for ch in seg.chars() {
+        nfc_decompose(ch, chars);
+    }
+
+    // Sort runs of non-starters by their combining class. The sort must be stable.
+    for i in 1..chars.len() {
+        let class = canonical_combining_class(chars[i]);
+        let mut j = i;
+        while j > 0 && class != 0 && canonical_combining_class(chars[j - 1]) > class {
+            chars.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    // Compose each char with the last starter, unless another char in between blocks it.
+    let mut starter = None;
+    let mut last_class = 0;
+    let mut len = 0;
+    for i in 0..chars.len() {
+        let ch = chars[i];
+        let class = canonical_combining_class(ch);
+        if let Some(s) = starter
+            && (s + 1 == len || last_class < class)
+            && let Some(composed) = nfc_compose(chars[s], ch)
+        {
+            chars[s] = composed;
+            continue;
+        }
+        if class == 0 {
+            starter = Some(len);
+        }
+        last_class = class;
+        chars[len] = ch;
+        len += 1;
+    }
+    chars.truncate(len);
+}
+
+/// Appends the full canonical decomposition of `ch` to `out`.
+fn nfc_decompose(ch: char, out: &mut Vec<char>) {
+    let c = ch as u32;
+    let jamo = |c| char::from_u32(c).unwrap();
+
+    if let Some(s) = c.checked_sub(HANGUL_S_BASE)
+        && s < HANGUL_S_COUNT
+    {
+        out.push(jamo(HANGUL_L_BASE + s / HANGUL_N_COUNT));
+        out.push(jamo(HANGUL_V_BASE + s % HANGUL_N_COUNT / HANGUL_T_COUNT));
+        if s % HANGUL_T_COUNT != 0 {
+            out.push(jamo(HANGUL_T_BASE + s % HANGUL_T_COUNT));
+        }
+        return;
+    }
+
+    match tables::CANONICAL_DECOMPOSITIONS.binary_search_by_key(&c, |&(c, _, _)| c) {
+        Ok(i) => {
+            let (_, first, second) = tables::CANONICAL_DECOMPOSITIONS[i];
+            nfc_decompose(jamo(first), out);
+            if second != 0 {
+                nfc_decompose(jamo(second), out);
+            }
+        }
+        Err(_) => out.push(ch),
+    }
+}
+
+/// Returns the primary composite of `a` and `b`, if any.
+fn nfc_compose(a: char, b: char) -> Option<char> {
+    let (a, b) = (a as u32, b as u32);
+
+    if (HANGUL_L_BASE..HANGUL_L_BASE + HANGUL_L_COUNT).contains(&a)
+        && (HANGUL_V_BASE..HANGUL_V_BASE + HANGUL_V_COUNT).contains(&b)
+    {
+        let lv = (a - HANGUL_L_BASE) * HANGUL_N_COUNT + (b - HANGUL_V_BASE) * HANGUL_T_COUNT;
+        return char::from_u32(HANGUL_S_BASE + lv);
+    }
+    if let Some(s) = a.checked_sub(HANGUL_S_BASE)
+        && s < HANGUL_S_COUNT
+        && s % HANGUL_T_COUNT == 0
+        && (HANGUL_T_BASE + 1..HANGUL_T_BASE + HANGUL_T_COUNT).contains(&b)
+    {
+        return char::from_u32(a + b - HANGUL_T_BASE);
+    }
+
+    let table = &tables::CANONICAL_COMPOSITIONS;
+    let i = table.binary_search_by_key(&(a, b), |&(a, b, _)| (a, b)).ok()?;
+    char::from_u32(table[i].2)
+}
+
+/// Whether `ch` starts a new segment for [`nfc`], because it can't interact with the chars before it.
+fn nfc_is_segment_start(ch: char) -> bool {
+    let c = ch as u32;
+    c < 0x300
+        || (canonical_combining_class(ch) == 0
+            && !(HANGUL_V_BASE..HANGUL_T_BASE + HANGUL_T_COUNT).contains(&c)
+            && tables::COMBINING_STARTERS.binary_search(&c).is_err())
+}
+
+fn canonical_combining_class(ch: char) -> u8 {
+    let c = ch as u32;
+    if c < 0x300 {
+        return 0;
+    }
+    let table = &tables::CANONICAL_COMBINING_CLASSES;
+    match table.partition_point(|&(first, _, _)| first <= c).checked_sub(1).map(|i| table[i]) {
+        Some((_, last, class)) if c <= last => class,
+        _ => 0,
+    }
+}
+
+// -----------------------------------------------------------------------------------------
+// Regex and Text implementation (Shared Logic)
+// -----------------------------------------------------------------------------------------
+
+#[derive(Default)]
+pub struct Text<'tb> {
+    /// Shared with the [`Regex`] objects searching it, to avoid copying the document.
+    pub content: Rc<String>,
+    /// The buffer that [`Text::refresh`] reads from, unless the `Text` is detached.
+    tb: Option<&'tb TextBuffer>,
+    /// The invalid UTF-8 that was replaced with U+FFFD of a different length during the last
+    /// refresh, in which case the `content` offsets don't match the buffer anymore.
+    replacements: Vec<ChangedSpan>,
+    /// The offsets at which lines start in `content`. Computed lazily by [`Text::offset_to_line_col`].
+    line_starts: OnceCell<Vec<usize>>,
+    /// Set by [`Text::set_normalize`]: The spans of `content` that were changed by normalization.
+    normalized: Option<Vec<ChangedSpan>>,
+    /// See [`Text::set_final_newline`].
+    final_newline: FinalNewline,
+}
+
+/// Whether a newline at the end of the text is followed by another, empty line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FinalNewline {
+    /// The newline terminates the last line, like POSIX tools see it: "a\n" has 1 line.
+    #[default]
+    Terminates,
+    /// The newline starts an empty last line, like editors show it: "a\n" has 2 lines,
+    /// and an empty text has a single empty line.
+    StartsLine,
+}
+
+impl Text<'static> {
+    /// A `Text` of `content` without a buffer, for compiling a [`Regex`] for it.
+    fn detached(content: Rc<String>) -> Self {
+        Text {
+            content,
+            tb: None,
+            replacements: Vec::new(),
+            line_starts: OnceCell::new(),
+            normalized: None,
+            final_newline: FinalNewline::default(),
+        }
+    }
+}
+
+impl<'tb> Text<'tb> {
+    /// Creates a snapshot of the contents of `tb`.
+    pub fn from_buffer(tb: &'tb TextBuffer) -> Self {
+        let mut t = Self {
+            content: Rc::default(),
+            tb: Some(tb),
+            replacements: Vec::new(),
+            line_starts: OnceCell::new(),
+            normalized: None,
+            final_newline: FinalNewline::default(),
+        };
+        t.refresh();
+        t
+    }
+
+    /// Releases the borrow of the buffer while keeping the contents, so that the `Text`
+    /// can be stored next to it, for instance by the [`TextBuffer`] itself.
+    /// It can't be refreshed until it's given back to the buffer via [`Text::attach`].
+    pub fn detach(self) -> Text<'static> {
+        self.rebind(None)
+    }
+
+    /// Lets [`Text::refresh`] read from `tb` again after [`Text::detach`].
+    /// `tb` must be the buffer the `Text` was created from, or [`Text::refresh_range`] breaks.
+    pub fn attach(self, tb: &TextBuffer) -> Text<'_> {
+        self.rebind(Some(tb))
+    }
+
+    fn rebind<'a>(self, tb: Option<&'a TextBuffer>) -> Text<'a> {
+        Text {
+            content: self.content,
+            tb,
+            replacements: self.replacements,
+            line_starts: self.line_starts,
+            normalized: self.normalized,
+            final_newline: self.final_newline,
+        }
+    }
+
+    /// Re-reads the contents of the underlying [`TextBuffer`]. Does nothing if it's detached.
+    pub fn refresh(&mut self) {
+        let Some(tb) = self.tb else {
+            return;
+        };
+        self.line_starts.take();
+        // This only copies the contents if a `Regex` still holds onto them.
+        let content = Rc::make_mut(&mut self.content);
+        content.clear();
+        content.reserve(tb.text_length());
+        self.replacements.clear();
+        Self::read_document(content, tb, &mut self.replacements);
+
+        if self.normalized.take().is_some() {
+            self.normalize();
+        }
+    }
+
+    /// Normalizes `content` to NFC, now and after every refresh, so that canonically equivalent
+    /// text can be found with [`Regex::NORMALIZE`] patterns: For instance, "é" as a single char
+    /// and as "e" followed by a combining accent. See [`normalize_nfc`].
+    ///
+    /// Offsets into `content` then no longer match the buffer. Use [`Text::original_range`]
+    /// to map matches back. Disabling normalization again re-reads the buffer.
+    pub fn set_normalize(&mut self, normalize: bool) {
+        if normalize && self.normalized.is_none() {
+            self.normalize();
+        } else if !normalize && self.normalized.take().is_some() {
+            self.refresh();
+        }
+    }
+
+    fn normalize(&mut self) {
+        self.line_starts.take();
+        let spans = match nfc(&self.content) {
+            Some((normalized, spans)) => {
+                self.content = Rc::new(normalized);
+                spans
+            }
+            None => Vec::new(),
+        };
+        self.normalized = Some(spans);
+    }
+
+    /// Maps `range` in `content`, for instance a match, back to the buffer, see [`Text::content_to_source`].
+    /// A range that starts or ends within a char that was changed is extended to all of it.
+    pub fn original_range(&self, range: Range<usize>) -> Range<usize> {
+        self.map_to_source(range.start, false)..self.map_to_source(range.end, true)
+    }
+
+    /// Maps `offset` in `content` back to the buffer, for instance to edit a search result there.
+    /// The two differ after [`Text::set_normalize`] and if the buffer contains invalid UTF-8,
+    /// whose replacement with U+FFFD may change the length. Otherwise, this returns `offset`.
+    ///
+    /// Offsets within a char that was changed are mapped to its start.
+    pub fn content_to_source(&self, offset: usize) -> usize {
+        self.map_to_source(offset, false)
+    }
+
+    fn map_to_source(&self, offset: usize, round_up: bool) -> usize {
+        let offset = match &self.normalized {
+            Some(spans) => map_changed_offset(spans, offset, round_up),
+            None => offset,
+        };
+        map_changed_offset(&self.replacements, offset, round_up)
+    }
+
+    /// Appends the contents of `doc` to `content`, replacing invalid UTF-8 with U+FFFD.
+    /// Replacements that change the length are recorded in `replacements`.
+    ///
+    /// Chunks may end in the middle of a character. Such incomplete sequences
+    /// are carried over and completed with the start of the next chunk.
+    fn read_document(
+        content: &mut String,
+        doc: &dyn ReadableDocument,
+        replacements: &mut Vec<ChangedSpan>,
+    ) {
+        let mut carry = [0; 4];
+        let mut carry_len = 0;
+        // The offset of `carry` in `doc`.
+        let mut carry_offset = 0;
+        let mut offset = 0;
+
+        loop {
+            let mut chunk = doc.read_forward(offset);
+            if chunk.is_empty() {
+                break;
+            }
+            let mut chunk_offset = offset;
+            offset += chunk.len();
+
+            if carry_len > 0 {
+                // `carry` starts with a valid lead byte, which tells us how long the sequence is.
+                // Only continuation bytes can complete it, anything else truncates it.
+                let need = utf8_sequence_len(carry[0]);
+                let take = chunk
+                    .iter()
+                    .take(need - carry_len)
+                    .take_while(|&&b| (0x80..=0xBF).contains(&b))
+                    .count();
+                carry[carry_len..carry_len + take].copy_from_slice(&chunk[..take]);
+                carry_len += take;
+                chunk = &chunk[take..];
+                chunk_offset += take;
+                if carry_len < need && chunk.is_empty() {
+                    continue;
+                }
+                // If the continuation bytes turned out to be invalid, this replaces them.
+                let carry_end = carry_offset + carry_len;
+                let rest =
+                    Self::push_utf8_lossy(content, &carry[..carry_len], carry_offset, replacements);
+                // A truncated sequence is replaced as a whole, like by `String::from_utf8_lossy`.
+                if !rest.is_empty() {
+                    let start = carry_end - rest.len();
+                    Self::push_replacement(content, start..carry_end, replacements);
+                }
+            }
+
+            let rest = Self::push_utf8_lossy(content, chunk, chunk_offset, replacements);
+            carry[..rest.len()].copy_from_slice(rest);
+            carry_len = rest.len();
+            carry_offset = offset - rest.len();
+        }
+
+        // A sequence that's still incomplete at the end of the document is invalid.
+        if carry_len > 0 {
+            Self::push_replacement(content, carry_offset..carry_offset + carry_len, replacements);
+        }
+    }
+
+    /// Like [`String::from_utf8_lossy`], but appends to `content` without an intermediate allocation.
+    /// Returns the incomplete sequence at the end of `bytes`, if any, without consuming it.
+    ///
+    /// `offset` is the offset of `bytes` in the document, for recording `replacements`.
+    fn push_utf8_lossy<'a>(
+        content: &mut String,
+        mut bytes: &'a [u8],
+        mut offset: usize,
+        replacements: &mut Vec<ChangedSpan>,
+    ) -> &'a [u8] {
+        loop {
+            // Valid UTF-8 is by far the most common case and can be copied as-is.
+            match str::from_utf8(bytes) {
+                Ok(valid) => {
+                    content.push_str(valid);
+                    return &[];
+                }
+                Err(err) => {
+                    let (valid, rest) = bytes.split_at(err.valid_up_to());
+                    content.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                    let Some(len) = err.error_len() else {
+                        return rest;
+                    };
+                    offset += valid.len();
+                    Self::push_replacement(content, offset..offset + len, replacements);
+                    bytes = &rest[len..];
+                    offset += len;
+                }
+            }
+        }
+    }
+
+    /// Appends U+FFFD for the invalid bytes at `source` in the document.
+    fn push_replacement(
+        content: &mut String,
+        source: Range<usize>,
+        replacements: &mut Vec<ChangedSpan>,
+    ) {
+        let start = content.len();
+        content.push(char::REPLACEMENT_CHARACTER);
+        if source.len() != content.len() - start {
+            replacements.push(ChangedSpan { new: start..content.len(), old: source });
+        }
+    }
+
+    /// Like [`Text::refresh`], but only re-reads `range`: The range of bytes in `content`
+    /// that were modified. It may have grown or shrunk, but everything around it must be unchanged.
+    ///
+    /// Falls back to a full refresh if the buffer contains invalid UTF-8 or [`Text::set_normalize`] is on.
+    pub fn refresh_range(&mut self, range: Range<usize>) {
+        let Some(tb) = self.tb else {
+            return;
+        };
+        self.line_starts.take();
+        let content = Rc::make_mut(&mut self.content);
+        let mut beg = range.start.min(content.len());
+        let mut end = range.end.clamp(beg, content.len());
+
+        while !content.is_char_boundary(beg) {
+            beg -= 1;
+        }
+        while !content.is_char_boundary(end) {
+            end += 1;
+        }
+
+        // Where `end` ended up in the buffer after the modification.
+        let new_end = (end + tb.text_length()).checked_sub(content.len());
+
+        match new_end {
+            Some(new_end)
+                if self.replacements.is_empty() && self.normalized.is_none() && new_end >= beg =>
+            {
+                let mut bytes = Vec::with_capacity(new_end - beg);
+                let mut offset = beg;
+                while offset < new_end {
+                    let chunk = tb.read_forward(offset);
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    let len = chunk.len().min(new_end - offset);
+                    bytes.extend_from_slice(&chunk[..len]);
+                    offset += len;
+                }
+
+                content.replace_range(beg..end, &String::from_utf8_lossy(&bytes));
+                if content.len() != tb.text_length() {
+                    self.refresh();
+                }
+            }
+            _ => self.refresh(),
+        }
+    }
+
+    /// Chooses whether a newline at the end of `content` is followed by another, empty line
+    /// in [`Text::lines`], [`Text::line_count`] and [`Text::offset_to_line_col`].
+    /// By default it isn't, see [`FinalNewline::Terminates`].
+    pub fn set_final_newline(&mut self, final_newline: FinalNewline) {
+        self.final_newline = final_newline;
+    }
+
+    /// Iterates over the lines in `content` as `(offset, line)` pairs, without allocating.
+    ///
+    /// Lines are split at `\n` and don't include the line ending, including the `\r` of a `\r\n`.
+    /// Just like [`str::lines`], a trailing newline doesn't yield an empty last line,
+    /// unless [`FinalNewline::StartsLine`] is set.
+    pub fn lines(&self) -> impl Iterator<Item = (usize, &str)> {
+        let text = self.content.as_str();
+        let mut offset = 0;
+        let mut final_line = self.final_newline == FinalNewline::StartsLine
+            && (text.is_empty() || text.ends_with('\n'));
+
+        std::iter::from_fn(move || {
+            if offset >= text.len() {
+                if final_line {
+                    final_line = false;
+                    return Some((text.len(), ""));
+                }
+                return None;
+            }
+
+            let beg = offset;
+            let end = find_newline(text.as_bytes(), beg);
+            offset = end + 1;
+
+            let mut line = &text[beg..end];
+            if end < text.len() {
+                line = line.strip_suffix('\r').unwrap_or(line);
+            }
+            Some((beg, line))
+        })
+    }
+
+    /// Returns the number of lines in `content`, for instance for the status bar.
+    /// This is the number of lines that [`Text::lines`] yields: A trailing newline doesn't
+    /// start another, empty line, so both "a\nb" and "a\nb\n" have 2 lines, and "" has none.
+    /// With [`FinalNewline::StartsLine`], it's always the number of newlines plus one.
+    pub fn line_count(&self) -> usize {
+        if self.final_newline == FinalNewline::StartsLine {
+            return self.count_char('\n') + 1;
+        }
+        let unterminated = !self.content.is_empty() && !self.content.ends_with('\n');
+        self.count_char('\n') + unterminated as usize
+    }
+
+    /// Returns `content[range]`, or `None` if `range` is out of bounds or not on char boundaries,
+    /// for instance because the buffer changed since the search that found `range`.
+    pub fn get_slice(&self, range: Range<usize>) -> Option<&str> {
+        self.content.get(range)
+    }
+
+    /// Returns how often `c` occurs in `content`.
+    pub fn count_char(&self, c: char) -> usize {
+        if c.is_ascii() {
+            self.content.bytes().filter(|&b| b == c as u8).count()
+        } else {
+            self.content.matches(c).count()
+        }
+    }
+
+    /// Converts a byte offset in `content` into a 0-based `(line, column)` pair.
+    ///
+    /// The column is the number of Unicode scalar values since the start of the line,
+    /// so tabs and multi-byte characters count as one column each.
+    /// Offsets past the end are clamped to the end, and offsets inside a character to its start.
+    /// Unless [`FinalNewline::StartsLine`] is set, the end of a text with a trailing newline
+    /// is the end of the last line, as there's no empty line after it.
+    pub fn offset_to_line_col(&self, offset: usize) -> (usize, usize) {
+        let (line, line_start, offset) = self.line_of(offset);
+        let column = self.content[line_start..offset].chars().count();
+        (line, column)
+    }
+
+    /// Like [`Text::offset_to_line_col`], but the column counts grapheme clusters,
+    /// so that combining marks and emoji sequences (e.g. with a skin tone modifier)
+    /// don't add columns. Offsets inside a cluster are counted as if they were at its end.
+    ///
+    /// This uses the same segmentation as the editor itself, so it doesn't need a fallback.
+    pub fn offset_to_grapheme_col(&self, offset: usize) -> (usize, usize) {
+        let (line, line_start, offset) = self.line_of(offset);
+        let line_text = self.content[line_start..offset].as_bytes();
+        let cursor = MeasurementConfig::new(&line_text).goto_offset(line_text.len());
+        (line, cursor.logical_pos.x as usize)
+    }
+
+    /// Returns the range of the word that contains the char at `offset`, for instance to search for
+    /// the word under the cursor together with [`escape_literal`]. Words consist of alphanumeric
+    /// chars and underscores.
+    ///
+    /// Returns `None` if there's no word char at `offset` or if `offset` isn't on a char boundary.
+    pub fn word_at(&self, offset: usize) -> Option<Range<usize>> {
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+        let text = self.content.as_str();
+        if !text.get(offset..)?.chars().next().is_some_and(is_word_char) {
+            return None;
+        }
+
+        let beg = text[..offset]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| is_word_char(c))
+            .last()
+            .map_or(offset, |(i, _)| i);
+        let end = text[offset..].find(|c| !is_word_char(c)).map_or(text.len(), |i| offset + i);
+        Some(beg..end)
+    }
+
+    /// Returns the 0-based line that contains `offset`, the offset at which that line starts,
+    /// and `offset` itself, clamped to the text and rounded down to a char boundary.
+    fn line_of(&self, offset: usize) -> (usize, usize, usize) {
+        let text = self.content.as_str();
+        let mut offset = offset.min(text.len());
+        while !text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        if offset == text.len()
+            && self.final_newline == FinalNewline::Terminates
+            && let Some(rest) = text.strip_suffix('\n')
+        {
+            offset = rest.strip_suffix('\r').unwrap_or(rest).len();
+        }
+
+        let line_starts = self.line_starts.get_or_init(|| {
+            let mut starts = vec![0];
+            let mut off = 0;
+            loop {
+                off = find_newline(text.as_bytes(), off) + 1;
+                if off > text.len() {
+                    break;
+                }
+                starts.push(off);
+            }
+            starts
+        });
+
+        let line = line_starts.partition_point(|&start| start <= offset) - 1;
+        (line, line_starts[line], offset)
+    }
+}
+
+/// Finds the first occurrence of the exact byte sequence `needle` in `text` at or after `start`,
+/// for instance for a hex view. This works the same with or without the `regex` feature.
+///
+/// The search is byte-oriented, so the returned range may not be on char boundaries.
+/// An empty `needle` matches at `start`.
+pub fn find_bytes(text: &Text, needle: &[u8], start: usize) -> Option<Range<usize>> {
+    let beg = find_substring(text.content.as_bytes(), needle, start)?;
+    Some(beg..beg + needle.len())
+}
+
+/// Returns the index of the first occurrence of `needle` in `haystack` at or after `offset`.
+/// Candidates are found with [`memchr2`] on the first byte of `needle` and then compared in full.
+fn find_substring(haystack: &[u8], needle: &[u8], mut offset: usize) -> Option<usize> {
+    let Some((&first, rest)) = needle.split_first() else {
+        return (offset <= haystack.len()).then_some(offset);
+    };
+    loop {
+        offset = memchr2(first, first, haystack, offset);
+        if haystack.len() - offset < needle.len() {
+            return None;
+        }
+        if haystack[offset + 1..].starts_with(rest) {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+}
+
+/// The options for [`Regex::with_options`]. A readable alternative to the `Regex::LITERAL`, etc. flags.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegexOptions {
+    pub case_insensitive: bool,
+    /// Lets `^` and `$` match at the start and end of lines.
+    pub multi_line: bool,
+    /// Searches for the pattern as-is, without interpreting any metacharacters.
+    pub literal: bool,
+    /// Only matches the pattern if it's surrounded by non-word characters.
+    pub whole_word: bool,
+    /// Lets `.` match line breaks.
+    pub dot_all: bool,
+    /// Treats `\r\n` as a single line break, for documents with CRLF line endings:
+    /// `^` and `$` don't match between the `\r` and `\n`, and `.` doesn't match `\r`,
+    /// so that a `(.*)$` group doesn't end with a dangling `\r`. Offsets are unaffected.
+    pub crlf: bool,
+    /// Normalizes the pattern to NFC, see [`normalize_nfc`]. Only useful together with
+    /// [`Text::set_normalize`], which does the same for the text.
+    pub normalize: bool,
+}
+
+impl RegexOptions {
+    /// Decodes a bitmask of `Regex::CASE_INSENSITIVE`, `Regex::MULTILINE`, etc.
+    pub fn from_flags(flags: i32) -> Self {
+        Self {
+            case_insensitive: (flags & Regex::CASE_INSENSITIVE) != 0,
+            multi_line: (flags & Regex::MULTILINE) != 0,
+            literal: (flags & Regex::LITERAL) != 0,
+            whole_word: (flags & Regex::WHOLE_WORD) != 0,
+            dot_all: (flags & Regex::DOT_ALL) != 0,
+            crlf: (flags & Regex::CRLF) != 0,
+            normalize: (flags & Regex::NORMALIZE) != 0,
+        }
+    }
+}
+
+/// Escapes `s` so that it matches literally when embedded into a [`Regex`] pattern,
+/// for instance to search for the word under the cursor.
+///
+/// Lite mode searches for all patterns literally, so it returns `s` unchanged.
+pub fn escape_literal<'a>(arena: &'a Arena, s: &str) -> ArenaString<'a> {
+    #[cfg(feature = "regex")]
+    let s = &*regex::escape(s);
+    ArenaString::from_str(arena, s)
+}
+
+/// Returns whether `pattern` contains none of the regex metacharacters `.^$*+?()[]{}|\`,
+/// in which case it matches the same text with or without [`Regex::LITERAL`].
+/// For instance, to hint in the UI that a pattern doesn't make use of regular expressions.
+pub fn is_plain_literal(pattern: &str) -> bool {
+    !pattern.bytes().any(|b| b".^$*+?()[]{}|\\".contains(&b))
+}
+
+/// Sorts `ranges` by their start and merges overlapping and adjacent ones, so that the result
+/// can be highlighted without painting anything twice. Empty ranges are removed.
+pub fn merge_ranges(ranges: &mut Vec<Range<usize>>) {
+    ranges.retain(|r| !r.is_empty());
+    ranges.sort_unstable_by_key(|r| r.start);
+
+    let mut merged = 0;
+    for i in 0..ranges.len() {
+        if merged > 0 && ranges[i].start <= ranges[merged - 1].end {
+            ranges[merged - 1].end = ranges[merged - 1].end.max(ranges[i].end);
+        } else {
+            ranges[merged] = ranges[i].clone();
+            merged += 1;
+        }
+    }
+    ranges.truncate(merged);
+}
+
+/// A match along with its groups, as returned by [`Regex::captures_iter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    pub range: Range<usize>,
+    /// Group 0 is the match itself. Groups that didn't participate in the match are `None`.
+    pub groups: Vec<Option<Range<usize>>>,
+}
+
+/// Appends `replacement` to `out`, expanding the references `$0`, `$1`, ... and `${name}`
+/// to the `text` of the group that `group` resolves them to.
+///
+/// `$$` expands to a literal `$`. References that `group` can't resolve are copied as-is.
+///
+/// Just like in sed, `\U` and `\L` uppercase or lowercase everything that follows,
+/// literal text and groups alike, up to the next `\E`, `\U` or `\L`. Since that makes
+/// the backslash special, `\\` expands to a literal `\`. Other backslashes are copied as-is,
+/// so that for instance `C:\Temp` remains unchanged, but `C:\Users` needs to be `C:\\Users`.
+fn expand_replacement(
+    out: &mut ArenaString,
+    replacement: &str,
+    text: &str,
+    group: impl Fn(&str) -> Option<Range<usize>>,
+) {
+    let mut rest = replacement;
+    // The start of the text in `out` that `\U` or `\L` apply to, and whether it's `\U`.
+    let mut transform: Option<(usize, bool)> = None;
+
+    while let Some(i) = rest.find(['$', '\\']) {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+
+        if rest.as_bytes()[i] == b'\\' {
+            let token = after.chars().next();
+            if let Some('U' | 'L' | 'E') = token
+                && let Some((start, upper)) = transform.take()
+            {
+                convert_case(out, start, upper);
+            }
+            match token {
+                Some(c @ ('U' | 'L')) => transform = Some((out.len(), c == 'U')),
+                Some('E') => {}
+                Some('\\') => out.push('\\'),
+                _ => {
+                    out.push('\\');
+                    rest = after;
+                    continue;
+                }
+            }
+            rest = &after[1..];
+            continue;
+        }
+
+        if let Some(after) = after.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+
+        let (name, len) = match after.strip_prefix('{').and_then(|s| s.find('}').map(|e| &s[..e])) {
+            Some(name) => (name, name.len() + 2),
+            None => {
+                let digits = after.bytes().take_while(u8::is_ascii_digit).count();
+                (&after[..digits], digits)
+            }
+        };
+
+        match if name.is_empty() { None } else { group(name) } {
+            Some(range) => out.push_str(&text[range]),
+            None => out.push_str(&rest[i..i + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+
+    out.push_str(rest);
+    if let Some((start, upper)) = transform {
+        convert_case(out, start, upper);
+    }
+}
+
+/// Uppercases or lowercases `out[start..]` for the `\U` and `\L` of [`expand_replacement`].
+fn convert_case(out: &mut ArenaString, start: usize, upper: bool) {
+    // Just like in `adapt_case`, the original is removed after appending the converted version.
+    let end = out.len();
+    let mut off = start;
+    while off < end {
+        let ch = out[off..].chars().next().unwrap();
+        if upper {
+            out.extend(ch.to_uppercase());
+        } else {
+            out.extend(ch.to_lowercase());
+        }
+        off += ch.len_utf8();
+    }
+    out.replace_range(start..end, "");
+}
+
+/// Adapts the case of `out[start..]` to that of `matched`, see [`Regex::set_preserve_case`]:
+/// It's uppercased if `matched` is all uppercase, and capitalized if `matched` is.
+fn adapt_case(out: &mut ArenaString, start: usize, matched: &str) {
+    let mut chars = matched.chars().filter(|c| c.is_alphabetic());
+    let Some(first) = chars.next() else {
+        return;
+    };
+    if !first.is_uppercase() {
+        return;
+    }
+    let all_upper = !chars.any(char::is_lowercase);
+
+    // Append the uppercased version and then remove the original, to avoid a temporary copy.
+    let end = out.len();
+    let mut off = start;
+    while off < end {
+        let ch = out[off..].chars().next().unwrap();
+        if all_upper || off == start {
+            out.extend(ch.to_uppercase());
+        } else {
+            out.push(ch);
+        }
+        off += ch.len_utf8();
+    }
+    out.replace_range(start..end, "");
+}
+
+// -----------------------------------------------------------------------------------------
+// Implementation 1: FULL MODE (Using regex crate)
+// -----------------------------------------------------------------------------------------
+/// The number of compiled patterns [`Regex::build_cached`] keeps around per thread.
+#[cfg(feature = "regex")]
+const REGEX_CACHE_SIZE: usize = 8;
+
+/// The maximum size of a compiled pattern. Patterns like `a{1000000}{1000000}` exceed this
+/// and fail with [`IcuError::InvalidPattern`], instead of allocating gigabytes of memory.
+#[cfg(feature = "regex")]
+const REGEX_SIZE_LIMIT: usize = 8 * 1024 * 1024;
+
+/// The maximum amount of memory the lazy DFA of a compiled pattern may use.
+#[cfg(feature = "regex")]
+const REGEX_DFA_SIZE_LIMIT: usize = 2 * 1024 * 1024;
+
+#[cfg(feature = "regex")]
+thread_local! {
+    /// Recently compiled patterns, most recently used first. Typing into the search box
+    /// and "Find Next" compile the same patterns over and over again.
+    static REGEX_CACHE: RefCell<Vec<(String, RegexOptions, regex::Regex)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+#[cfg(feature = "regex")]
+pub struct Regex {
+    /// The compiled automaton. `None` for literals, which don't need it, see [`Regex::literal`].
+    inner: Option<regex::Regex>,
+    /// Set for case-sensitive patterns without metacharacters, see [`Regex::find_literal`].
+    literal: Option<String>,
+    options: RegexOptions,
+    /// `inner` anchored to both ends of the text. Compiled lazily by [`Regex::is_full_match`].
+    anchored: OnceCell<Option<regex::Regex>>,
+    text: Rc<String>,
+    last_idx: usize,
+    /// Set while `last_idx` is right after the current match, because [`Iterator::next`]
+    /// found it, so that [`Regex::prev`] doesn't find the same match again.
+    after_next: bool,
+    /// Matches must lie within this range, see [`Regex::set_search_range`].
+    search_range: Range<usize>,
+    /// The groups of the current match. `None` for groups that didn't participate in it.
+    captures: Option<Vec<Option<Range<usize>>>>,
+    /// See [`Regex::set_preserve_case`].
+    preserve_case: bool,
+    /// See [`Regex::set_region_filter`].
+    region_filter: Option<Box<dyn Fn(usize) -> bool>>,
+}
+
+#[cfg(feature = "regex")]
+impl Regex {
+    pub const CASE_INSENSITIVE: i32 = 1;
+    pub const MULTILINE: i32 = 2;
+    pub const LITERAL: i32 = 4;
+    pub const WHOLE_WORD: i32 = 8;
+    /// Lets `.` match line breaks. Independent of `MULTILINE`, which only affects `^` and `$`.
+    pub const DOT_ALL: i32 = 16;
+    /// See [`RegexOptions::crlf`].
+    pub const CRLF: i32 = 32;
+    /// See [`RegexOptions::normalize`].
+    pub const NORMALIZE: i32 = 64;
+
+    /// Compiles `pattern` and prepares it for searching `text`.
+    /// Same as [`Regex::with_options`], but with the options given as a bitmask of flags.
+    /// The `text` contents are shared, so it may be dropped afterwards.
+    pub fn new(pattern: &str, flags: i32, text: &Text<'_>) -> apperr::Result<Self> {
+        Self::with_options(pattern, &RegexOptions::from_flags(flags), text)
+    }
+
+    /// Compiles `pattern` and prepares it for searching `text`.
+    pub fn with_options(
+        pattern: &str,
+        options: &RegexOptions,
+        text: &Text<'_>,
+    ) -> apperr::Result<Self> {
+        let pattern = &*if options.normalize { normalize_nfc(pattern) } else { pattern.into() };
+        // Case-insensitive literals still go through the automaton,
+        // because its Unicode case folding isn't trivial to replicate.
+        let plain = options.literal || is_plain_literal(pattern);
+        let simple = !pattern.is_empty() && !options.case_insensitive && !options.whole_word;
+        let literal = (plain && simple).then(|| pattern.to_string());
+        // Literals are searched for without the automaton, so it isn't compiled in the first place.
+        let inner = match literal {
+            Some(_) => None,
+            None => Some(
+                Self::build_cached(&Self::prepare_pattern(pattern, options), options)
+                    .map_err(|err| Self::pattern_error(&err))?,
+            ),
+        };
+
+        Ok(Self {
+            inner,
+            literal,
+            options: *options,
+            anchored: OnceCell::new(),
+            text: Rc::clone(&text.content),
+            last_idx: 0,
+            after_next: false,
+            search_range: 0..usize::MAX,
+            captures: None,
+            preserve_case: false,
+            region_filter: None,
+        })
+    }
+
+    /// Maps a build error to [`IcuError::UnknownProperty`] if it's about a `\p{...}` name
+    /// or value that isn't supported, and to [`IcuError::InvalidPattern`] otherwise.
+    fn pattern_error(err: &regex::Error) -> apperr::Error {
+        let unknown_property = match err {
+            regex::Error::Syntax(msg) => msg.lines().any(|l| {
+                l == "error: Unicode property not found"
+                    || l == "error: Unicode property value not found"
+            }),
+            _ => false,
+        };
+        let error =
+            if unknown_property { IcuError::UnknownProperty } else { IcuError::InvalidPattern };
+        apperr::Error::new_icu(error.code())
+    }
+
+    /// Checks whether `pattern` compiles with the given `flags`, without the need for a [`Text`].
+    ///
+    /// On failure, returns the approximate byte offset of the error in `pattern` and a description.
+    pub fn validate_pattern(pattern: &str, flags: i32) -> Result<(), (usize, String)> {
+        let options = RegexOptions::from_flags(flags);
+        if options.literal {
+            return Ok(());
+        }
+
+        match Self::build(pattern, &options) {
+            Ok(_) => Ok(()),
+            Err(regex::Error::Syntax(msg)) => Err(Self::parse_syntax_error(pattern, &msg)),
+            Err(err) => Err((0, err.to_string())),
+        }
+    }
+
+    /// Extracts the offset and message from a [`regex::Error::Syntax`], which looks like this:
+    /// ```text
+    /// regex parse error:
+    ///     a(b
+    ///      ^
+    /// error: unclosed group
+    /// ```
+    /// The caret line is only there for single-line patterns. Otherwise, the offset is 0.
+    fn parse_syntax_error(pattern: &str, msg: &str) -> (usize, String) {
+        let lines: Vec<_> = msg.lines().collect();
+        let message = lines.iter().rev().find_map(|l| l.strip_prefix("error: ")).unwrap_or(msg);
+
+        let mut offset = 0;
+        if let [_, p, caret, ..] = lines[..]
+            && p.strip_prefix("    ") == Some(pattern)
+            && let Some(column) = caret.find('^')
+        {
+            // The column counts chars, not bytes.
+            let column = column.saturating_sub(4);
+            offset = pattern.char_indices().nth(column).map_or(pattern.len(), |(i, _)| i);
+        }
+
+        (offset, message.to_string())
+    }
+
+    /// Applies the `literal` and `whole_word` options to `pattern`.
+    fn prepare_pattern<'p>(pattern: &'p str, options: &RegexOptions) -> Cow<'p, str> {
+        let mut pattern = Cow::Borrowed(pattern);
+        if options.literal {
+            pattern = Cow::Owned(regex::escape(&pattern));
+        }
+        if options.whole_word {
+            pattern = Cow::Owned(format!(r"\b(?:{pattern})\b"));
+        }
+        pattern
+    }
+
+    /// See [`next_match_offset`].
+    fn next_offset(&self, range: &Range<usize>) -> usize {
+        next_match_offset(&self.text, range)
+    }
+
+    /// Like [`Regex::build`], but reuses recently compiled patterns.
+    /// Cloning a `regex::Regex` is cheap, as it shares the compiled automaton.
+    fn build_cached(pattern: &str, options: &RegexOptions) -> Result<regex::Regex, regex::Error> {
+        REGEX_CACHE.with_borrow_mut(|cache| {
+            if let Some(i) = cache.iter().position(|(p, o, _)| p == pattern && o == options) {
+                let entry = cache.remove(i);
+                let inner = entry.2.clone();
+                cache.insert(0, entry);
+                return Ok(inner);
+            }
+
+            let inner = Self::build(pattern, options)?;
+            cache.truncate(REGEX_CACHE_SIZE - 1);
+            cache.insert(0, (pattern.to_string(), *options, inner.clone()));
+            Ok(inner)
+        })
+    }
+
+    fn build(pattern: &str, options: &RegexOptions) -> Result<regex::Regex, regex::Error> {
+        regex::RegexBuilder::new(pattern)
+            .case_insensitive(options.case_insensitive)
+            .multi_line(options.multi_line)
+            .dot_matches_new_line(options.dot_all)
+            .crlf(options.crlf)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+            .build()
+    }
+
+    /// Refreshes `text` and continues searching it from `offset`.
+    pub fn set_text(&mut self, text: &mut Text<'_>, offset: usize) {
+        // Release our reference first, so that `refresh` can reuse the allocation.
+        self.text = Rc::default();
+        text.refresh();
+        self.text = Rc::clone(&text.content);
+        self.captures = None;
+        self.reset(offset);
+    }
+
+    /// Reads what was appended to the buffer of `text` since the last refresh and continues
+    /// searching into it, for instance when following a growing log file. Unlike
+    /// [`Regex::set_text`] the position is kept and only the appended bytes are read.
+    ///
+    /// A match that ended at the old end of the text may continue into the appended text
+    /// (think `a+` over "aa" + "a"), so it's searched for again and reported anew if it changed.
+    pub fn append_text(&mut self, text: &mut Text<'_>) {
+        let old_len = self.text.len();
+        // Release our reference first, so that `refresh_range` can append in place.
+        self.text = Rc::default();
+        text.refresh_range(old_len..old_len);
+        self.text = Rc::clone(&text.content);
+
+        if let Some(range) = self.current_match()
+            && range.end == old_len
+            && self.last_idx >= old_len
+            && self.find_at(range.start) != Some(range.clone())
+        {
+            self.last_idx = range.start;
+        }
+    }
+
+    /// Continues searching from `offset`. The current match and its groups are kept,
+    /// so that it can still be replaced after moving elsewhere, see [`Regex::current_match`].
+    pub fn reset(&mut self, offset: usize) {
+        self.last_idx = offset;
+        self.after_next = false;
+    }
+
+    /// Returns the most recent match, even after a [`Regex::reset`].
+    pub fn current_match(&self) -> Option<Range<usize>> {
+        self.captures.as_ref()?.first().cloned().flatten()
+    }
+
+    /// Returns the groups of the most recent match, starting with the match itself as group 0,
+    /// even after a [`Regex::reset`]. Groups that didn't participate in the match are `None`.
+    pub fn current_captures(&self) -> Option<&[Option<Range<usize>>]> {
+        self.captures.as_deref()
+    }
+
+    /// Confines the search to matches within `range`, for instance for "Replace in Selection",
+    /// and moves the position to its start. Match and group offsets remain relative to the entire text.
+    ///
+    /// Pass `0..usize::MAX` to search the entire text again.
+    pub fn set_search_range(&mut self, range: Range<usize>) {
+        self.reset(range.start);
+        self.search_range = range;
+    }
+
+    /// Makes replacements adopt the case of the text they replace: Replacing "color" with "colour"
+    /// turns "COLOR" into "COLOUR" and "Color" into "Colour". Other matches are replaced as-is.
+    pub fn set_preserve_case(&mut self, preserve_case: bool) {
+        self.preserve_case = preserve_case;
+    }
+
+    /// Only accepts matches starting at an offset for which `filter` returns `true`, so that
+    /// a tokenizer can restrict the search to code and skip comments and strings, or vice versa.
+    /// Rejected matches are skipped as if they didn't exist, and the search continues after them.
+    pub fn set_region_filter(&mut self, filter: Box<dyn Fn(usize) -> bool>) {
+        self.region_filter = Some(filter);
+    }
+
+    /// Removes the filter set with [`Regex::set_region_filter`].
+    pub fn clear_region_filter(&mut self) {
+        self.region_filter = None;
+    }
+
+    /// Like [`Iterator::next`], but continues at the start of the search range once the end
+    /// has been reached ("Find Next"). The `bool` indicates whether the search wrapped around.
+    ///
+    /// Returns `None` if there's no match anywhere.
+    pub fn next_wrapping(&mut self) -> Option<(Range<usize>, bool)> {
+        let start = self.last_idx;
+        if let Some(range) = self.next() {
+            return Some((range, false));
+        }
+
+        self.reset(self.search_range.start);
+        match self.next() {
+            Some(range) if range.start < start => Some((range, true)),
+            _ => {
+                self.reset(start);
+                None
+            }
+        }
+    }
+
+    pub fn group_count(&mut self) -> i32 {
+        if let Some(caps) = &self.captures {
+            (caps.len() as i32).saturating_sub(1)
+        } else {
+            0
+        }
+    }
+
+    /// Returns the range of group `group` in the current match, or `None` if it doesn't exist
+    /// or didn't participate in the match, like the `(x)` in `a(x)?` matching "a".
+    pub fn group(&mut self, group: i32) -> Option<Range<usize>> {
+        if let Some(caps) = &self.captures {
+            caps.get(group as usize).cloned().flatten()
+        } else {
+            None
+        }
+    }
+
+    /// Like [`Regex::group`], but shifted by `base`, for instance to map the group
+    /// from a search in a window of a document back to the document itself.
+    pub fn group_absolute(&mut self, group: i32, base: usize) -> Option<Range<usize>> {
+        self.group(group).map(|r| r.start + base..r.end + base)
+    }
+
+    /// Returns the range of the group named `name` in the current match, as in `(?P<name>...)`.
+    pub fn group_by_name(&mut self, name: &str) -> Option<Range<usize>> {
+        let idx = self.group_index(name)?;
+        self.captures.as_ref()?.get(idx).cloned().flatten()
+    }
+
+    /// Returns the names of all groups in order, starting with the implicit group 0.
+    /// Unnamed groups yield `None`.
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+        // Literals only have the implicit group 0.
+        let names = self.inner.as_ref().map(|inner| inner.capture_names().skip(1));
+        std::iter::once(None).chain(names.into_iter().flatten())
+    }
+
+    /// Finds the last match that ends at or before the current position ("Find Previous").
+    /// The position is moved to the start of the match, so that repeated calls walk backwards.
+    /// Right after [`Iterator::next`] it's the match before the current one, not the same one.
+    pub fn prev(&mut self) -> Option<Range<usize>> {
+        let mut limit = self.last_idx.min(self.text.len()).min(self.search_range.end);
+        if let Some(m) = self.current_match()
+            && self.after_next
+        {
+            limit = limit.min(m.start);
+        }
+        let mut offset = self.search_range.start;
+        let mut found = None;
+
+        while offset <= limit {
+            let Some(caps) = self.captures_at(offset) else {
+                break;
+            };
+            let m = caps[0].clone().unwrap();
+            // An empty match at `limit` would be returned over and over again.
+            if m.end > limit || m.start >= limit {
+                break;
+            }
+            offset = self.next_offset(&m);
+            if self.in_region(m.start) {
+                found = Some(caps);
+            }
+        }
+
+        let caps = found?;
+        let range = caps[0].clone().unwrap();
+        self.captures = Some(caps);
+        self.reset(range.start);
+        Some(range)
+    }
+
+    /// Finds the last match within the search range, for instance to jump to the last error
+    /// in a build log. Like with [`Regex::prev`], the position is moved to the start of the
+    /// match, so that `prev` continues with the match before it. `find_last` itself always
+    /// starts over at the end.
+    ///
+    /// The match may span multiple lines, if the pattern allows for it. To get the last matching
+    /// line as a whole, use a [`Regex::MULTILINE`] pattern like `^.*error.*$`.
+    pub fn find_last(&mut self) -> Option<Range<usize>> {
+        let last_idx = self.last_idx;
+        self.reset(usize::MAX);
+        let range = self.prev();
+        if range.is_none() {
+            self.reset(last_idx);
+        }
+        range
+    }
+
+    /// Jumps to the `n`-th (0-based) match, counting from the start of the text,
+    /// for instance to go to a result picked from a list of matches.
+    ///
+    /// Afterwards the captures of that match are available via [`Regex::group`]
+    /// and [`Iterator::next`] continues with match `n + 1`.
+    pub fn nth_match(&mut self, n: usize) -> Option<Range<usize>> {
+        self.reset(0);
+        self.nth(n)
+    }
+
+    /// Counts the matches in the entire text, without disturbing the current search position.
+    pub fn count_matches(&self) -> usize {
+        self.count_matches_from(self.search_range.start)
+    }
+
+    /// Counts the matches that [`Iterator::next`] has yet to return, without disturbing
+    /// the current search position. Together with [`Regex::count_matches`] this gives "3 of 17".
+    pub fn remaining_matches(&self) -> usize {
+        self.count_matches_from(self.last_idx)
+    }
+
+    fn count_matches_from(&self, offset: usize) -> usize {
+        let mut count = 0;
+        let mut offset = offset.max(self.search_range.start);
+        while offset <= self.text.len() {
+            let Some(range) = self.find_at(offset) else {
+                break;
+            };
+            if range.end > self.search_range.end {
+                break;
+            }
+            count += 1;
+            offset = self.next_offset(&range);
+        }
+        count
+    }
+
+    /// Returns the first match at or after `offset`, ignoring the search range.
+    fn find_at(&self, offset: usize) -> Option<Range<usize>> {
+        self.find_in(&self.text, offset)
+    }
+
+    /// Like [`Regex::find_at`], but searches `haystack` instead of the text of the regex.
+    fn find_in(&self, haystack: &str, mut offset: usize) -> Option<Range<usize>> {
+        loop {
+            if offset > haystack.len() {
+                return None;
+            }
+            let range = match &self.inner {
+                Some(inner) => inner.find_at(haystack, offset)?.range(),
+                None => self.find_literal(haystack, offset)?,
+            };
+            if self.in_region(range.start) {
+                return Some(range);
+            }
+            offset = next_match_offset(haystack, &range);
+        }
+    }
+
+    /// Checks a match start against the filter of [`Regex::set_region_filter`].
+    fn in_region(&self, offset: usize) -> bool {
+        self.region_filter.as_ref().is_none_or(|filter| filter(offset))
+    }
+
+    /// Plain substring search for `literal`, which is a lot cheaper than running the regex
+    /// automaton for the common case of typing a word into the search bar.
+    fn find_literal(&self, haystack: &str, offset: usize) -> Option<Range<usize>> {
+        let literal = self.literal.as_deref()?;
+        // Since the literal is valid UTF-8, it can't match in the middle of a character,
+        // even if `offset` is.
+        let start = find_substring(haystack.as_bytes(), literal.as_bytes(), offset)?;
+        Some(start..start + literal.len())
+    }
+
+    /// Returns the groups of the first match at or after `offset`,
+    /// ignoring the search range and the region filter.
+    fn captures_at(&self, offset: usize) -> Option<Vec<Option<Range<usize>>>> {
+        match &self.inner {
+            Some(inner) => {
+                Some(Self::capture_ranges(&inner.captures_at(self.text.as_str(), offset)?))
+            }
+            None => Some(vec![Some(self.find_literal(&self.text, offset)?)]),
+        }
+    }
+
+    /// Collects up to `max` matches from the start of the search range,
+    /// without disturbing the current search position.
+    pub fn find_all(&mut self, max: usize) -> Vec<Range<usize>> {
+        let last_idx = self.last_idx;
+        let after_next = self.after_next;
+        let captures = self.captures.take();
+
+        self.reset(self.search_range.start);
+        let matches = self.by_ref().take(max).collect();
+
+        self.last_idx = last_idx;
+        self.after_next = after_next;
+        self.captures = captures;
+        matches
+    }
+
+    /// Collects the matches within `range`, for instance the visible part of the document,
+    /// as sorted, disjoint spans for highlighting them, see [`merge_ranges`].
+    /// Just like [`Regex::find_all`], this doesn't disturb the current search position.
+    pub fn find_all_merged(&mut self, range: Range<usize>) -> Vec<Range<usize>> {
+        let start = range.start.max(self.search_range.start);
+        let end = range.end.min(self.search_range.end);
+        if start > end {
+            return Vec::new();
+        }
+
+        let search_range = std::mem::replace(&mut self.search_range, start..end);
+        let mut matches = self.find_all(usize::MAX);
+        self.search_range = search_range;
+
+        merge_ranges(&mut matches);
+        matches
+    }
+
+    /// Like [`Iterator::next`], but each match comes with a snapshot of its groups,
+    /// so that they don't need to be queried with [`Regex::group`] in between.
+    pub fn captures_iter(&mut self) -> impl Iterator<Item = Match> {
+        std::iter::from_fn(move || {
+            let range = self.next()?;
+            let groups = self.current_captures().map_or_else(Vec::new, <[_]>::to_vec);
+            Some(Match { range, groups })
+        })
+    }
+
+    /// Like [`Iterator::next`], but sticky, for instance to tokenize a line: Each match must
+    /// start exactly where the previous one ended, and the first one at the search position.
+    /// Iteration stops at the first gap and leaves the search position there,
+    /// so that it's known where tokenizing failed. Empty matches stop it as well.
+    pub fn tokens(&mut self) -> impl Iterator<Item = Range<usize>> {
+        std::iter::from_fn(move || {
+            let range = self.match_at(self.last_idx).filter(|r| !r.is_empty())?;
+            self.last_idx = range.end;
+            Some(range)
+        })
+    }
+
+    /// Returns whether a match covers the entire text, as opposed to just a part of it.
+    pub fn is_full_match(&self) -> bool {
+        let Some(inner) = &self.inner else {
+            return self.literal.as_deref().is_some_and(|l| l == self.text.as_str());
+        };
+        let anchored = self.anchored.get_or_init(|| {
+            Self::build(&format!(r"\A(?:{})\z", inner.as_str()), &self.options).ok()
+        });
+        anchored.as_ref().is_some_and(|re| re.is_match(&self.text))
+    }
+
+    /// Returns the match that starts exactly at `offset`, if any, and makes it the current match.
+    /// Unlike [`Iterator::next`], this doesn't look for matches further ahead,
+    /// and the search position is unaffected. The text before `offset` still counts for `\b`, etc.
+    pub fn match_at(&mut self, offset: usize) -> Option<Range<usize>> {
+        if offset < self.search_range.start
+            || !self.text.is_char_boundary(offset)
+            || !self.in_region(offset)
+        {
+            return None;
+        }
+
+        // The leftmost match starts at `offset` if there's one starting there at all.
+        let caps = self.captures_at(offset)?;
+        let m = caps[0].clone().unwrap();
+        if m.start != offset || m.end > self.search_range.end {
+            return None;
+        }
+
+        self.captures = Some(caps);
+        Some(m)
+    }
+
+    /// Returns the replacement for the current match, see [`Regex::replace_all`].
+    pub fn replace<'a>(&self, arena: &'a Arena, replacement: &str) -> ArenaString<'a> {
+        let mut out = ArenaString::with_capacity_in(replacement.len(), arena);
+        self.push_replacement(&mut out, replacement, self.current_match().unwrap_or(0..0));
+        out
+    }
+
+    /// Like [`Regex::replace`], but also returns the range of the current match to replace,
+    /// or `None` if there's no current match. Nothing is modified and the search position is kept:
+    /// The caller applies the edit and then calls [`Regex::set_text`] to continue after it,
+    /// at `range.start` plus the length of the replacement.
+    pub fn replace_current<'a>(
+        &self,
+        arena: &'a Arena,
+        replacement: &str,
+    ) -> Option<(Range<usize>, ArenaString<'a>)> {
+        let range = self.current_match()?;
+        let mut out = ArenaString::with_capacity_in(replacement.len(), arena);
+        self.push_replacement(&mut out, replacement, range.clone());
+        Some((range, out))
+    }
+
+    /// Appends the expansion of `replacement` for the current match `range` to `out`.
+    fn push_replacement(&self, out: &mut ArenaString, replacement: &str, range: Range<usize>) {
+        let start = out.len();
+        expand_replacement(out, replacement, &self.text, |name| self.lookup_group(name));
+        if self.preserve_case {
+            adapt_case(out, start, &self.text[range]);
+        }
+    }
+
+    /// Returns the text with all matches replaced by `replacement`.
+    ///
+    /// `$0`, `$1`, ... and `${name}` expand to the captured groups, and `$$` is a literal `$`.
+    /// References to groups that don't exist are copied literally.
+    /// `\U` and `\L` uppercase or lowercase the rest up to `\E`, and `\\` is a literal `\`.
+    /// The search starts over at the beginning of the text and consumes all matches.
+    pub fn replace_all<'a>(&mut self, arena: &'a Arena, replacement: &str) -> ArenaString<'a> {
+        self.replace_all_counted(arena, replacement).0
+    }
+
+    /// Like [`Regex::replace_all`], but also returns the number of replacements,
+    /// and leaves the current match and search position untouched.
+    /// Meant for previewing the result before the user confirms it.
+    pub fn replace_all_preview<'a>(
+        &mut self,
+        arena: &'a Arena,
+        replacement: &str,
+    ) -> (ArenaString<'a>, usize) {
+        let last_idx = self.last_idx;
+        let after_next = self.after_next;
+        let captures = self.captures.take();
+
+        let result = self.replace_all_counted(arena, replacement);
+
+        self.last_idx = last_idx;
+        self.after_next = after_next;
+        self.captures = captures;
+        result
+    }
+
+    fn replace_all_counted<'a>(
+        &mut self,
+        arena: &'a Arena,
+        replacement: &str,
+    ) -> (ArenaString<'a>, usize) {
+        let mut out = ArenaString::with_capacity_in(self.text.len(), arena);
+        let mut copied = 0;
+        let mut count = 0;
+
+        self.reset(0);
+        while let Some(range) = self.next() {
+            // Just like `regex::Regex::replace_all`, an empty match right
+            // after the previous one isn't replaced: "x*" turns "axb" into "-a-b-".
+            if count > 0 && range.is_empty() && range.start == copied {
+                continue;
+            }
+            out.push_str(&self.text[copied..range.start]);
+            copied = range.end;
+            self.push_replacement(&mut out, replacement, range);
+            count += 1;
+        }
+        out.push_str(&self.text[copied..]);
+
+        (out, count)
+    }
+
+    fn group_index(&self, name: &str) -> Option<usize> {
+        self.capture_names().position(|n| n == Some(name))
+    }
+
+    /// Resolves a group reference for [`expand_replacement`]. Groups that exist,
+    /// but didn't participate in the match, resolve to an empty range and expand to nothing.
+    fn lookup_group(&self, name: &str) -> Option<Range<usize>> {
+        let caps = self.captures.as_ref()?;
+        let idx = match name.parse::<usize>() {
+            Ok(idx) => idx,
+            Err(_) => self.group_index(name)?,
+        };
+        caps.get(idx).map(|g| g.clone().unwrap_or(0..0))
+    }
+
+    fn capture_ranges(caps: &regex::Captures) -> Vec<Option<Range<usize>>> {
+        caps.iter().map(|g| g.map(|g| g.range())).collect()
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Iterator for Regex {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut start = self.last_idx.max(self.search_range.start);
+        self.after_next = false;
+        loop {
+            if start > self.text.len() {
+                return None;
+            }
+
+            let Some(inner) = &self.inner else {
+                let range = self
+                    .find_literal(&self.text, start)
+                    .filter(|r| r.end <= self.search_range.end)?;
+                if !self.in_region(range.start) {
+                    start = self.next_offset(&range);
+                    continue;
+                }
+                self.captures = Some(vec![Some(range.clone())]);
+                self.last_idx = self.next_offset(&range);
+                self.after_next = true;
+                return Some(range);
+            };
+
+            match inner.captures_at(self.text.as_str(), start) {
+                Some(caps) if caps.get(0).unwrap().end() <= self.search_range.end => {
+                    let m = caps.get(0).unwrap();
+                    let range = m.start()..m.end();
+                    if !self.in_region(range.start) {
+                        start = self.next_offset(&range);
+                        continue;
+                    }
+                    self.captures = Some(Self::capture_ranges(&caps));
+
+                    self.last_idx = self.next_offset(&range);
+                    self.after_next = true;
+                    return Some(range);
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Searches a [`ReadableDocument`] such as a [`TextBuffer`] directly,
+/// instead of a [`Text`] copy of the entire document. Full mode only.
+///
+/// The document is copied into a window of at least [`ChunkedRegex::WINDOW`] bytes at a time,
+/// which always starts and ends at a line boundary, so that `^`, `$` and `\b` work as usual.
+/// Matches that reach the end of the window are retried with a window twice as large,
+/// and consecutive windows overlap by the lines in the last [`ChunkedRegex::OVERLAP`] bytes.
+///
+/// Unlike [`Regex`], this can miss matches that start before that overlap and end after the
+/// window, and a pattern that can only match by looking further ahead than the window
+/// will lose against a later match within it, for instance `a[\s\S]*z|b`.
+#[cfg(feature = "regex")]
+pub struct ChunkedRegex {
+    inner: regex::bytes::Regex,
+    /// A copy of the document starting at `window_start`, which is the start of a line.
+    window: Vec<u8>,
+    /// `usize::MAX` if the window needs to be filled first, for instance after a reset.
+    window_start: usize,
+    /// Whether `window` extends to the end of the document.
+    window_eof: bool,
+    last_idx: usize,
+}
+
+#[cfg(feature = "regex")]
+impl ChunkedRegex {
+    pub const WINDOW: usize = 64 * 1024;
+    pub const OVERLAP: usize = 4 * 1024;
+
+    /// Compiles `pattern` for searching any [`ReadableDocument`] with [`ChunkedRegex::next`].
+    pub fn with_options(pattern: &str, options: &RegexOptions) -> apperr::Result<Self> {
+        let inner = regex::bytes::RegexBuilder::new(&Regex::prepare_pattern(pattern, options))
+            .case_insensitive(options.case_insensitive)
+            .multi_line(options.multi_line)
+            .dot_matches_new_line(options.dot_all)
+            .crlf(options.crlf)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .dfa_size_limit(REGEX_DFA_SIZE_LIMIT)
+            .build()
+            .map_err(|err| Regex::pattern_error(&err))?;
+        Ok(Self {
+            inner,
+            window: Vec::new(),
+            window_start: usize::MAX,
+            window_eof: false,
+            last_idx: 0,
+        })
+    }
+
+    /// Continues searching from `offset`. This must also be called after modifying the document.
+    pub fn reset(&mut self, offset: usize) {
+        self.last_idx = offset;
+        self.window.clear();
+        self.window_start = usize::MAX;
+    }
+
+    /// Finds the next match in `doc`, which must be the same document on every call until
+    /// the next [`ChunkedRegex::reset`].
+    pub fn next(&mut self, doc: &dyn ReadableDocument) -> Option<Range<usize>> {
+        if self.window_start > self.last_idx {
+            let start = Self::line_start(doc, self.last_idx);
+            self.fill(doc, start, self.last_idx + Self::WINDOW);
+        }
+
+        loop {
+            let from = self.last_idx - self.window_start;
+            if from > self.window.len() {
+                return None;
+            }
+
+            match self.inner.find_at(&self.window, from) {
+                Some(m) if self.window_eof || m.end() < self.window.len() => {
+                    let range = self.window_start + m.start()..self.window_start + m.end();
+                    // Empty matches skip a character, just like in `Regex`.
+                    self.last_idx = match self.window.get(m.end()) {
+                        Some(&b) if range.is_empty() => range.end + utf8_sequence_len(b),
+                        None if range.is_empty() => range.end + 1,
+                        _ => range.end,
+                    };
+                    return Some(range);
+                }
+                // The match may continue past the window.
+                Some(_) => {
+                    let end = self.window_start + 2 * self.window.len().max(Self::WINDOW);
+                    self.fill(doc, self.window_start, end);
+                }
+                None if self.window_eof => return None,
+                None => {
+                    // A match may start near the end of the window and continue past it,
+                    // so the next window overlaps the lines within the last `OVERLAP` bytes.
+                    let end = self.window.len();
+                    let tail = end.saturating_sub(Self::OVERLAP).max(1);
+                    let overlap = find_newline(&self.window[..end - 1], tail - 1) + 1;
+                    let start = self.window_start + overlap;
+                    self.last_idx = self.last_idx.max(start);
+                    self.fill(doc, start, start + Self::WINDOW);
+                }
+            }
+        }
+    }
+
+    /// Copies `doc` into the window, from `start` up to at least `min_end`
+    /// and from there on until the end of the line.
+    fn fill(&mut self, doc: &dyn ReadableDocument, start: usize, min_end: usize) {
+        self.window.clear();
+        self.window_start = start;
+        self.window_eof = true;
+
+        let mut off = start;
+        loop {
+            let chunk = doc.read_forward(off);
+            if chunk.is_empty() {
+                break;
+            }
+
+            let skip = min_end.saturating_sub(off).min(chunk.len());
+            let newline = find_newline(chunk, skip);
+            if newline < chunk.len() {
+                self.window.extend_from_slice(&chunk[..newline + 1]);
+                self.window_eof = doc.read_forward(off + newline + 1).is_empty();
+                break;
+            }
+
+            self.window.extend_from_slice(chunk);
+            off += chunk.len();
+        }
+    }
+
+    /// Returns the start of the line containing `offset`.
+    fn line_start(doc: &dyn ReadableDocument, offset: usize) -> usize {
+        // `read_backward` clamps the offset, which would throw off the calculation below.
+        if offset > 0 && doc.read_forward(offset - 1).is_empty() {
+            return offset;
+        }
+
+        let mut off = offset;
+        loop {
+            let chunk = doc.read_backward(off);
+            if chunk.is_empty() {
+                return 0;
+            }
+            if let Some(i) = chunk.iter().rposition(|&b| b == b'\n') {
+                return off - chunk.len() + i + 1;
+            }
+            off -= chunk.len();
+        }
+    }
+}
+
+/// Calls `callback` with the range of every match of `pattern` in `doc`, for instance
+/// to grep through a [`TextBuffer`] without copying all of it into a [`Text`] first.
+/// Full mode only.
+///
+/// Only a window of the document is held in memory at a time, see [`ChunkedRegex`]
+/// for how windows overlap and its limitations. Each match is reported exactly once,
+/// even if it lies within the overlap of two windows.
+#[cfg(feature = "regex")]
+pub fn search_stream(
+    doc: &dyn ReadableDocument,
+    pattern: &str,
+    flags: i32,
+    mut callback: impl FnMut(Range<usize>),
+) -> apperr::Result<()> {
+    let mut regex = ChunkedRegex::with_options(pattern, &RegexOptions::from_flags(flags))?;
+    while let Some(range) = regex.next(doc) {
+        callback(range);
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------------------
+// Implementation 2: LITE MODE (Using std string search)
+// -----------------------------------------------------------------------------------------
+#[cfg(not(feature = "regex"))]
+pub struct Regex {
+    /// Usually a single pattern, unless created with [`Regex::new_multi`].
+    patterns: Vec<Needle>,
+    text: Rc<String>,
+    last_idx: usize,
+    /// Set while `last_idx` is right after the current match, because [`Iterator::next`]
+    /// found it, so that [`Regex::prev`] doesn't find the same match again.
+    after_next: bool,
+    case_insensitive: bool,
+    /// Set for [`Regex::MULTILINE`] patterns starting with `^`, which was stripped from `pattern`.
+    line_start: bool,
+    /// Set for [`Regex::MULTILINE`] patterns ending with `$`, which was stripped from `pattern`.
+    line_end: bool,
+    /// Matches must lie within this range, see [`Regex::set_search_range`].
+    search_range: Range<usize>,
+    current: Option<Range<usize>>,
+    /// The index into `patterns` of the current match, see [`Regex::pattern_index`].
+    current_pattern: usize,
+    /// See [`Regex::set_preserve_case`].
+    preserve_case: bool,
+    /// See [`Regex::set_literal_index`].
+    index: Option<Rc<LiteralIndex>>,
+    /// See [`Regex::set_region_filter`].
+    region_filter: Option<Box<dyn Fn(usize) -> bool>>,
+    /// See [`Regex::find_any_at`].
+    next_matches: RefCell<NextMatches>,
+}
+
+/// The next match of each pattern of a [`Regex::new_multi`], so that [`Regex::find_any_at`]
+/// only searches for a pattern again once the position has passed its match.
+#[cfg(not(feature = "regex"))]
+#[derive(Default)]
+struct NextMatches {
+    /// The text the matches were found in, as its address and length.
+    text: (usize, usize),
+    /// For each pattern the offset it was searched from, and the first match from there.
+    matches: Vec<Option<(usize, Option<Range<usize>>)>>,
+}
+
+/// One of the patterns of a lite mode [`Regex`].
+#[cfg(not(feature = "regex"))]
+struct Needle {
+    pattern: String,
+    /// See [`Regex::set_whole_word`].
+    whole_word: bool,
+}
+
+#[cfg(not(feature = "regex"))]
+impl Regex {
+    pub const CASE_INSENSITIVE: i32 = 1;
+    /// Only effective without `LITERAL`, see [`Regex::with_options`].
+    pub const MULTILINE: i32 = 2;
+    pub const LITERAL: i32 = 4; // Always literal in lite
+    pub const WHOLE_WORD: i32 = 8;
+    pub const DOT_ALL: i32 = 16; // Ignored in lite
+    pub const CRLF: i32 = 32; // Always on in lite
+    /// See [`RegexOptions::normalize`].
+    pub const NORMALIZE: i32 = 64;
+
+    /// Prepares searching `text` for `pattern`.
+    /// Same as [`Regex::with_options`], but with the options given as a bitmask of flags.
+    /// The `text` contents are shared, so it may be dropped afterwards.
+    pub fn new(pattern: &str, flags: i32, text: &Text<'_>) -> apperr::Result<Self> {
+        Self::with_options(pattern, &RegexOptions::from_flags(flags), text)
+    }
+
+    /// Prepares searching `text` for `pattern`.
+    ///
+    /// There's no regex engine in lite mode and `pattern` is searched for literally.
+    /// The only exception are `multi_line` patterns that aren't `literal`:
+    /// A leading `^` anchors the match to the start of a line and a trailing `$`
+    /// to the end of a line (before a `\n` or `\r\n`, or at the end of the text).
+    /// No other metacharacters are supported.
+    pub fn with_options(
+        pattern: &str,
+        options: &RegexOptions,
+        text: &Text<'_>,
+    ) -> apperr::Result<Self> {
+        let anchors = options.multi_line && !options.literal;
+        let mut pattern = &*if options.normalize { normalize_nfc(pattern) } else { pattern.into() };
+        let mut line_start = false;
+        let mut line_end = false;
+
+        if anchors && let Some(p) = pattern.strip_prefix('^') {
+            pattern = p;
+            line_start = true;
+        }
+        if anchors && let Some(p) = pattern.strip_suffix('$') {
+            pattern = p;
+            line_end = true;
+        }
+
+        Ok(Self {
+            patterns: vec![Needle { pattern: pattern.to_string(), whole_word: options.whole_word }],
+            text: Rc::clone(&text.content),
+            last_idx: 0,
+            after_next: false,
+            case_insensitive: options.case_insensitive,
+            line_start,
+            line_end,
+            search_range: 0..usize::MAX,
+            current: None,
+            current_pattern: 0,
+            preserve_case: false,
+            index: None,
+            region_filter: None,
+            next_matches: RefCell::default(),
+        })
+    }
+
+    /// Prepares searching `text` for any of `patterns`, for instance to highlight several
+    /// keywords at once. Each match is the earliest one of any of the patterns, preferring
+    /// lower indices for matches at the same offset. [`Regex::pattern_index`] tells them apart.
+    ///
+    /// The patterns are always searched for literally, ignoring [`Regex::MULTILINE`] anchors.
+    /// [`Regex::WHOLE_WORD`] applies to all of them, see [`Regex::set_whole_word`] otherwise.
+    pub fn new_multi(patterns: &[&str], flags: i32, text: &Text<'_>) -> apperr::Result<Self> {
+        let options = RegexOptions { literal: true, ..RegexOptions::from_flags(flags) };
+        let mut regex = Self::with_options("", &options, text)?;
+        regex.patterns = patterns
+            .iter()
+            .map(|&p| {
+                let pattern = if options.normalize { normalize_nfc(p) } else { p.into() };
+                Needle { pattern: pattern.into_owned(), whole_word: options.whole_word }
+            })
+            .collect();
+        Ok(regex)
+    }
+
+    /// Overrides [`Regex::WHOLE_WORD`] for the pattern at `index` of a [`Regex::new_multi`],
+    /// so that for instance "cat" can be searched for as a whole word alongside a prefix "dog".
+    pub fn set_whole_word(&mut self, index: usize, whole_word: bool) {
+        if let Some(needle) = self.patterns.get_mut(index) {
+            needle.whole_word = whole_word;
+        }
+        self.forget_next_matches();
+    }
+
+    /// Any pattern is valid in lite mode, because it's searched for literally.
+    pub fn validate_pattern(_pattern: &str, _flags: i32) -> Result<(), (usize, String)> {
+        Ok(())
+    }
+
+    /// Refreshes `text` and continues searching it from `offset`.
+    pub fn set_text(&mut self, text: &mut Text<'_>, offset: usize) {
+        // Release our reference first, so that `refresh` can reuse the allocation.
+        self.text = Rc::default();
+        text.refresh();
+        self.text = Rc::clone(&text.content);
+        self.current = None;
+        self.forget_next_matches();
+        self.reset(offset);
+    }
+
+    /// Reads what was appended to the buffer of `text` since the last refresh and continues
+    /// searching into it, for instance when following a growing log file. Unlike
+    /// [`Regex::set_text`] the position is kept and only the appended bytes are read.
+    ///
+    /// A match that ended at the old end of the text may continue into the appended text
+    /// (think `a+` over "aa" + "a"), so it's searched for again and reported anew if it changed.
+    pub fn append_text(&mut self, text: &mut Text<'_>) {
+        let old_len = self.text.len();
+        // Release our reference first, so that `refresh_range` can append in place.
+        self.text = Rc::default();
+        text.refresh_range(old_len..old_len);
+        self.text = Rc::clone(&text.content);
+        self.forget_next_matches();
+
+        if let Some(range) = self.current_match()
+            && range.end == old_len
+            && self.last_idx >= old_len
+            && self.find_at(range.start) != Some(range.clone())
+        {
+            self.last_idx = range.start;
+        }
+    }
+
+    /// Continues searching from `offset`. The current match is kept,
+    /// so that it can still be replaced after moving elsewhere, see [`Regex::current_match`].
+    pub fn reset(&mut self, offset: usize) {
+        self.last_idx = offset;
+        self.after_next = false;
+    }
+
+    /// Returns the most recent match, even after a [`Regex::reset`].
+    pub fn current_match(&self) -> Option<Range<usize>> {
+        self.current.clone()
+    }
+
+    /// Returns the groups of the most recent match, even after a [`Regex::reset`].
+    /// There are no groups in lite mode, so this only contains the match itself as group 0.
+    pub fn current_captures(&self) -> Option<&[Option<Range<usize>>]> {
+        self.current.as_ref()?;
+        Some(std::slice::from_ref(&self.current))
+    }
+
+    /// Returns the index of the pattern passed to [`Regex::new_multi`] that produced
+    /// the current match. Always 0 for regexes with a single pattern.
+    pub fn pattern_index(&self) -> usize {
+        self.current_pattern
+    }
+
+    /// Confines the search to matches within `range`, for instance for "Replace in Selection",
+    /// and moves the position to its start. Match offsets remain relative to the entire text.
+    ///
+    /// Pass `0..usize::MAX` to search the entire text again.
+    pub fn set_search_range(&mut self, range: Range<usize>) {
+        self.reset(range.start);
+        self.search_range = range;
+        self.forget_next_matches();
+    }
+
+    /// Makes replacements adopt the case of the text they replace: Replacing "color" with "colour"
+    /// turns "COLOR" into "COLOUR" and "Color" into "Colour". Other matches are replaced as-is.
+    pub fn set_preserve_case(&mut self, preserve_case: bool) {
+        self.preserve_case = preserve_case;
+    }
+
+    /// Only accepts matches starting at an offset for which `filter` returns `true`, so that
+    /// a tokenizer can restrict the search to code and skip comments and strings, or vice versa.
+    /// Rejected matches are skipped as if they didn't exist, and the search continues after them.
+    pub fn set_region_filter(&mut self, filter: Box<dyn Fn(usize) -> bool>) {
+        self.region_filter = Some(filter);
+        self.forget_next_matches();
+    }
+
+    /// Removes the filter set with [`Regex::set_region_filter`].
+    pub fn clear_region_filter(&mut self) {
+        self.region_filter = None;
+        self.forget_next_matches();
+    }
+
+    /// Speeds up case-sensitive searches with an index from [`build_literal_index`],
+    /// which can be shared by all regexes searching the same [`Text`].
+    ///
+    /// The index is ignored once the text changes, for instance via [`Regex::set_text`],
+    /// and case-insensitive searches don't use it at all.
+    pub fn set_literal_index(&mut self, index: Rc<LiteralIndex>) {
+        self.index = Some(index);
+    }
+
+    /// Like [`Iterator::next`], but continues at the start of the search range once the end
+    /// has been reached ("Find Next"). The `bool` indicates whether the search wrapped around.
+    ///
+    /// Returns `None` if there's no match anywhere.
+    pub fn next_wrapping(&mut self) -> Option<(Range<usize>, bool)> {
+        let start = self.last_idx;
+        if let Some(range) = self.next() {
+            return Some((range, false));
+        }
+
+        self.reset(self.search_range.start);
+        match self.next() {
+            Some(range) if range.start < start => Some((range, true)),
+            _ => {
+                self.reset(start);
+                None
+            }
+        }
+    }
+
+    pub fn group_count(&mut self) -> i32 { 0 }
+
+    pub fn group(&mut self, _group: i32) -> Option<Range<usize>> { None }
+
+    /// Like [`Regex::group`], but shifted by `base`, for instance to map the group
+    /// from a search in a window of a document back to the document itself.
+    pub fn group_absolute(&mut self, group: i32, base: usize) -> Option<Range<usize>> {
+        self.group(group).map(|r| r.start + base..r.end + base)
+    }
+
+    pub fn group_by_name(&mut self, _name: &str) -> Option<Range<usize>> {
+        None
+    }
+
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+        std::iter::empty()
+    }
+
+    /// Finds the last match that ends at or before the current position ("Find Previous").
+    /// The position is moved to the start of the match, so that repeated calls walk backwards.
+    /// Right after [`Iterator::next`] it's the match before the current one, not the same one.
+    pub fn prev(&mut self) -> Option<Range<usize>> {
+        let bounds = self.search_bounds(&self.text);
+        let mut limit = self.last_idx.min(bounds.end);
+        if let Some(m) = &self.current
+            && self.after_next
+        {
+            limit = limit.min(m.start);
+        }
+        while !self.text.is_char_boundary(limit) {
+            limit -= 1;
+        }
+        if self.patterns.iter().all(|n| n.pattern.is_empty()) || limit < bounds.start {
+            return None;
+        }
+
+        let (range, index) = if let [needle] = &self.patterns[..]
+            && !self.case_insensitive
+            && !self.has_filter(needle)
+        {
+            let pattern = needle.pattern.as_str();
+            let start = bounds.start + self.text[bounds.start..limit].rfind(pattern)?;
+            (start..start + pattern.len(), 0)
+        } else {
+            // Case folding, word boundaries and multiple patterns make a reverse scan awkward,
+            // so scan forward and keep the last match before `limit`.
+            let mut offset = 0;
+            let mut found = None;
+            while let Some((range, index)) = self.find_any_at(&self.text, offset) {
+                if range.end > limit {
+                    break;
+                }
+                offset = self.next_offset(&range);
+                found = Some((range, index));
+            }
+            found?
+        };
+
+        self.reset(range.start);
+        self.current = Some(range.clone());
+        self.current_pattern = index;
+        Some(range)
+    }
+
+    /// Finds the last match within the search range, for instance to jump to the last error
+    /// in a build log. Like with [`Regex::prev`], the position is moved to the start of the
+    /// match, so that `prev` continues with the match before it. `find_last` itself always
+    /// starts over at the end.
+    ///
+    /// The match may span multiple lines, if the pattern allows for it. To get the last matching
+    /// line as a whole, use a [`Regex::MULTILINE`] pattern like `^.*error.*$`.
+    pub fn find_last(&mut self) -> Option<Range<usize>> {
+        let last_idx = self.last_idx;
+        self.reset(usize::MAX);
+        let range = self.prev();
+        if range.is_none() {
+            self.reset(last_idx);
+        }
+        range
+    }
+
+    /// Jumps to the `n`-th (0-based) match, counting from the start of the text,
+    /// for instance to go to a result picked from a list of matches.
+    ///
+    /// Afterwards the captures of that match are available via [`Regex::group`]
+    /// and [`Iterator::next`] continues with match `n + 1`.
+    pub fn nth_match(&mut self, n: usize) -> Option<Range<usize>> {
+        self.reset(0);
+        self.nth(n)
+    }
+
+    /// Returns the replacement for the current match, see [`Regex::replace_all`].
+    pub fn replace<'a>(&self, arena: &'a Arena, replacement: &str) -> ArenaString<'a> {
+        let mut out = ArenaString::with_capacity_in(replacement.len(), arena);
+        self.push_replacement(&mut out, replacement, self.current_match().unwrap_or(0..0));
+        out
+    }
+
+    /// Like [`Regex::replace`], but also returns the range of the current match to replace,
+    /// or `None` if there's no current match. Nothing is modified and the search position is kept:
+    /// The caller applies the edit and then calls [`Regex::set_text`] to continue after it,
+    /// at `range.start` plus the length of the replacement.
+    pub fn replace_current<'a>(
+        &self,
+        arena: &'a Arena,
+        replacement: &str,
+    ) -> Option<(Range<usize>, ArenaString<'a>)> {
+        let range = self.current_match()?;
+        let mut out = ArenaString::with_capacity_in(replacement.len(), arena);
+        self.push_replacement(&mut out, replacement, range.clone());
+        Some((range, out))
+    }
+
+    /// Appends the expansion of `replacement` for the current match `range` to `out`.
+    fn push_replacement(&self, out: &mut ArenaString, replacement: &str, range: Range<usize>) {
+        let start = out.len();
+        expand_replacement(out, replacement, &self.text, |name| self.lookup_group(name));
+        if self.preserve_case {
+            adapt_case(out, start, &self.text[range]);
+        }
+    }
+
+    /// Returns the text with all matches replaced by `replacement`.
+    ///
+    /// There are no groups in lite mode: `$0` expands to the match, `$$` is a literal `$`,
+    /// `\U` and `\L` uppercase or lowercase the rest up to `\E`, `\\` is a literal `\`,
+    /// and everything else is copied literally.
+    /// The search starts over at the beginning of the text and consumes all matches.
+    pub fn replace_all<'a>(&mut self, arena: &'a Arena, replacement: &str) -> ArenaString<'a> {
+        self.replace_all_counted(arena, replacement).0
+    }
+
+    /// Like [`Regex::replace_all`], but also returns the number of replacements,
+    /// and leaves the current match and search position untouched.
+    /// Meant for previewing the result before the user confirms it.
+    pub fn replace_all_preview<'a>(
+        &mut self,
+        arena: &'a Arena,
+        replacement: &str,
+    ) -> (ArenaString<'a>, usize) {
+        let last_idx = self.last_idx;
+        let after_next = self.after_next;
+        let current = self.current.take();
+        let current_pattern = self.current_pattern;
+
+        let result = self.replace_all_counted(arena, replacement);
+
+        self.last_idx = last_idx;
+        self.after_next = after_next;
+        self.current = current;
+        self.current_pattern = current_pattern;
+        result
+    }
+
+    fn replace_all_counted<'a>(
+        &mut self,
+        arena: &'a Arena,
+        replacement: &str,
+    ) -> (ArenaString<'a>, usize) {
+        let mut out = ArenaString::with_capacity_in(self.text.len(), arena);
+        let mut copied = 0;
+        let mut count = 0;
+
+        self.reset(0);
+        while let Some(range) = self.next() {
+            // Just like `regex::Regex::replace_all`, an empty match right
+            // after the previous one isn't replaced: "x*" turns "axb" into "-a-b-".
+            if count > 0 && range.is_empty() && range.start == copied {
+                continue;
+            }
+            out.push_str(&self.text[copied..range.start]);
+            copied = range.end;
+            self.push_replacement(&mut out, replacement, range);
+            count += 1;
+        }
+        out.push_str(&self.text[copied..]);
+
+        (out, count)
+    }
+
+    fn lookup_group(&self, name: &str) -> Option<Range<usize>> {
+        if name == "0" { self.current.clone() } else { None }
+    }
+
+    /// Counts the matches in the entire text, without disturbing the current search position.
+    pub fn count_matches(&self) -> usize {
+        self.count_matches_from(0)
+    }
+
+    /// Counts the matches that [`Iterator::next`] has yet to return, without disturbing
+    /// the current search position. Together with [`Regex::count_matches`] this gives "3 of 17".
+    pub fn remaining_matches(&self) -> usize {
+        self.count_matches_from(self.last_idx)
+    }
+
+    fn count_matches_from(&self, mut offset: usize) -> usize {
+        let mut count = 0;
+        while let Some(range) = self.find_at(offset) {
+            count += 1;
+            offset = self.next_offset(&range);
+        }
+        count
+    }
+
+    /// Collects up to `max` matches from the start of the search range,
+    /// without disturbing the current search position.
+    pub fn find_all(&mut self, max: usize) -> Vec<Range<usize>> {
+        let last_idx = self.last_idx;
+        let after_next = self.after_next;
+        let current = self.current.take();
+        let current_pattern = self.current_pattern;
+
+        self.reset(0);
+        let matches = self.by_ref().take(max).collect();
+
+        self.last_idx = last_idx;
+        self.after_next = after_next;
+        self.current = current;
+        self.current_pattern = current_pattern;
+        matches
+    }
+
+    /// Collects the matches within `range`, for instance the visible part of the document,
+    /// as sorted, disjoint spans for highlighting them, see [`merge_ranges`].
+    /// Just like [`Regex::find_all`], this doesn't disturb the current search position.
+    pub fn find_all_merged(&mut self, range: Range<usize>) -> Vec<Range<usize>> {
+        let start = range.start.max(self.search_range.start);
+        let end = range.end.min(self.search_range.end);
+        if start > end {
+            return Vec::new();
+        }
+
+        let search_range = std::mem::replace(&mut self.search_range, start..end);
+        let mut matches = self.find_all(usize::MAX);
+        self.search_range = search_range;
+
+        merge_ranges(&mut matches);
+        matches
+    }
+
+    /// Like [`Iterator::next`], but each match comes with a snapshot of its groups,
+    /// so that they don't need to be queried with [`Regex::group`] in between.
+    pub fn captures_iter(&mut self) -> impl Iterator<Item = Match> {
+        std::iter::from_fn(move || {
+            let range = self.next()?;
+            let groups = self.current_captures().map_or_else(Vec::new, <[_]>::to_vec);
+            Some(Match { range, groups })
+        })
+    }
+
+    /// Like [`Iterator::next`], but sticky, for instance to tokenize a line: Each match must
+    /// start exactly where the previous one ended, and the first one at the search position.
+    /// Iteration stops at the first gap and leaves the search position there,
+    /// so that it's known where tokenizing failed. Empty matches stop it as well.
+    pub fn tokens(&mut self) -> impl Iterator<Item = Range<usize>> {
+        std::iter::from_fn(move || {
+            let range = self.match_at(self.last_idx).filter(|r| !r.is_empty())?;
+            self.last_idx = range.end;
+            Some(range)
+        })
+    }
+
+    /// Returns whether a match covers the entire text, as opposed to just a part of it.
+    pub fn is_full_match(&self) -> bool {
+        self.patterns.iter().any(|Needle { pattern, .. }| {
+            if self.case_insensitive {
+                Self::match_len_ci(&self.text, &Self::lowercase(pattern)) == Some(self.text.len())
+            } else {
+                *self.text == *pattern
+            }
+        })
+    }
+
+    /// Returns the match that starts exactly at `offset`, if any, and makes it the current match.
+    /// Unlike [`Iterator::next`], this doesn't look for matches further ahead,
+    /// and the search position is unaffected.
+    pub fn match_at(&mut self, offset: usize) -> Option<Range<usize>> {
+        let bounds = self.search_bounds(&self.text);
+        if offset < bounds.start || offset > bounds.end || !self.text.is_char_boundary(offset) {
+            return None;
+        }
+
+        let haystack = &self.text[offset..bounds.end];
+        let (range, index) = self.patterns.iter().enumerate().find_map(|(index, needle)| {
+            let pattern = needle.pattern.as_str();
+            let len = if self.case_insensitive {
+                Self::match_len_ci(haystack, &Self::lowercase(pattern))?
+            } else if haystack.starts_with(pattern) {
+                pattern.len()
+            } else {
+                return None;
+            };
+
+            let range = offset..offset + len;
+            if self.has_filter(needle) && !self.accepts(&self.text, &range, needle) {
+                return None;
+            }
+            Some((range, index))
+        })?;
+
+        self.current = Some(range.clone());
+        self.current_pattern = index;
+        Some(range)
+    }
+
+    /// Returns the first match within the search range that starts at or after `from`.
+    fn find_at(&self, from: usize) -> Option<Range<usize>> {
+        self.find_in(&self.text, from)
+    }
+
+    /// Like [`Regex::find_at`], but searches `text` instead of the text of the regex.
+    fn find_in(&self, text: &str, from: usize) -> Option<Range<usize>> {
+        self.find_any_at(text, from).map(|(range, _)| range)
+    }
+
+    /// Like [`Regex::find_in`], but also returns the index of the matching pattern.
+    ///
+    /// With several patterns, the next match of each one is cached in `next_matches`:
+    /// It's still the next one from `from`, as long as `from` hasn't passed its start.
+    fn find_any_at(&self, text: &str, from: usize) -> Option<(Range<usize>, usize)> {
+        if let [needle] = &self.patterns[..] {
+            return Some((self.find_pattern_at(text, needle, from)?, 0));
+        }
+
+        let mut next_matches = self.next_matches.borrow_mut();
+        let key = (text.as_ptr() as usize, text.len());
+        if next_matches.text != key || next_matches.matches.len() != self.patterns.len() {
+            next_matches.text = key;
+            next_matches.matches = vec![None; self.patterns.len()];
+        }
+
+        let mut best: Option<(Range<usize>, usize)> = None;
+        for (index, needle) in self.patterns.iter().enumerate() {
+            let range = match &next_matches.matches[index] {
+                Some((searched, range))
+                    if *searched <= from && range.as_ref().is_none_or(|r| from <= r.start) =>
+                {
+                    range.clone()
+                }
+                _ => {
+                    let range = self.find_pattern_at(text, needle, from);
+                    next_matches.matches[index] = Some((from, range.clone()));
+                    range
+                }
+            };
+            // Lower indices take precedence for matches at the same offset.
+            if let Some(range) = range
+                && best.as_ref().is_none_or(|(b, _)| range.start < b.start)
+            {
+                best = Some((range, index));
+            }
+        }
+        best
+    }
+
+    /// Drops the cache of [`Regex::find_any_at`], because the text or the options changed.
+    fn forget_next_matches(&mut self) {
+        self.next_matches.get_mut().matches.clear();
+    }
+
+    fn find_pattern_at(&self, text: &str, needle: &Needle, from: usize) -> Option<Range<usize>> {
+        let pattern = needle.pattern.as_str();
+        // Case folding happens char by char, so that the match can be mapped back to
+        // the original text, even if lowercasing changes the length (e.g. "İ" -> "i̇").
+        let pat_lower = if self.case_insensitive { Self::lowercase(pattern) } else { Vec::new() };
+        let bounds = self.search_bounds(text);
+        let mut from = from.max(bounds.start);
+
+        loop {
+            if from > bounds.end {
+                return None;
+            }
+            while !text.is_char_boundary(from) {
+                from += 1;
+            }
+
+            let haystack = &text[from..bounds.end];
+            let range = if self.case_insensitive {
+                let r = Self::find_ci(haystack, &pat_lower)?;
+                from + r.start..from + r.end
+            } else if let Some(index) = self.literal_index(text)
+                && !pattern.is_empty()
+            {
+                let start = index.find(pattern, from, bounds.end)?;
+                start..start + pattern.len()
+            } else {
+                let start = from + haystack.find(pattern)?;
+                start..start + pattern.len()
+            };
+
+            if !self.has_filter(needle) || self.accepts(text, &range, needle) {
+                return Some(range);
+            }
+
+            // Continue searching past the start of the rejected match.
+            from = range.start + text[range.start..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+
+    /// The index from [`Regex::set_literal_index`], if it was built for `text`
+    /// and that's still the text of the regex.
+    fn literal_index(&self, text: &str) -> Option<&LiteralIndex> {
+        self.index.as_deref().filter(|index| {
+            Rc::ptr_eq(&index.text, &self.text) && std::ptr::eq(index.text.as_str(), text)
+        })
+    }
+
+    fn lowercase(pattern: &str) -> Vec<char> {
+        pattern.chars().flat_map(char::to_lowercase).collect()
+    }
+
+    /// The search range clamped to `text`, on char boundaries.
+    fn search_bounds(&self, text: &str) -> Range<usize> {
+        let mut start = self.search_range.start.min(text.len());
+        let mut end = self.search_range.end.min(text.len());
+        while !text.is_char_boundary(start) {
+            start += 1;
+        }
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        start..end
+    }
+
+    /// Case-insensitive version of `str::find`, with `pat_lower` being the lowercased pattern.
+    fn find_ci(haystack: &str, pat_lower: &[char]) -> Option<Range<usize>> {
+        if pat_lower.is_empty() {
+            return Some(0..0);
+        }
+
+        haystack.char_indices().find_map(|(offset, _)| {
+            let len = Self::match_len_ci(&haystack[offset..], pat_lower)?;
+            Some(offset..offset + len)
+        })
+    }
+
+    /// Returns the length in bytes of the prefix of `haystack` which lowercases to `pat_lower`.
+    /// Characters whose lowercase form only partially matches are never split.
+    fn match_len_ci(haystack: &str, pat_lower: &[char]) -> Option<usize> {
+        let mut pat = pat_lower.iter();
+        let mut len = 0;
+
+        for ch in haystack.chars() {
+            if pat.len() == 0 {
+                break;
+            }
+            for lower in ch.to_lowercase() {
+                if pat.next() != Some(&lower) {
+                    return None;
+                }
+            }
+            len += ch.len_utf8();
+        }
+
+        if pat.len() == 0 { Some(len) } else { None }
+    }
+
+    /// Whether candidate matches need to be checked with [`Regex::accepts`].
+    fn has_filter(&self, needle: &Needle) -> bool {
+        needle.whole_word || self.line_start || self.line_end || self.region_filter.is_some()
+    }
+
+    /// Checks the word boundaries, line anchors and region filter of a candidate match.
+    /// Word boundaries are determined by the neighboring chars, not bytes, so that they work
+    /// for non-ASCII text. The start and end of the text count as non-word neighbors.
+    fn accepts(&self, text: &str, range: &Range<usize>, needle: &Needle) -> bool {
+        let before = &text[..range.start];
+        let after = &text[range.end..];
+
+        if needle.whole_word
+            && (before.chars().next_back().is_some_and(Self::is_word_char)
+                || after.chars().next().is_some_and(Self::is_word_char))
+        {
+            return false;
+        }
+        if self.line_start && !(before.is_empty() || before.ends_with('\n')) {
+            return false;
+        }
+        if self.line_end
+            && !(after.is_empty() || after.starts_with('\n') || after.starts_with("\r\n"))
+        {
+            return false;
+        }
+        if let Some(filter) = &self.region_filter
+            && !filter(range.start)
+        {
+            return false;
+        }
+        true
+    }
+
+    /// See [`next_match_offset`].
+    fn next_offset(&self, range: &Range<usize>) -> usize {
+        next_match_offset(&self.text, range)
+    }
+
+    fn is_word_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+}
+
+/// The offsets of each byte value in a [`Text`], see [`build_literal_index`].
+#[cfg(not(feature = "regex"))]
+pub struct LiteralIndex {
+    text: Rc<String>,
+    /// `positions[b]` lists the offsets of all bytes `b` in ascending order.
+    positions: Vec<Vec<u32>>,
+}
+
+/// The largest text that [`build_literal_index`] indexes, which caps the index at 64 MiB.
+#[cfg(not(feature = "regex"))]
+const LITERAL_INDEX_MAX_LEN: usize = 16 * 1024 * 1024;
+
+/// Indexes `text`, so that the literal searches of a [`Regex`] only need to look at the offsets
+/// at which the first byte of the pattern occurs, see [`Regex::set_literal_index`].
+///
+/// Lite mode lacks the optimized searchers of the regex crate and scans the entire text
+/// for every search. That's plenty fast for a single search, but "highlight all" and
+/// keyword lists search the same text over and over. The index costs a pass over the text
+/// and 4 bytes per byte of text, so it only pays off in that case. Returns `None` for texts
+/// larger than [`LITERAL_INDEX_MAX_LEN`], which are searched without an index instead.
+#[cfg(not(feature = "regex"))]
+pub fn build_literal_index(text: &Text<'_>) -> Option<LiteralIndex> {
+    let content = text.content.as_bytes();
+    if content.len() > LITERAL_INDEX_MAX_LEN {
+        return None;
+    }
+
+    let mut positions = vec![Vec::new(); 256];
+    for (offset, &b) in content.iter().enumerate() {
+        positions[b as usize].push(offset as u32);
+    }
+    Some(LiteralIndex { text: Rc::clone(&text.content), positions })
+}
+
+#[cfg(not(feature = "regex"))]
+impl LiteralIndex {
+    /// Returns the offset of the first occurrence of the non-empty `pattern`
+    /// that starts at or after `from` and ends at or before `end`.
+    fn find(&self, pattern: &str, from: usize, end: usize) -> Option<usize> {
+        let pattern = pattern.as_bytes();
+        let text = self.text.as_bytes();
+        let candidates = &self.positions[pattern[0] as usize];
+        let first = candidates.partition_point(|&offset| (offset as usize) < from);
+
+        candidates[first..]
+            .iter()
+            .map(|&offset| offset as usize)
+            .take_while(|&offset| offset + pattern.len() <= end)
+            .find(|&offset| text[offset..].starts_with(pattern))
+    }
+}
+
+#[cfg(not(feature = "regex"))]
+impl Iterator for Regex {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.after_next = false;
+        let (range, index) = self.find_any_at(&self.text, self.last_idx)?;
+        self.last_idx = self.next_offset(&range);
+        self.after_next = true;
+        self.current = Some(range.clone());
+        self.current_pattern = index;
+        Some(range)
+    }
+}
+
+/// All matches of a pattern as it's being typed, for highlighting them in the document.
+/// When the pattern is extended, [`IncrementalSearch::refine`] narrows down the previous matches
+/// instead of searching the entire text again, if possible.
+pub struct IncrementalSearch {
+    regex: Regex,
+    pattern: String,
+    options: RegexOptions,
+    text: Rc<String>,
+    matches: Vec<Range<usize>>,
+}
+
+impl IncrementalSearch {
+    /// Finds all matches of `pattern` in `text`.
+    pub fn new(pattern: &str, options: &RegexOptions, text: &Text<'_>) -> apperr::Result<Self> {
+        let mut regex = Regex::with_options(pattern, options, text)?;
+        let matches = regex.find_all(usize::MAX);
+        Ok(Self {
+            regex,
+            pattern: pattern.to_string(),
+            options: *options,
+            text: Rc::clone(&text.content),
+            matches,
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// The matches of the current pattern, sorted and without overlap.
+    pub fn matches(&self) -> &[Range<usize>] {
+        &self.matches
+    }
+
+    /// The regex for the current pattern, for instance to step through the matches.
+    pub fn regex(&mut self) -> &mut Regex {
+        &mut self.regex
+    }
+
+    /// Switches to `longer_pattern`, typically the current one with a character appended.
+    ///
+    /// If both are plain, case-sensitive literals and `longer_pattern` starts with the current
+    /// pattern, its matches are among the previous ones, which are filtered instead of searching
+    /// the text again. Otherwise, or if the current pattern could overlap with itself
+    /// and thus some of its occurrences weren't found, this falls back to a full search.
+    /// On failure, the current pattern and its matches are kept.
+    pub fn refine(&mut self, longer_pattern: &str) -> apperr::Result<()> {
+        let text = Text::detached(Rc::clone(&self.text));
+        let mut regex = Regex::with_options(longer_pattern, &self.options, &text)?;
+
+        if self.can_refine(longer_pattern) {
+            let text = &self.text;
+            let mut end = 0;
+            self.matches.retain_mut(|m| {
+                if m.start < end || !text[m.start..].starts_with(longer_pattern) {
+                    return false;
+                }
+                *m = m.start..m.start + longer_pattern.len();
+                end = m.end;
+                true
+            });
+        } else {
+            self.matches = regex.find_all(usize::MAX);
+        }
+
+        self.regex = regex;
+        self.pattern = longer_pattern.to_string();
+        Ok(())
+    }
+
+    fn can_refine(&self, longer_pattern: &str) -> bool {
+        let options = &self.options;
+        let literal = options.literal
+            || (is_plain_literal(&self.pattern) && is_plain_literal(longer_pattern));
+        let pattern = self.pattern.as_str();
+
+        literal
+            && !options.case_insensitive
+            && !options.whole_word
+            && !options.normalize
+            && !pattern.is_empty()
+            && longer_pattern.starts_with(pattern)
+            // Occurrences of a pattern that begins with one of its own suffixes
+            // (like "aa" in "aaa") can overlap, so that some of them were skipped.
+            && !(1..pattern.len())
+                .any(|i| pattern.is_char_boundary(i) && pattern.ends_with(&pattern[..i]))
+    }
+}
+
+/// Returns the offset to continue searching `text` from after the match `range`.
+/// Empty matches skip a character, so that iteration always makes progress
+/// without ending up in the middle of a multi-byte character.
+fn next_match_offset(text: &str, range: &Range<usize>) -> usize {
+    if range.is_empty() {
+        range.end + text[range.end..].chars().next().map_or(1, char::len_utf8)
+    } else {
+        range.end
+    }
+}
+
+/// A match found by [`search_text`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub range: Range<usize>,
+    /// The 0-based line of the start of the match.
+    pub line: usize,
+    /// The line of the match, without its line ending. Lines longer than 1 KiB
+    /// are cut down to the 1 KiB around the start of the match.
+    pub line_text: String,
+}
+
+/// How many bytes of a line [`SearchHit::line_text`] holds at most.
+const SEARCH_HIT_LINE_MAX: usize = 1024;
+
+/// Finds up to `max_per_file` matches of `pattern` in `content` along with their lines,
+/// for instance for a project-wide search that's fed the contents of each file.
+/// The result size is bounded, even for minified files consisting of a single huge line.
+pub fn search_text(
+    content: &str,
+    pattern: &str,
+    flags: i32,
+    max_per_file: usize,
+) -> apperr::Result<Vec<SearchHit>> {
+    // The regex is compiled for an empty text and searches `content` directly,
+    // so that it doesn't need to be copied into a `Text`.
+    let regex = Regex::with_options(pattern, &RegexOptions::from_flags(flags), &Text::default())?;
+    let mut hits = Vec::new();
+    let mut offset = 0;
+    let mut line = 0;
+    let mut line_start = 0;
+
+    while hits.len() < max_per_file
+        && let Some(range) = regex.find_in(content, offset)
+    {
+        offset = next_match_offset(content, &range);
+
+        // Same as `Text::line_of`: The end of a text with a final newline is part of the last line.
+        let mut pos = range.start;
+        if pos == content.len() && content.ends_with('\n') {
+            pos -= 1;
+        }
+        // Matches are found in order, so the lines are counted incrementally.
+        loop {
+            let newline = find_newline(content.as_bytes(), line_start);
+            if newline >= pos {
+                break;
+            }
+            line += 1;
+            line_start = newline + 1;
+        }
+
+        let mut line_end = find_newline(content.as_bytes(), line_start);
+        // The same as `Text::lines`, which only strips the `\r` of a `\r\n`.
+        if line_end < content.len() && content[..line_end].ends_with('\r') {
+            line_end -= 1;
+        }
+
+        let mut beg = range.start.saturating_sub(SEARCH_HIT_LINE_MAX / 2).max(line_start);
+        let mut end = line_end.min(beg + SEARCH_HIT_LINE_MAX);
+        beg = beg.min(end.saturating_sub(SEARCH_HIT_LINE_MAX).max(line_start));
+        while !content.is_char_boundary(beg) {
+            beg -= 1;
+        }
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        hits.push(SearchHit { range, line, line_text: content[beg..end].to_string() });
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use super::*;
+
+    /// Converts `input` by feeding it to the converter in chunks of `chunk_size` bytes.
+    fn convert_chunked(source: &str, target: &str, input: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        let mut c = Converter::new(&mut pivot, source, target).unwrap();
+        let mut output = [MaybeUninit::uninit(); 16];
+        let mut result = Vec::new();
+        let mut beg = 0;
+        let mut end = 0;
+
+        loop {
+            // Just like the file reader, keep unconsumed input around and append to it.
+            end = (end + chunk_size).min(input.len());
+            let (read, written) = c.convert(&input[beg..end], &mut output).unwrap();
+            result.extend_from_slice(unsafe { output[..written].assume_init_ref() });
+            beg += read;
+            if beg == input.len() && read == 0 && written == 0 {
+                break;
+            }
+        }
+
+        result
+    }
+
+    fn utf16le(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    fn utf16be(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_be_bytes).collect()
+    }
+
+    #[test]
+    fn test_utf16_decode() {
+        let text = "Hello, Wörld! 🦀 こんにちは 😀😀";
+
+        for chunk_size in [1, 2, 3, 4, 5, 7, 1024] {
+            assert_eq!(
+                convert_chunked("UTF-16LE", "UTF-8", &utf16le(text), chunk_size),
+                text.as_bytes()
+            );
+            assert_eq!(
+                convert_chunked("UTF-16BE", "UTF-8", &utf16be(text), chunk_size),
+                text.as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_utf16_decode_alignment() {
+        let text = "Hello, Wörld! 🦀 こんにちは \u{FFFD}";
+
+        // One of the two is guaranteed to be misaligned for `u16`.
+        for (input, pad) in [(utf16le(text), 0), ([&[0][..], &utf16le(text)].concat(), 1)] {
+            assert_eq!(convert_chunked("UTF-16LE", "UTF-8", &input[pad..], 1024), text.as_bytes());
+        }
+        for (input, pad) in [(utf16be(text), 0), ([&[0][..], &utf16be(text)].concat(), 1)] {
+            assert_eq!(convert_chunked("UTF-16BE", "UTF-8", &input[pad..], 1024), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_utf16_odd_byte() {
+        let text = "Hello, Wörld! 🦀 こんにちは";
+        let input = utf16le(text);
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        let mut output = [MaybeUninit::uninit(); 64];
+
+        // Feeding one byte at a time, without ever passing the same byte twice.
+        let mut c = Converter::new(&mut pivot, "UTF-16LE", "UTF-8").unwrap();
+        let mut result = Vec::new();
+        for b in input.chunks(1).chain([&[][..]]) {
+            let (read, written) = c.convert(b, &mut output).unwrap();
+            assert_eq!(read, b.len());
+            result.extend_from_slice(unsafe { output[..written].assume_init_ref() });
+        }
+        assert_eq!(result, text.as_bytes());
+        assert_eq!(c.replacement_count(), 0);
+
+        // A lone byte at the end of the stream.
+        let mut c = Converter::new(&mut pivot, "UTF-16LE", "UTF-8").unwrap();
+        assert_eq!(c.convert(b"a\x00b", &mut output).unwrap(), (3, 1));
+        assert_eq!(c.convert(b"", &mut output).unwrap(), (0, 3));
+        assert_eq!(unsafe { output[..3].assume_init_ref() }, "\u{FFFD}".as_bytes());
+        assert_eq!(c.replacement_count(), 1);
+    }
+
+    #[test]
+    fn test_utf16_encode() {
+        let text = "Hello, Wörld! 🦀 こんにちは 😀😀";
+
+        for chunk_size in [1, 2, 3, 4, 5, 7, 1024] {
+            assert_eq!(
+                convert_chunked("UTF-8", "UTF-16LE", text.as_bytes(), chunk_size),
+                utf16le(text)
+            );
+            assert_eq!(
+                convert_chunked("UTF-8", "UTF-16BE", text.as_bytes(), chunk_size),
+                utf16be(text)
+            );
+        }
+    }
+
+    #[test]
+    fn test_utf16_encode_surrogate_pairs() {
+        let text = "a🦀b😀😀c";
+        let mut pivot = [MaybeUninit::uninit(); 64];
+
+        // With an odd number of free code units, the output boundary falls between the halves.
+        for output_len in [4, 5, 6, 7, 8] {
+            let mut output = vec![MaybeUninit::uninit(); output_len];
+            let mut c = Converter::new(&mut pivot, "UTF-8", "UTF-16LE").unwrap();
+            let mut result = Vec::new();
+            let mut read = 0;
+
+            loop {
+                let (r, w) = c.convert(&text.as_bytes()[read..], &mut output).unwrap();
+                let chunk = unsafe { output[..w].assume_init_ref() };
+                let units = chunk.chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+                assert!(char::decode_utf16(units).all(|c| c.is_ok()), "{output_len}: {chunk:?}");
+                result.extend_from_slice(chunk);
+                read += r;
+                if read == text.len() && r == 0 && w == 0 {
+                    break;
+                }
+            }
+
+            assert_eq!(result, utf16le(text));
+            assert_eq!(convert_chunked("UTF-16LE", "UTF-8", &result, output_len), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_utf8_to_utf8_invalid() {
+        // Valid text is copied as-is, even if it's split mid-sequence between chunks.
+        // Invalid sequences are replaced, including a truncated one at the end of the stream.
+        let input = "a\u{FFFD}b€c".as_bytes().iter().chain(b"\xFFd\xE2\x82e\xE2\x82");
+        let input: Vec<u8> = input.copied().collect();
+        for chunk_size in [1, 2, 3, 4, 5, 1024] {
+            assert_eq!(
+                convert_chunked("UTF-8", "UTF-8", &input, chunk_size),
+                "a\u{FFFD}b€c\u{FFFD}d\u{FFFD}e\u{FFFD}".as_bytes()
+            );
+        }
+
+        // The sequence cut off at the end of the first input is completed by the second one.
+        // Replacements are counted, but not the literal U+FFFD.
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        let mut output = [MaybeUninit::uninit(); 64];
+        let mut c = Converter::new(&mut pivot, "UTF-8", "UTF-8").unwrap();
+        assert_eq!(c.convert(b"a\xFFb\xE2\x82", &mut output).unwrap(), (5, 5));
+        assert_eq!(c.convert(b"\xACc\xEF\xBF\xBD\xC3", &mut output).unwrap(), (6, 7));
+        assert_eq!(c.convert(b"", &mut output).unwrap(), (0, 3));
+        assert_eq!(c.replacement_count(), 2);
+    }
+
+    #[test]
+    fn test_utf16_lone_surrogates() {
+        // A lone low surrogate, a high surrogate followed by a non-surrogate,
+        // and a high surrogate at the very end of the stream.
+        let input: Vec<u8> = [0x0061, 0xDC00, 0x0062, 0xD800, 0x0063, 0xD83D]
+            .iter()
+            .flat_map(|u: &u16| u.to_le_bytes())
+            .collect();
+
+        for chunk_size in [1, 2, 3, 1024] {
+            assert_eq!(
+                convert_chunked("UTF-16LE", "UTF-8", &input, chunk_size),
+                "a\u{FFFD}b\u{FFFD}c\u{FFFD}".as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_windows1252() {
+        let input = b"caf\xE9 \x80 \x93quoted\x94 \x81";
+        let text = "café € “quoted” \u{81}";
+
+        for chunk_size in [1, 2, 3, 1024] {
+            assert_eq!(
+                convert_chunked("windows-1252", "UTF-8", input, chunk_size),
+                text.as_bytes()
+            );
+            assert_eq!(
+                convert_chunked("UTF-8", "windows-1252", text.as_bytes(), chunk_size),
+                input
+            );
+        }
+
+        // Characters that can't be represented turn into "?".
+        assert_eq!(convert_chunked("UTF-8", "windows-1252", "a😀b€".as_bytes(), 1024), b"a?b\x80");
+    }
+
+    #[test]
+    fn test_gb18030() {
+        let cases: [(&[u8], &str); 8] = [
+            (
+                b"\xC4\xE3\xBA\xC3, \xCA\xC0\xBD\xE7! \xA1\xE8\xA8\xA6\x94\x39\xFC\x36",
+                "你好, 世界! ¤é😀",
+            ),
+            (b"\x80\xA8\xBC\x81\x35\xF4\x37\x81\x30\x81\x30", "€ḿ\u{E7C7}\u{80}"),
+            // Invalid trail bytes. ASCII isn't swallowed.
+            (b"\x81!\xFF\x81\xFFb", "\u{FFFD}!\u{FFFD}\u{FFFD}b"),
+            // Invalid 3rd and 4th bytes of a four-byte sequence.
+            (b"\x81\x30a\x81\x30\x81!", "\u{FFFD}0a\u{FFFD}0\u{FFFD}!"),
+            // A four-byte sequence outside of the mapped ranges.
+            (b"\x84\x32\x81\x30!", "\u{FFFD}!"),
+            // Truncated sequences at the end of the stream.
+            (b"a\x81", "a\u{FFFD}"),
+            (b"a\x81\x30", "a\u{FFFD}"),
+            (b"a\x81\x30\x81", "a\u{FFFD}"),
+        ];
+
+        for (input, expected) in cases {
+            for chunk_size in [1, 2, 3, 1024] {
+                assert_eq!(
+                    convert_chunked("gb18030", "UTF-8", input, chunk_size),
+                    expected.as_bytes(),
+                    "{input:x?} in chunks of {chunk_size}"
+                );
+            }
+        }
+
+        // Decoding only.
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        assert!(Converter::new(&mut pivot, "UTF-8", "gb18030").is_err());
+    }
+
+    #[test]
+    fn test_shift_jis() {
+        let cases: [(&[u8], &str); 6] = [
+            (b"\x82\xA0\x88\x9F, \xB1\xDD! \x80\x5C", "あ亜, ｱﾝ! \u{80}\\"),
+            // JIS X 0208 wave dash, NEC and IBM extensions and the private use area.
+            (b"\x81\x60\x87\x40\xFA\x40\xF0\x40", "\u{301C}①ⅰ\u{E000}"),
+            // Invalid lead and trail bytes. ASCII isn't swallowed.
+            (b"\xA0\x81!\x81\xFDb", "\u{FFFD}\u{FFFD}!\u{FFFD}b"),
+            // Unmapped codes.
+            (b"\x85\x40\x85\x80", "\u{FFFD}@\u{FFFD}"),
+            // Truncated sequences at the end of the stream.
+            (b"a\x81", "a\u{FFFD}"),
+            (b"a\xE0", "a\u{FFFD}"),
+        ];
+
+        for (input, expected) in cases {
+            for chunk_size in [1, 2, 3, 1024] {
+                assert_eq!(
+                    convert_chunked("shift_jis", "UTF-8", input, chunk_size),
+                    expected.as_bytes(),
+                    "{input:x?} in chunks of {chunk_size}"
+                );
+            }
+        }
+
+        // Decoding only.
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        assert!(Converter::new(&mut pivot, "UTF-8", "shift_jis").is_err());
+    }
+
+    #[test]
+    fn test_euc_kr() {
+        let cases: [(&[u8], &str); 6] = [
+            (b"\xC7\xD1\xB1\xB9\xBE\xEE, \xA1\xA1\xA2\xE7!", "한국어, \u{3000}®!"),
+            // The UHC extensions in the 0x41-0xA0 trail byte range.
+            (b"\x81\x41", "갂"),
+            // Invalid lead and trail bytes. ASCII isn't swallowed.
+            (b"\x80\xFF!\xB0\xFFb\xB0\x5B", "\u{FFFD}\u{FFFD}!\u{FFFD}b\u{FFFD}["),
+            // Unmapped codes.
+            (b"\xA2\xE8\xC9\xA1", "\u{FFFD}\u{FFFD}"),
+            // Truncated sequences at the end of the stream.
+            (b"a\xB0", "a\u{FFFD}"),
+            (b"\xB0\xA1\xFE", "가\u{FFFD}"),
+        ];
+
+        for (input, expected) in cases {
+            for chunk_size in [1, 2, 3, 1024] {
+                assert_eq!(
+                    convert_chunked("euc-kr", "UTF-8", input, chunk_size),
+                    expected.as_bytes(),
+                    "{input:x?} in chunks of {chunk_size}"
+                );
+            }
+        }
+
+        // Decoding only.
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        assert!(Converter::new(&mut pivot, "UTF-8", "euc-kr").is_err());
+    }
+
+    #[test]
+    fn test_utf32() {
+        let cases: [(&[u8], &str); 5] = [
+            (b"a\x00\x00\x00\xE4\x00\x00\x00\x00\xF6\x01\x00", "aä😀"),
+            // Surrogates and code points beyond U+10FFFF.
+            (b"\x00\xD8\x00\x00\x00\x00\x11\x00b\x00\x00\x00", "\u{FFFD}\u{FFFD}b"),
+            // Truncated code points at the end of the stream.
+            (b"a\x00\x00\x00b", "a\u{FFFD}"),
+            (b"a\x00\x00", "\u{FFFD}"),
+            (b"", ""),
+        ];
+
+        for (input, expected) in cases {
+            let be: Vec<u8> = input.chunks(4).flat_map(|c| c.iter().rev().copied()).collect();
+            for chunk_size in [1, 2, 3, 5, 1024] {
+                assert_eq!(
+                    convert_chunked("UTF-32LE", "UTF-8", input, chunk_size),
+                    expected.as_bytes(),
+                    "{input:x?} in chunks of {chunk_size}"
+                );
+                // Truncated code points can't be reversed meaningfully.
+                if input.len() % 4 == 0 {
+                    assert_eq!(
+                        convert_chunked("UTF-32BE", "UTF-8", &be, chunk_size),
+                        expected.as_bytes(),
+                        "{be:x?} in chunks of {chunk_size}"
+                    );
+                }
+            }
+        }
+
+        assert_eq!(
+            convert_chunked("UTF-32LE", "UTF-16LE", b"\x00\xF6\x01\x00", 3),
+            b"\x3D\xD8\x00\xDE"
+        );
+
+        // Decoding only.
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        assert!(Converter::new(&mut pivot, "UTF-8", "UTF-32LE").is_err());
+    }
+
+    #[test]
+    fn test_encodings_for_menu() {
+        let menu = encodings_for_menu();
+        let labels: Vec<(&str, Vec<&str>)> = menu
+            .iter()
+            .map(|(group, list)| (*group, list.iter().map(|enc| enc.label).collect()))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                ("Preferred", vec!["UTF-8", "UTF-8 BOM", "UTF-16 LE", "UTF-16 BE"]),
+                ("Unicode", vec!["UTF-32 BE", "UTF-32 LE"]),
+                ("Western", vec!["Windows-1252"]),
+                ("East Asian", vec!["EUC-KR", "GB18030", "Shift-JIS"]),
+            ]
+        );
+
+        // Every encoding is listed exactly once.
+        let count: usize = menu.iter().map(|(_, list)| list.len()).sum();
+        assert_eq!(count, get_available_encodings().all.len());
+    }
+
+    #[test]
+    fn test_encoding_from_canonical() {
+        let canonical = |name| encoding_from_canonical(name).map(|enc| enc.canonical);
+        assert_eq!(canonical("UTF-8 BOM"), Some("UTF-8 BOM"));
+        assert_eq!(canonical("utf-16le"), Some("UTF-16LE"));
+        assert_eq!(canonical("UTF-16 LE"), Some("UTF-16LE"));
+        assert_eq!(canonical("SHIFT_JIS"), Some("shift_jis"));
+        assert_eq!(canonical("Shift-JIS"), Some("shift_jis"));
+        assert_eq!(canonical("ebcdic"), None);
+    }
+
+    #[test]
+    fn test_convert_all() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let mut pivot = [MaybeUninit::uninit(); 64];
+
+        // Large enough to require several passes through the pivot buffer.
+        let text = "Grüße, 世界! 😀 ".repeat(100);
+        let input: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        let mut c = Converter::new(&mut pivot, "UTF-16BE", "UTF-8").unwrap();
+        assert_eq!(c.convert_all(&input, &arena).unwrap().as_str(), text);
+
+        // Incomplete sequences at the end are flushed.
+        let mut c = Converter::new(&mut pivot, "gb18030", "UTF-8 BOM").unwrap();
+        assert_eq!(c.convert_all(b"\xC4\xE3\x81", &arena).unwrap().as_str(), "\u{FEFF}你\u{FFFD}");
+
+        // Invalid UTF-8 is passed through by `convert`, but not here.
+        let mut c = Converter::new(&mut pivot, "UTF-8", "UTF-8").unwrap();
+        assert_eq!(c.convert_all(b"a\xFFb", &arena).unwrap().as_str(), "a\u{FFFD}b");
+
+        let mut c = Converter::new(&mut pivot, "UTF-8", "UTF-16LE").unwrap();
+        assert!(c.convert_all(b"abc", &arena).is_err());
+    }
+
+    #[test]
+    fn test_converter_without_pivot() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let text = "Grüße, 世界! 😀 ".repeat(100);
+
+        let mut c = Converter::without_pivot("windows-1252", "UTF-8").unwrap();
+        assert_eq!(c.convert_all(b"caf\xE9 \x80", &arena).unwrap().as_str(), "café €");
+
+        let mut c = Converter::without_pivot("UTF-8", "windows-1252").unwrap();
+        let mut output = [MaybeUninit::uninit(); 16];
+        let (read, written) = c.convert("café €".as_bytes(), &mut output).unwrap();
+        assert_eq!((read, written), (9, 6));
+        assert_eq!(unsafe { output[..written].assume_init_ref() }, b"caf\xE9 \x80");
+
+        // Anything else works, too, even with far more input than fits into the pivot buffer.
+        // Misaligned UTF-16 can't take the fast path and has to go through the pivot buffer.
+        let unaligned = [&[0][..], &utf16le(&text)].concat();
+        let mut c = Converter::without_pivot("UTF-16LE", "UTF-8").unwrap();
+        assert_eq!(c.convert_all(&unaligned[1..], &arena).unwrap().as_str(), text);
+        let mut c = Converter::without_pivot("UTF-8", "UTF-16BE").unwrap();
+        let mut output = vec![MaybeUninit::uninit(); 2 * text.len()];
+        let (read, written) = c.convert(text.as_bytes(), &mut output).unwrap();
+        assert_eq!(read, text.len());
+        assert_eq!(unsafe { output[..written].assume_init_ref() }, utf16be(&text));
+
+        assert!(Converter::without_pivot("UTF-8", "euc-kr").is_err());
+    }
+
+    #[test]
+    fn test_converter_set_replacement() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let mut output = [MaybeUninit::uninit(); 64];
+
+        let mut c = Converter::without_pivot("UTF-8", "UTF-16LE").unwrap();
+        c.set_replacement('?').unwrap();
+        let (_, written) = c.convert(b"a\xFFb\xEF\xBF\xBD", &mut output).unwrap();
+        assert_eq!(unsafe { output[..written].assume_init_ref() }, utf16le("a?b\u{FFFD}"));
+
+        // The UTF-16 fast path, as well as the multi-byte decoders.
+        let mut c = Converter::without_pivot("UTF-16LE", "UTF-8").unwrap();
+        c.set_replacement('#').unwrap();
+        assert_eq!(c.convert_all(b"a\x00\x00\xDCb\x00", &arena).unwrap().as_str(), "a#b");
+        let mut c = Converter::without_pivot("shift_jis", "UTF-8").unwrap();
+        c.set_replacement('#').unwrap();
+        assert_eq!(c.convert_all(b"a\xFFb", &arena).unwrap().as_str(), "a#b");
+
+        // Single-byte targets use it for unmappable characters, too.
+        let mut c = Converter::without_pivot("UTF-8", "windows-1252").unwrap();
+        let (_, written) = c.convert(b"a\xFF\xE3\x81\x82", &mut output).unwrap();
+        assert_eq!(unsafe { output[..written].assume_init_ref() }, b"a??");
+        c.reset();
+        c.set_replacement('\u{A4}').unwrap();
+        let (_, written) = c.convert(b"a\xFF\xE3\x81\x82", &mut output).unwrap();
+        assert_eq!(unsafe { output[..written].assume_init_ref() }, b"a\xA4\xA4");
+        assert_eq!(c.replacement_count(), 1);
+        assert_eq!(c.unmappable_count(), 1);
+
+        assert!(c.set_replacement('\u{FFFD}').is_err());
+        assert!(c.set_replacement('\u{3042}').is_err());
+        let mut c = Converter::without_pivot("UTF-8", "UTF-16LE").unwrap();
+        assert!(c.set_replacement('\u{1F600}').is_err());
+    }
+
+    #[test]
+    fn test_converter_set_transliterate() {
+        let input = "Kőszeg ‐ “Łódź” café あ".as_bytes();
+        let mut output = [MaybeUninit::uninit(); 64];
+
+        let mut c = Converter::without_pivot("UTF-8", "windows-1252").unwrap();
+        let (_, written) = c.convert(input, &mut output).unwrap();
+        let expected = b"K?szeg ? \x93?\xF3d?\x94 caf\xE9 ?";
+        assert_eq!(unsafe { output[..written].assume_init_ref() }, expected);
+        assert_eq!(c.replacement_count(), 0);
+        assert_eq!(c.unmappable_count(), 5);
+
+        let mut c = Converter::without_pivot("UTF-8", "windows-1252").unwrap();
+        c.set_transliterate(true);
+        let (_, written) = c.convert(input, &mut output).unwrap();
+        let expected = b"Koszeg - \x93L\xF3dz\x94 caf\xE9 ?";
+        assert_eq!(unsafe { output[..written].assume_init_ref() }, expected);
+        assert_eq!(c.unmappable_count(), 1);
+
+        assert!(tables::TRANSLITERATIONS.is_sorted_by_key(|&(c, _)| c));
+        // U+2032 PRIME only turns into ASCII via U+00B4 ACUTE ACCENT.
+        assert_eq!(transliterate('\u{2032}'), Some(b'\''));
+        assert_eq!(transliterate('æ'), None);
+    }
+
+    #[test]
+    fn test_can_encode_losslessly() {
+        let enc = |name| encoding_from_canonical(name).unwrap();
+        let text = "café €5 – 日本 😀";
+
+        assert_eq!(can_encode_losslessly(text, enc("UTF-8")), Ok(()));
+        assert_eq!(can_encode_losslessly(text, enc("UTF-16BE")), Ok(()));
+        assert_eq!(can_encode_losslessly(text, enc("gb18030")), Ok(()));
+        assert_eq!(
+            can_encode_losslessly(text, enc("windows-1252")),
+            Err(vec![(15, '日'), (18, '本'), (22, '😀')])
+        );
+        assert_eq!(
+            can_encode_losslessly(text, enc("shift_jis")),
+            Err(vec![(3, 'é'), (6, '€'), (11, '–'), (22, '😀')])
+        );
+        assert_eq!(can_encode_losslessly("한국어 abc", enc("euc-kr")), Ok(()));
+        assert_eq!(can_encode_losslessly("", enc("windows-1252")), Ok(()));
+    }
+
+    #[test]
+    fn test_replacement_count() {
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        let mut output = [MaybeUninit::uninit(); 64];
+
+        // An invalid lead byte, a truncated sequence, and a literal U+FFFD which doesn't count.
+        let mut c = Converter::new(&mut pivot, "UTF-8", "UTF-16LE").unwrap();
+        let input = b"a\xFFb\xE2\x82c\xEF\xBF\xBD";
+        assert_eq!(c.convert(input, &mut output).unwrap(), (input.len(), 12));
+        assert_eq!(c.replacement_count(), 2);
+
+        // A truncated sequence at the end of the stream.
+        c.convert(b"\xE2\x82", &mut output).unwrap();
+        assert_eq!(c.replacement_count(), 2);
+        c.convert(b"", &mut output).unwrap();
+        assert_eq!(c.replacement_count(), 3);
+
+        // A lone low surrogate and a lone high surrogate at the end of the stream.
+        let mut c = Converter::new(&mut pivot, "UTF-16LE", "UTF-8").unwrap();
+        c.convert(b"\x00\xDCa\x00\x00\xD8", &mut output).unwrap();
+        c.convert(b"", &mut output).unwrap();
+        assert_eq!(c.replacement_count(), 2);
+    }
+
+    #[test]
+    fn test_utf8_bom() {
+        for chunk_size in [1, 2, 3, 1024] {
+            let convert =
+                |source, target, input: &[u8]| convert_chunked(source, target, input, chunk_size);
+
+            // The BOM is stripped when decoding, but only at the start of the stream.
+            assert_eq!(
+                convert("UTF-8 BOM", "UTF-8", b"\xEF\xBB\xBFa\xEF\xBB\xBF"),
+                b"a\xEF\xBB\xBF"
+            );
+            assert_eq!(convert("UTF-8 BOM", "UTF-8", b"abc"), b"abc");
+            // A partial BOM is an invalid sequence like any other.
+            assert_eq!(convert("UTF-8 BOM", "UTF-8", b"\xEF\xBBa"), "\u{FFFD}a".as_bytes());
+            assert_eq!(convert("UTF-8 BOM", "UTF-8", b"\xEF\xBB"), "\u{FFFD}".as_bytes());
+            assert_eq!(convert("UTF-8 BOM", "UTF-16LE", b"\xEF\xBB\xBFa"), b"a\x00");
+            assert_eq!(convert("UTF-8 BOM", "UTF-16LE", b"\xEF\xBBa"), b"\xFD\xFFa\x00");
+
+            // The BOM is emitted when encoding.
+            assert_eq!(convert("UTF-8", "UTF-8 BOM", b"abc"), b"\xEF\xBB\xBFabc");
+            assert_eq!(convert("UTF-8", "UTF-8 BOM", b""), b"\xEF\xBB\xBF");
+            assert_eq!(convert("UTF-8 BOM", "UTF-8 BOM", b"\xEF\xBB\xBFa"), b"\xEF\xBB\xBFa");
+        }
+
+        // The BOM is written exactly once, even if the output is too small for it at first.
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        let mut output = [MaybeUninit::uninit(); 8];
+        let mut c = Converter::new(&mut pivot, "UTF-8", "UTF-8 BOM").unwrap();
+        assert!(c.convert(b"ab", &mut output[..2]).is_err());
+        assert_eq!(c.convert(b"ab", &mut output).unwrap(), (2, 5));
+        assert_eq!(c.convert(b"cd", &mut output).unwrap(), (2, 2));
+        assert_eq!(unsafe { output[..2].assume_init_ref() }, b"cd");
+    }
+
+    #[test]
+    fn test_max_bytes_per_char() {
+        let max = |name| max_bytes_per_char(encoding_from_canonical(name).unwrap());
+        assert_eq!(max("UTF-8"), 4);
+        assert_eq!(max("UTF-16LE"), 4);
+        assert_eq!(max("windows-1252"), 1);
+        assert_eq!(max("shift_jis"), 2);
+        assert_eq!(max_bytes_per_char(&Encoding { label: "?", canonical: "?" }), 4);
+
+        // Outputs that can't hold a single character are rejected instead of making no progress.
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        let mut output = [MaybeUninit::uninit(); 4];
+        let mut c = Converter::new(&mut pivot, "UTF-16LE", "UTF-8").unwrap();
+        let err = c.convert(&utf16le("🦀"), &mut output[..3]).unwrap_err();
+        assert_eq!(err, apperr::Error::new_icu(IcuError::BufferOverflow.code()));
+        assert_eq!(c.convert(&utf16le("🦀"), &mut output).unwrap(), (4, 4));
+
+        let mut c = Converter::new(&mut pivot, "UTF-8", "windows-1252").unwrap();
+        assert_eq!(c.convert("ä".as_bytes(), &mut output[..1]).unwrap(), (2, 1));
+        assert_eq!(unsafe { output[0].assume_init() }, 0xE4);
+    }
+
+    #[test]
+    fn test_line_ending() {
+        let line_ending = |source: &str, input: &[u8], chunk_size: usize| {
+            let mut pivot = [MaybeUninit::uninit(); 64];
+            let mut output = [MaybeUninit::uninit(); 16];
+            let mut c = Converter::new(&mut pivot, source, "UTF-8").unwrap();
+            for chunk in input.chunks(chunk_size) {
+                let mut read = 0;
+                while read < chunk.len() {
+                    read += c.convert(&chunk[read..], &mut output).unwrap().0;
+                }
+            }
+            while c.convert(b"", &mut output).unwrap().1 != 0 {}
+            c.line_ending()
+        };
+
+        // Every chunk size splits some "\r\n" in two.
+        for chunk_size in [1, 2, 3, 1024] {
+            assert_eq!(line_ending("UTF-8", b"a\nb\nc", chunk_size), LineEnding::Lf);
+            assert_eq!(line_ending("UTF-8", b"a\r\nb\r\n", chunk_size), LineEnding::CrLf);
+            assert_eq!(line_ending("UTF-8", b"a\rb\r", chunk_size), LineEnding::Cr);
+            assert_eq!(line_ending("UTF-8", b"a\r\nb\n", chunk_size), LineEnding::Mixed);
+            assert_eq!(line_ending("UTF-8", b"a\r\rb", chunk_size), LineEnding::Cr);
+            assert_eq!(line_ending("UTF-8", b"abc", chunk_size), LineEnding::Lf);
+            assert_eq!(
+                line_ending("UTF-16LE", &utf16le("a\r\nb\r\n"), chunk_size),
+                LineEnding::CrLf
+            );
+            assert_eq!(line_ending("UTF-16BE", &utf16be("a\rb\n"), chunk_size), LineEnding::Mixed);
+        }
+    }
+
+    #[test]
+    fn test_converter_reset() {
+        let mut output = [MaybeUninit::uninit(); 64];
+        let mut convert = |c: &mut Converter, input: &[u8]| {
+            let (read, written) = c.convert(input, &mut output).unwrap();
+            assert_eq!(read, input.len());
+            unsafe { output[..written].assume_init_ref() }.to_vec()
+        };
+
+        // The first file contains a lone surrogate and ends in the middle of a code unit.
+        let mut c = Converter::without_pivot("UTF-16LE", "UTF-8").unwrap();
+        let input = [&utf16le("a\r\n")[..], b"\x00\xDCb"].concat();
+        assert_eq!(convert(&mut c, &input), "a\r\n\u{FFFD}".as_bytes());
+        assert_eq!(c.replacement_count(), 1);
+        assert_eq!(c.line_ending(), LineEnding::CrLf);
+
+        // Without a reset, the incomplete code unit would be prepended to the next file.
+        c.reset();
+        assert_eq!(c.replacement_count(), 0);
+        assert_eq!(c.line_ending(), LineEnding::Lf);
+        assert_eq!(convert(&mut c, &utf16le("c\n")), b"c\n");
+        assert_eq!(convert(&mut c, b""), b"");
+
+        // The same goes for the start of a BOM, and the next file gets its BOM stripped again.
+        let mut c = Converter::without_pivot("UTF-8 BOM", "UTF-8").unwrap();
+        assert_eq!(convert(&mut c, b"\xEF\xBB"), b"");
+        c.reset();
+        assert_eq!(convert(&mut c, b"x"), b"x");
+        c.reset();
+        assert_eq!(convert(&mut c, b"\xEF\xBB\xBFy"), b"y");
+    }
+
+    #[test]
+    fn test_detect_encoding() {
+        let detect = |bytes: &[u8]| detect_encoding(bytes).map(|enc| enc.canonical);
+        assert_eq!(detect(b"\xEF\xBB\xBFabc"), Some("UTF-8 BOM"));
+        assert_eq!(detect(b"\xFF\xFEa\x00"), Some("UTF-16LE"));
+        assert_eq!(detect(b"\xFE\xFF\x00a"), Some("UTF-16BE"));
+        assert_eq!(detect(b"\xFF\xFE\x00\x00a\x00\x00\x00"), Some("UTF-32LE"));
+        assert_eq!(detect(b"\x00\x00\xFE\xFF\x00\x00\x00a"), Some("UTF-32BE"));
+        assert_eq!(detect(b"\xEF\xBB"), None);
+        assert_eq!(detect(b"abc"), None);
+        assert_eq!(detect(b""), None);
+    }
+
+    #[test]
+    fn test_sniff_encoding() {
+        let sniff = |bytes: &[u8]| sniff_encoding(bytes).canonical;
+        assert_eq!(sniff(b"\xEF\xBB\xBFabc"), "UTF-8 BOM");
+        assert_eq!(sniff(b""), "UTF-8");
+        assert_eq!(sniff(b"plain ASCII"), "UTF-8");
+        assert_eq!(sniff("Größe 日本".as_bytes()), "UTF-8");
+        // Cut off in the middle of "ñ".
+        assert_eq!(sniff(b"a\xC3"), "UTF-8");
+
+        assert_eq!(sniff(b"h\x00i\x00 \x00\x16\x4E"), "UTF-16LE");
+        assert_eq!(sniff(b"\x00h\x00i\x00 \x4E\x16"), "UTF-16BE");
+        assert_eq!(sniff(b"h\x00\x00\x00i\x00\x00\x00"), "UTF-32LE");
+        assert_eq!(sniff(b"\x00\x00\x00h\x00\x00\x00i"), "UTF-32BE");
+
+        assert_eq!(sniff(b"Gr\xF6\xDFe caf\xE9 na\xEFve"), "windows-1252");
+        assert_eq!(sniff(b"Gr\xF6\xDFe"), "windows-1252");
+        assert_eq!(sniff(b"\x93quoted\x94"), "windows-1252");
+        // "こんにちは、世界。テキスト"
+        let japanese =
+            b"\x82\xB1\x82\xF1\x82\xC9\x82\xBF\x82\xCD\x81A\x90\xA2\x8AE\x81B\x83e\x83L\x83X\x83g";
+        assert_eq!(sniff(japanese), "shift_jis");
+        // "안녕하세요 세계. 한국어"
+        let korean =
+            b"\xBE\xC8\xB3\xE7\xC7\xCF\xBC\xBC\xBF\xE4 \xBC\xBC\xB0\xE8. \xC7\xD1\xB1\xB9\xBE\xEE";
+        assert_eq!(sniff(korean), "euc-kr");
+        // "你好，世界。这是一个"
+        let chinese =
+            b"\xC4\xE3\xBA\xC3\xA3\xAC\xCA\xC0\xBD\xE7\xA1\xA3\xD5\xE2\xCA\xC7\xD2\xBB\xB8\xF6";
+        assert_eq!(sniff(chinese), "gb18030");
+    }
+
+    #[test]
+    fn test_compare_str() {
+        assert_eq!(compare_str("", ""), Ordering::Equal);
+        assert_eq!(compare_str("a", "ab"), Ordering::Less);
+        assert_eq!(compare_str("B", "a"), Ordering::Less);
+        assert_eq!(compare_str("é", "z"), Ordering::Greater);
+        // U+FF5E is greater than the surrogates of U+1F600 in UTF-16.
+        assert_eq!(compare_str("\u{FF5E}", "\u{1F600}"), Ordering::Less);
+        for (a, b) in [("ä", "b"), ("日本", "日"), ("x\u{10000}", "x\u{FFFF}")] {
+            assert_eq!(compare_str(a, b), compare_strings(a.as_bytes(), b.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_compare_strings_ci() {
+        assert_eq!(compare_strings_ci(b"apple", b"Apple"), Ordering::Equal);
+        assert_eq!(compare_strings_ci(b"Apple", b"banana"), Ordering::Less);
+        assert_eq!(compare_strings_ci(b"apple", b"BANANA"), Ordering::Less);
+        assert_eq!(compare_strings_ci(b"apple", b"APPLES"), Ordering::Less);
+        assert_eq!(compare_strings_ci(b"a_b", b"A[B"), Ordering::Greater);
+        assert_eq!(compare_strings_ci("Äpfel".as_bytes(), "äpfel".as_bytes()), Ordering::Less);
+        assert_eq!(compare_strings_ci(b"", b""), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_compare_strings_natural() {
+        let mut names = ["file10", "file2", "file1", "file", "file1a", "file01", "a100b"];
+        names.sort_by(|a, b| compare_strings_natural(a.as_bytes(), b.as_bytes()));
+        assert_eq!(names, ["a100b", "file", "file1", "file01", "file1a", "file2", "file10"]);
+
+        assert_eq!(compare_strings_natural(b"7", b"007"), Ordering::Less);
+        assert_eq!(compare_strings_natural(b"007", b"7"), Ordering::Greater);
+        assert_eq!(compare_strings_natural(b"007b", b"7a"), Ordering::Greater);
+        assert_eq!(compare_strings_natural(b"0", b"00"), Ordering::Less);
+        assert_eq!(compare_strings_natural(b"x1.10", b"x1.9"), Ordering::Greater);
+        assert_eq!(
+            compare_strings_natural(b"99999999999999999999999999", b"100000000000000000000000000"),
+            Ordering::Less
+        );
+        assert_eq!(compare_strings_natural(b"abc", b"abc"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_compare_strings_with() {
+        let opts = CompareOptions { case_insensitive: true, numeric: true, ..Default::default() };
+        let mut names = ["File10", "file2", "FILE1", "file1b", "File1A"];
+        names.sort_by(|a, b| compare_strings_with(a.as_bytes(), b.as_bytes(), opts));
+        assert_eq!(names, ["FILE1", "File1A", "file1b", "file2", "File10"]);
+
+        assert_eq!(compare_strings_with(b"Img7", b"img007", opts), Ordering::Less);
+        assert_eq!(compare_strings_with(b"B", b"a", CompareOptions::default()), Ordering::Less);
+        assert_eq!(compare_strings_with(b"B", b"a", opts), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_compare_lines() {
+        assert_eq!(compare_lines(b"foo \t\r", b"foo", true, false), Ordering::Equal);
+        assert_eq!(compare_lines(b"foo\r", b"foo", false, false), Ordering::Greater);
+        assert_eq!(compare_lines(b" \r", b"", true, false), Ordering::Equal);
+        // Only trailing whitespace is ignored.
+        assert_eq!(compare_lines(b" foo", b"foo", true, false), Ordering::Less);
+        assert_eq!(compare_lines(b"a b", b"a  b", true, false), Ordering::Greater);
+
+        assert_eq!(compare_lines(b"Foo ", b"fOO", true, true), Ordering::Equal);
+        assert_eq!(compare_lines(b"Foo", b"fOO", true, false), Ordering::Less);
+    }
+
+    #[test]
+    fn test_fold_case() {
+        let arena = Arena::new(4 * 1024).unwrap();
+        let fold = |s| fold_case(&arena, s);
+
+        assert_eq!(fold("Hello, World!"), "hello, world!");
+        assert_eq!(fold("Straße"), "strasse");
+        assert_eq!(fold("STRASSE"), "strasse");
+        assert_eq!(fold("ẞ"), "ss");
+        assert_eq!(fold("ΣΑΣ"), "σασ");
+        assert_eq!(fold("σας"), "σασ");
+        assert_eq!(fold("ﬁle"), "file");
+        assert_eq!(fold("µ"), "μ");
+        assert_eq!(fold("ᏸ Ᏸ ꭰ Ꭰ"), "Ᏸ Ᏸ Ꭰ Ꭰ");
+
+        // No Turkish mappings.
+        assert_eq!(fold("I"), "i");
+        assert_eq!(fold("ı"), "ı");
+        assert_eq!(fold("İ"), "i\u{307}");
+    }
+
+    #[test]
+    fn test_fold_char_simple() {
+        let fold: String = "Straße ẞ ΣΑς ﬁ İ ꭰ".chars().map(fold_char_simple).collect();
+        assert_eq!(fold, "straße ß σασ ﬁ İ Ꭰ");
+    }
+
+    #[test]
+    fn test_fold_case_into() {
+        let arena = Arena::new(4 * 1024).unwrap();
+        let mut buf = ArenaString::new_in(&arena);
+
+        fold_case_into(&mut buf, "Straße");
+        assert_eq!(buf, "strasse");
+        fold_case_into(&mut buf, "ABC");
+        assert_eq!(buf, "abc");
+        fold_case_into(&mut buf, "");
+        assert_eq!(buf, "");
+        for s in ["Hello, World!", "ΣΑΣ", "ᏸ Ᏸ", "İ"] {
+            fold_case_into(&mut buf, s);
+            assert_eq!(buf, fold_case(&arena, s), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_fold_case_locale() {
+        let arena = Arena::new(4 * 1024).unwrap();
+        let fold = |s| fold_case_locale(&arena, s, Locale::Turkish);
+
+        assert_eq!(fold("DİYARBAKIR"), "diyarbakır");
+        assert_eq!(fold("ıi"), "ıi");
+        assert_eq!(fold("TITLE"), "tıtle");
+        assert_eq!(fold("Title"), "title");
+        assert_eq!(fold("Straße"), "strasse");
+        assert_eq!(fold_case_locale(&arena, "DİYARBAKIR", Locale::Default), "di\u{307}yarbakir");
+    }
+
+    #[test]
+    fn test_text_get_slice() {
+        let text = text_detached("añb");
+        assert_eq!(text.get_slice(0..4), Some("añb"));
+        assert_eq!(text.get_slice(1..3), Some("ñ"));
+        assert_eq!(text.get_slice(4..4), Some(""));
+        assert_eq!(text.get_slice(1..2), None);
+        assert_eq!(text.get_slice(2..4), None);
+        assert_eq!(text.get_slice(3..5), None);
+    }
+
+    #[test]
+    fn test_text_line_count() {
+        let count = |s| text_detached(s).line_count();
+        assert_eq!(count(""), 0);
+        assert_eq!(count("a"), 1);
+        assert_eq!(count("a\nb"), 2);
+        assert_eq!(count("a\nb\n"), 2);
+        assert_eq!(count("a\r\n\r\nb"), 3);
+        assert_eq!(count("\n"), 1);
+        for s in ["", "a\nb\n", "\n\n", "a\n\nb"] {
+            assert_eq!(count(s), text_detached(s).lines().count(), "{s:?}");
+        }
+
+        let text = text_detached("añb ñ\nñ");
+        assert_eq!(text.count_char('ñ'), 3);
+        assert_eq!(text.count_char(' '), 1);
+        assert_eq!(text.count_char('x'), 0);
+    }
+
+    #[test]
+    fn test_normalize_nfc() {
+        assert!(matches!(normalize_nfc("café 日本 한국"), Cow::Borrowed(_)));
+        assert_eq!(normalize_nfc("cafe\u{301}"), "café");
+        assert_eq!(normalize_nfc("\u{301}e\u{301}"), "\u{301}é");
+        // Marks are reordered by their combining class before they're composed.
+        assert_eq!(normalize_nfc("e\u{301}\u{323}"), "ẹ\u{301}");
+        assert_eq!(normalize_nfc("q\u{307}\u{323}"), "q\u{323}\u{307}");
+        // Singletons and composition exclusions stay decomposed.
+        assert_eq!(normalize_nfc("\u{212B}\u{2126}"), "\u{C5}\u{3A9}");
+        assert_eq!(normalize_nfc("\u{958}"), "\u{915}\u{93C}");
+        // Hangul is composed algorithmically, and some starters compose with each other.
+        assert_eq!(normalize_nfc("\u{1100}\u{1161}\u{11A8}"), "\u{AC01}");
+        assert_eq!(normalize_nfc("\u{AC00}\u{11A8}"), "\u{AC01}");
+        assert_eq!(normalize_nfc("\u{9C7}\u{9BE}"), "\u{9CB}");
+
+        let opts = CompareOptions { normalize: true, ..Default::default() };
+        let decomposed = "cafe\u{301}".as_bytes();
+        assert_eq!(compare_strings_with("café".as_bytes(), decomposed, opts), Ordering::Equal);
+        assert_ne!(compare_strings("café".as_bytes(), decomposed), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_text_normalize() {
+        let mut text = text_detached("Cafe\u{301} café cafe\u{301}!");
+        text.set_normalize(true);
+        assert_eq!(*text.content, "Café café café!");
+
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE | Regex::NORMALIZE;
+        let mut regex = Regex::new("cafe\u{301}", flags, &text).unwrap();
+        let matches: Vec<_> = regex.by_ref().map(|r| text.original_range(r)).collect();
+        assert_eq!(matches, [0..6, 7..12, 13..19]);
+
+        // Offsets within a changed char are extended to all of it.
+        assert_eq!(text.original_range(3..4), 3..6);
+        assert_eq!(text.original_range(17..18), 19..20);
+        text.set_normalize(true);
+        assert_eq!(text.original_range(17..18), 19..20);
+    }
+
+    #[test]
+    fn test_eq_ignore_case() {
+        assert!(eq_ignore_case("[Section]", "[SECTION]"));
+        assert!(!eq_ignore_case("Section", "Sections"));
+        assert!(eq_ignore_case("Straße", "STRASSE"));
+        assert!(eq_ignore_case("ẞ", "ss"));
+        assert!(eq_ignore_case("ΣΑΣ", "σας"));
+        assert!(eq_ignore_case("ᏸ", "Ᏸ"));
+        assert!(!eq_ignore_case("Straße", "strass"));
+        assert!(!eq_ignore_case("ß", "s"));
+        assert!(eq_ignore_case("", ""));
+    }
+
+    fn text_buffer(s: &str) -> TextBuffer {
+        let mut tb = TextBuffer::new(true).unwrap();
+        tb.write_raw(s.as_bytes());
+        tb
+    }
+
+    /// Creates a `Text` without a backing `TextBuffer`. It must not be refreshed.
+    /// Useful for multi-line contents, because `TextBuffer` needs the scratch arena for those.
+    fn text_detached(s: &str) -> Text<'static> {
+        Text {
+            content: Rc::new(s.to_string()),
+            tb: None,
+            replacements: Vec::new(),
+            line_starts: OnceCell::new(),
+            normalized: None,
+            final_newline: FinalNewline::default(),
+        }
+    }
+
+    #[test]
+    fn test_regex_set_text() {
+        let mut tb = text_buffer("foo bar foo");
+        let text = Text::from_buffer(&tb).detach();
+        let mut regex = Regex::new("foo", Regex::LITERAL, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 8..11]);
+
+        tb.write_raw(b" foo");
+        let mut text = text.attach(&tb);
+        regex.set_text(&mut text, 0);
+        assert_eq!(text.content.as_str(), "foo bar foo foo");
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 8..11, 12..15]);
+    }
+
+    #[test]
+    fn test_regex_prev() {
+        let tb = text_buffer("foo bar Foo baz foo");
+        let text = Text::from_buffer(&tb);
+
+        let mut regex = Regex::new("foo", Regex::LITERAL, &text).unwrap();
+        regex.reset(19);
+        assert_eq!(regex.prev(), Some(16..19));
+        assert_eq!(regex.prev(), Some(0..3));
+        assert_eq!(regex.prev(), None);
+
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+        regex.reset(18);
+        assert_eq!(regex.prev(), Some(8..11));
+        assert_eq!(regex.prev(), Some(0..3));
+        assert_eq!(regex.prev(), None);
+
+        // Right after `next`, `prev` moves to the match before the current one.
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.next(), Some(8..11));
+        assert_eq!(regex.prev(), Some(0..3));
+        assert_eq!(regex.prev(), None);
+        regex.reset(11);
+        assert_eq!(regex.next(), Some(16..19));
+        assert_eq!(regex.prev(), Some(8..11));
+
+        // An explicit position isn't affected by the current match.
+        regex.reset(11);
+        assert_eq!(regex.next(), Some(16..19));
+        regex.reset(19);
+        assert_eq!(regex.prev(), Some(16..19));
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        let mut ranges = vec![8..10, 0..2, 4..4, 1..3, 3..5, 10..12, 20..21, 7..9];
+        merge_ranges(&mut ranges);
+        assert_eq!(ranges, [0..5, 7..12, 20..21]);
+    }
+
+    #[test]
+    fn test_regex_find_all_merged() {
+        let text = text_detached("aa xa aaa\nxa a");
+        let mut regex = Regex::new("a", Regex::LITERAL, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..1));
+
+        assert_eq!(regex.find_all_merged(0..usize::MAX), [0..2, 4..5, 6..9, 11..12, 13..14]);
+        assert_eq!(regex.find_all_merged(1..8), [1..2, 4..5, 6..8]);
+        assert!(regex.find_all_merged(2..4).is_empty());
+
+        // The search range applies as well, and the position is unaffected.
+        regex.set_search_range(5..12);
+        regex.reset(7);
+        assert_eq!(regex.find_all_merged(7..usize::MAX), [7..9, 11..12]);
+        assert_eq!(regex.next(), Some(7..8));
+    }
+
+    #[test]
+    fn test_regex_find_last() {
+        let text = text_detached("error: a\nok\nerror: b\nok\n");
+        let mut regex = Regex::new("error:", Regex::LITERAL, &text).unwrap();
+
+        assert_eq!(regex.find_last(), Some(12..18));
+        assert_eq!(regex.prev(), Some(0..6));
+        assert_eq!(regex.find_last(), Some(12..18));
+        assert_eq!(regex.next(), Some(12..18));
+        assert_eq!(regex.next(), None);
+
+        regex.set_search_range(0..12);
+        assert_eq!(regex.find_last(), Some(0..6));
+
+        // Without a match the position is unaffected.
+        let mut regex = Regex::new("warning:", Regex::LITERAL, &text).unwrap();
+        regex.reset(3);
+        assert_eq!(regex.find_last(), None);
+        assert_eq!(regex.remaining_matches(), 0);
+        assert_eq!(regex.prev(), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_nth_match() {
+        let tb = text_buffer("a1 b2 c3 d4");
+        let text = Text::from_buffer(&tb);
+        let mut regex = Regex::new(r"(\w)\d", 0, &text).unwrap();
+
+        assert_eq!(regex.next(), Some(0..2));
+        assert_eq!(regex.next(), Some(3..5));
+        assert_eq!(regex.nth_match(2), Some(6..8));
+        assert_eq!(regex.group(1), Some(6..7));
+        assert_eq!(regex.next(), Some(9..11));
+        assert_eq!(regex.nth_match(0), Some(0..2));
+        assert_eq!(regex.next(), Some(3..5));
+        assert_eq!(regex.nth_match(4), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_group_absolute() {
+        let window = text_detached("id=42;");
+        let mut regex = Regex::new(r"id=(\d+)", 0, &window).unwrap();
+        assert_eq!(regex.next(), Some(0..5));
+        assert_eq!(regex.group_absolute(1, 1000), Some(1003..1005));
+        assert_eq!(regex.group_absolute(0, 0), Some(0..5));
+        assert_eq!(regex.group_absolute(2, 1000), None);
+    }
+
+    #[test]
+    fn test_regex_count_matches() {
+        let tb = text_buffer("foo bar Foo baz foo");
+        let text = Text::from_buffer(&tb);
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.count_matches(), 3);
+        assert_eq!(regex.next(), Some(8..11));
+
+        let regex = Regex::new("xyz", Regex::LITERAL, &text).unwrap();
+        assert_eq!(regex.count_matches(), 0);
+    }
+
+    #[test]
+    fn test_find_bytes() {
+        let text = text_detached("ab€ab\u{FFFD}");
+        assert_eq!(find_bytes(&text, b"ab", 0), Some(0..2));
+        assert_eq!(find_bytes(&text, b"ab", 1), Some(5..7));
+        assert_eq!(find_bytes(&text, b"ab", 6), None);
+        // The first candidate for `b` doesn't match in full.
+        assert_eq!(find_bytes(&text, b"b\xEF", 0), Some(6..8));
+        assert_eq!(find_bytes(&text, b"\xBD\x00", 0), None);
+
+        // Matches may start and end in the middle of a char.
+        assert_eq!(find_bytes(&text, b"\x82\xACa", 0), Some(3..6));
+        assert_eq!(find_bytes(&text, b"\xBD", 0), Some(9..10));
+
+        assert_eq!(find_bytes(&text, b"", 4), Some(4..4));
+        assert_eq!(find_bytes(&text, b"", 11), None);
+        assert_eq!(find_bytes(&text, b"abc", 0), None);
+    }
+
+    #[test]
+    fn test_escape_literal() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let text = text_detached("a+b (a+b) a.b");
+
+        for needle in ["a+b", "(a+b)", "a.b", "$"] {
+            let pattern = escape_literal(&arena, needle);
+            let regex = Regex::new(&pattern, 0, &text).unwrap();
+            let expected = text.content.matches(needle).count();
+            assert_eq!(regex.count_matches(), expected, "{needle} as {pattern}");
+        }
+
+        #[cfg(feature = "regex")]
+        assert_eq!(escape_literal(&arena, "a.b*"), r"a\.b\*");
+        #[cfg(not(feature = "regex"))]
+        assert_eq!(escape_literal(&arena, "a.b*"), "a.b*");
+    }
+
+    #[test]
+    fn test_is_plain_literal() {
+        assert!(is_plain_literal("foo bar"));
+        assert!(is_plain_literal("größe-42, #!"));
+        assert!(is_plain_literal(""));
+        for meta in [".", "^", "$", "*", "+", "?", "(", ")", "[", "]", "{", "}", "|", "\\"] {
+            assert!(!is_plain_literal(&format!("a{meta}b")), "{meta}");
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_cache() {
+        let text = text_detached("foo");
+        let cached =
+            || REGEX_CACHE.with_borrow(|c| c.iter().map(|e| e.0.clone()).collect::<Vec<_>>());
+        REGEX_CACHE.with_borrow_mut(Vec::clear);
+
+        // Plain literals aren't compiled at all, so these patterns use a metacharacter.
+        for i in 0..10 {
+            Regex::new(&format!("a{i}+"), 0, &text).unwrap();
+        }
+        assert_eq!(cached(), ["a9+", "a8+", "a7+", "a6+", "a5+", "a4+", "a3+", "a2+"]);
+
+        // Hits move to the front. The flags are part of the key.
+        Regex::new("a5+", 0, &text).unwrap();
+        Regex::new("a9+", Regex::CASE_INSENSITIVE, &text).unwrap();
+        assert_eq!(cached(), ["a9+", "a5+", "a9+", "a8+", "a7+", "a6+", "a4+", "a3+"]);
+        Regex::new("a1", 0, &text).unwrap();
+        assert_eq!(cached()[0], "a9+");
+
+        // Invalid patterns aren't cached.
+        assert!(Regex::new("(", 0, &text).is_err());
+        assert_eq!(cached().len(), REGEX_CACHE_SIZE);
+        assert_eq!(cached()[0], "a9+");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_literal_fast_path() {
+        let text = text_detached("größe a.b grö größe\ngröße");
+        let literal = |pattern, flags| {
+            let regex = Regex::new(pattern, flags, &text).unwrap();
+            // The automaton is only compiled for patterns that need it.
+            assert_eq!(regex.inner.is_none(), regex.literal.is_some());
+            regex.literal
+        };
+        assert_eq!(literal("größe", 0).as_deref(), Some("größe"));
+        assert_eq!(literal("a.b", Regex::LITERAL).as_deref(), Some("a.b"));
+        assert_eq!(literal("a.b", 0), None);
+        assert_eq!(literal("größe", Regex::CASE_INSENSITIVE), None);
+        assert_eq!(literal("größe", Regex::WHOLE_WORD), None);
+
+        // The fast path finds the same matches as the automaton.
+        for (pattern, flags) in [("größe", 0), ("ö", 0), ("a.b", Regex::LITERAL), ("gr", 0)] {
+            let mut fast = Regex::new(pattern, flags, &text).unwrap();
+            let mut slow = Regex::new(pattern, flags, &text).unwrap();
+            let options = RegexOptions::from_flags(flags);
+            slow.inner =
+                Some(Regex::build(&Regex::prepare_pattern(pattern, &options), &options).unwrap());
+            slow.literal = None;
+            assert!(fast.capture_names().eq(slow.capture_names()));
+
+            for range in [0..usize::MAX, 1..20, 3..26] {
+                fast.set_search_range(range.clone());
+                slow.set_search_range(range);
+                assert_eq!(fast.count_matches(), slow.count_matches(), "{pattern}");
+                assert_eq!(fast.find_all(100), slow.find_all(100), "{pattern}");
+                fast.reset(3);
+                slow.reset(3);
+                assert_eq!(fast.next(), slow.next(), "{pattern}");
+                assert_eq!(fast.current_captures(), slow.current_captures(), "{pattern}");
+                assert_eq!(fast.prev(), slow.prev(), "{pattern}");
+                assert_eq!(fast.match_at(6), slow.match_at(6), "{pattern}");
+                assert_eq!(fast.is_full_match(), slow.is_full_match(), "{pattern}");
+            }
+        }
+
+        // Literals that are too long for the automaton work as well.
+        let long = "ab".repeat(500_000);
+        let mut regex = Regex::new(&long, 0, &text_detached(&long)).unwrap();
+        assert_eq!(regex.next(), Some(0..long.len()));
+        assert!(regex.is_full_match());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_unknown_property() {
+        let text = text_detached("漢字 abc");
+        let mut re = Regex::new(r"\p{Han}+", 0, &text).unwrap();
+        assert_eq!(re.next(), Some(0..6));
+
+        let unknown = apperr::Error::new_icu(IcuError::UnknownProperty.code());
+        let invalid = apperr::Error::new_icu(IcuError::InvalidPattern.code());
+        for pattern in [r"\p{Nope}", r"\p{Script=Nope}", r"a\P{Bogus}"] {
+            let res = Regex::new(pattern, 0, &text);
+            assert!(matches!(res, Err(e) if e == unknown), "{pattern}");
+            let res = ChunkedRegex::with_options(pattern, &RegexOptions::default());
+            assert!(matches!(res, Err(e) if e == unknown), "{pattern}");
+        }
+        let res = Regex::new(r"\p{Han", 0, &text);
+        assert!(matches!(res, Err(e) if e == invalid));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_size_limit() {
+        let text = text_detached("aaa");
+        let start = std::time::Instant::now();
+        let res = Regex::new("a{1000000}{1000000}", 0, &text);
+        let invalid = apperr::Error::new_icu(IcuError::InvalidPattern.code());
+        assert!(matches!(res, Err(e) if e == invalid));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+        // Reasonably large patterns still compile.
+        assert!(Regex::new(r"\w{50}", 0, &text).is_ok());
+    }
+
+    #[test]
+    fn test_regex_tokens() {
+        let text = text_detached("ababxab");
+        let mut regex = Regex::new("ab", Regex::LITERAL, &text).unwrap();
+        assert_eq!(regex.tokens().collect::<Vec<_>>(), vec![0..2, 2..4]);
+        // The position stays at the gap.
+        assert_eq!(regex.tokens().next(), None);
+        assert_eq!(regex.next(), Some(5..7));
+        assert_eq!(regex.tokens().next(), None);
+
+        regex.reset(1);
+        assert_eq!(regex.tokens().next(), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_tokens_lexer() {
+        let text = text_detached("let x = 42 ?");
+        let mut regex = Regex::new(r"[a-z]+|\d+|=|\s+|x*", 0, &text).unwrap();
+        let tokens: Vec<_> = regex.tokens().map(|r| &text.content[r]).collect();
+        assert_eq!(tokens, ["let", " ", "x", " ", "=", " ", "42", " "]);
+        // `x*` matches the empty string before `?`, which mustn't loop forever.
+        assert_eq!(regex.next(), Some(11..11));
+    }
+
+    #[test]
+    fn test_regex_match_at() {
+        let text = text_detached("foo xfoo Foo");
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+
+        assert_eq!(regex.match_at(0), Some(0..3));
+        assert_eq!(regex.match_at(4), None);
+        assert_eq!(regex.match_at(5), Some(5..8));
+        assert_eq!(regex.match_at(9), Some(9..12));
+        assert_eq!(regex.match_at(12), None);
+        assert_eq!(regex.match_at(100), None);
+
+        // The search position is unaffected.
+        assert_eq!(regex.next(), Some(0..3));
+
+        // The text before the offset is taken into account.
+        let flags = Regex::LITERAL | Regex::WHOLE_WORD;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+        assert_eq!(regex.match_at(0), Some(0..3));
+        assert_eq!(regex.match_at(5), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_match_at_groups() {
+        let text = text_detached("a1 b22 ü");
+        let mut regex = Regex::new(r"(\w)(\d*)", 0, &text).unwrap();
+
+        assert_eq!(regex.match_at(3), Some(3..6));
+        assert_eq!(regex.group(2), Some(4..6));
+        assert_eq!(regex.match_at(4), Some(4..6));
+        assert_eq!(regex.match_at(2), None);
+        assert_eq!(regex.match_at(7), Some(7..9));
+        assert_eq!(regex.match_at(8), None);
+    }
+
+    #[test]
+    fn test_regex_current_match() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let mut tb = text_buffer("foo bar foo");
+        let mut text = Text::from_buffer(&tb);
+        let mut regex = Regex::new("foo", Regex::LITERAL, &text).unwrap();
+        assert_eq!(regex.current_match(), None);
+        assert_eq!(regex.current_captures(), None);
+
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.next(), Some(8..11));
+
+        // The current match survives repositioning, so that it can still be replaced.
+        regex.reset(0);
+        assert_eq!(regex.current_match(), Some(8..11));
+        assert_eq!(regex.current_captures(), Some(&[Some(8..11)][..]));
+        assert_eq!(regex.replace(&arena, "<$0>").as_str(), "<foo>");
+        assert_eq!(regex.next(), Some(0..3));
+
+        // ...but not new text.
+        drop(text);
+        tb.write_raw(b" foo");
+        text = Text::from_buffer(&tb);
+        regex.set_text(&mut text, 0);
+        assert_eq!(regex.current_match(), None);
+    }
+
+    #[test]
+    fn test_regex_remaining_matches() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("foo bar Foo baz foo");
+        let text = Text::from_buffer(&tb);
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+
+        assert_eq!(regex.remaining_matches(), 3);
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.remaining_matches(), 2);
+
+        // The current match and position are unaffected.
+        assert_eq!(regex.replace(&arena, "<$0>").as_str(), "<foo>");
+        assert_eq!(regex.next(), Some(8..11));
+        assert_eq!(regex.next(), Some(16..19));
+        assert_eq!(regex.remaining_matches(), 0);
+        assert_eq!(regex.count_matches(), 3);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_remaining_matches_empty() {
+        let text = text_detached("aé");
+        let mut regex = Regex::new("x*", 0, &text).unwrap();
+        assert_eq!(regex.remaining_matches(), 3);
+        assert_eq!(regex.next(), Some(0..0));
+        assert_eq!(regex.remaining_matches(), 2);
+        assert_eq!(regex.by_ref().count(), 2);
+        assert_eq!(regex.remaining_matches(), 0);
+    }
+
+    #[test]
+    fn test_regex_find_all() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("foo bar Foo baz foo");
+        let text = Text::from_buffer(&tb);
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.find_all(2), [0..3, 8..11]);
+        assert_eq!(regex.find_all(100), [0..3, 8..11, 16..19]);
+        assert!(regex.find_all(0).is_empty());
+
+        // The current match and position are unaffected.
+        assert_eq!(regex.replace(&arena, "<$0>").as_str(), "<foo>");
+        assert_eq!(regex.next(), Some(8..11));
+    }
+
+    #[test]
+    fn test_regex_append_text() {
+        let mut tb = text_buffer("xab");
+        let text = Text::from_buffer(&tb).detach();
+        let mut regex = Regex::new("ab", Regex::LITERAL, &text).unwrap();
+
+        assert_eq!(regex.next(), Some(1..3));
+        assert_eq!(regex.next(), None);
+        tb.write_raw(b"-ab");
+        let mut text = text.attach(&tb);
+        regex.append_text(&mut text);
+        assert_eq!(regex.next(), Some(4..6));
+        assert_eq!(regex.next(), None);
+
+        // The text is appended to in place, instead of the regex searching a copy.
+        assert_eq!(text.content.as_str(), "xab-ab");
+        assert!(Rc::ptr_eq(&text.content, &regex.text));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_append_text_boundary() {
+        let mut tb = text_buffer("aaa");
+        let text = Text::from_buffer(&tb).detach();
+        let mut regex = Regex::new("a+", 0, &text).unwrap();
+
+        // The match at the old end is reported again, now spanning both parts.
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.next(), None);
+        tb.write_raw(b"aa");
+        regex.append_text(&mut text.attach(&tb));
+        assert_eq!(regex.next(), Some(0..5));
+        assert_eq!(regex.next(), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_append_text_empty_match() {
+        let mut tb = text_buffer("ab");
+        let text = Text::from_buffer(&tb).detach();
+        let mut regex = Regex::new("x*", 0, &text).unwrap();
+
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..0, 1..1, 2..2]);
+        tb.write_raw("xéx".as_bytes());
+        regex.append_text(&mut text.attach(&tb));
+        assert_eq!(regex.collect::<Vec<_>>(), [2..3, 3..3, 5..6, 6..6]);
+    }
+
+    #[test]
+    fn test_regex_replace() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("foo bar Foo");
+        let text = Text::from_buffer(&tb);
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.replace(&arena, "<$0>").as_str(), "<foo>");
+        assert_eq!(
+            regex.replace_all(&arena, "[$0|$$0|$1|$]").as_str(),
+            "[foo|$0|$1|$] bar [Foo|$0|$1|$]"
+        );
+    }
+
+    #[test]
+    fn test_incremental_search() {
+        let text = text_detached("foo food fool foobar aaab");
+        let options = RegexOptions { literal: true, ..Default::default() };
+        // Pairs instead of ranges, because clippy frowns upon `[a..b]`.
+        let pairs = |s: &IncrementalSearch| -> Vec<_> {
+            s.matches().iter().map(|r| (r.start, r.end)).collect()
+        };
+        let mut search = IncrementalSearch::new("foo", &options, &text).unwrap();
+        assert_eq!(pairs(&search), [(0, 3), (4, 7), (9, 12), (14, 17)]);
+
+        search.refine("foob").unwrap();
+        assert_eq!(search.pattern(), "foob");
+        assert_eq!(pairs(&search), [(14, 18)]);
+        assert_eq!(search.regex().next(), Some(14..18));
+
+        // Not an extension of the current pattern.
+        search.refine("ood").unwrap();
+        assert_eq!(pairs(&search), [(5, 8)]);
+
+        // "aa" overlaps with itself, so "aab" needs a full search.
+        let mut search = IncrementalSearch::new("aa", &options, &text).unwrap();
+        assert_eq!(pairs(&search), [(21, 23)]);
+        search.refine("aab").unwrap();
+        assert_eq!(pairs(&search), [(22, 25)]);
+
+        // Filtering must give the same results as a full search.
+        let text = text_detached("abcab abcabcab ab");
+        let mut search = IncrementalSearch::new("ab", &options, &text).unwrap();
+        for pattern in ["abc", "abca", "abcab"] {
+            search.refine(pattern).unwrap();
+            let full = IncrementalSearch::new(pattern, &options, &text).unwrap();
+            assert_eq!(search.matches(), full.matches(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn test_regex_region_filter() {
+        let text = text_detached("foo // foo\nfoo");
+        let mut regex = Regex::new("foo", 0, &text).unwrap();
+        // Pretend that a tokenizer found a comment from `//` to the end of the line.
+        regex.set_region_filter(Box::new(|offset| !(4..10).contains(&offset)));
+
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 11..14]);
+        assert_eq!(regex.count_matches(), 2);
+        assert_eq!(regex.match_at(7), None);
+        assert_eq!(regex.match_at(11), Some(11..14));
+        regex.reset(usize::MAX);
+        assert_eq!(regex.prev(), Some(11..14));
+        assert_eq!(regex.prev(), Some(0..3));
+
+        regex.clear_region_filter();
+        assert_eq!(regex.count_matches(), 3);
+
+        // The same for the regex engine, as opposed to the plain substring search above.
+        #[cfg(feature = "regex")]
+        {
+            let mut regex = Regex::new("fo+", 0, &text).unwrap();
+            regex.set_region_filter(Box::new(|offset| !(4..10).contains(&offset)));
+            assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 11..14]);
+            assert_eq!(regex.count_matches(), 2);
+            regex.reset(usize::MAX);
+            assert_eq!(regex.prev(), Some(11..14));
+        }
+    }
+
+    #[test]
+    fn test_search_text() {
+        let hits = search_text("foo\nbar foo\r\nbaz\n", "foo", 0, 10).unwrap();
+        assert_eq!(
+            hits,
+            [
+                SearchHit { range: 0..3, line: 0, line_text: "foo".to_string() },
+                SearchHit { range: 8..11, line: 1, line_text: "bar foo".to_string() },
+            ]
+        );
+        assert_eq!(search_text("foo foo foo", "foo", 0, 2).unwrap().len(), 2);
+        let hits = search_text("a\n\nb a\na", "a", 0, 10).unwrap();
+        assert_eq!(hits.iter().map(|hit| hit.line).collect::<Vec<_>>(), [0, 2, 3]);
+        assert!(search_text("", "foo", 0, 10).unwrap().is_empty());
+
+        // Only the part of a long line around the match is kept.
+        let long = format!("{}foo{}", "ä".repeat(1000), "x".repeat(2000));
+        let hits = search_text(&long, "foo", 0, 10).unwrap();
+        let line_text = &hits[0].line_text;
+        assert!(line_text.len() <= SEARCH_HIT_LINE_MAX && line_text.len() > 1000);
+        assert!(line_text.contains("äfoox"));
+    }
+
+    #[test]
+    fn test_regex_replace_current() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let mut tb = text_buffer("foo bar foo");
+        let text = Text::from_buffer(&tb).detach();
+        let mut regex = Regex::new("foo", Regex::LITERAL, &text).unwrap();
+        assert!(regex.replace_current(&arena, "[$0]").is_none());
+
+        assert_eq!(regex.next(), Some(0..3));
+        let (range, replacement) = regex.replace_current(&arena, "[$0]").unwrap();
+        assert_eq!(range, 0..3);
+        assert_eq!(replacement.as_str(), "[foo]");
+
+        tb.cursor_move_to_offset(range.start);
+        tb.selection_update_offset(range.end);
+        tb.write_raw(replacement.as_bytes());
+        let mut text = text.attach(&tb);
+        regex.set_text(&mut text, range.start + replacement.len());
+        assert_eq!(text.content.as_str(), "[foo] bar foo");
+        assert_eq!(regex.next(), Some(10..13));
+        assert_eq!(regex.next(), None);
+    }
+
+    #[test]
+    fn test_regex_replace_case_transform() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("Foo bar");
+        let text = Text::from_buffer(&tb);
+        let mut regex = Regex::new("Foo", Regex::LITERAL, &text).unwrap();
+        let mut replace_all = |replacement| regex.replace_all(&arena, replacement).to_string();
+
+        assert_eq!(replace_all(r"\U$0\E!"), "FOO! bar");
+        assert_eq!(replace_all(r"\L$0 X\E X"), "foo x X bar");
+        assert_eq!(replace_all(r"\Lab\Ucd$0"), "abCDFOO bar");
+        assert_eq!(replace_all(r"\E$0\U"), "Foo bar");
+        assert_eq!(replace_all(r"\\U$0\\"), r"\UFoo\ bar");
+        assert_eq!(replace_all(r"C:\Temp\$0"), r"C:\Temp\Foo bar");
+    }
+
+    #[test]
+    fn test_regex_replace_all_preview() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("foo bar Foo");
+        let text = Text::from_buffer(&tb);
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+
+        assert_eq!(regex.next(), Some(0..3));
+        let (preview, count) = regex.replace_all_preview(&arena, "<$0>");
+        assert_eq!(preview.as_str(), "<foo> bar <Foo>");
+        assert_eq!(count, 2);
+
+        // The current match and position are unaffected.
+        assert_eq!(regex.replace(&arena, "[$0]").as_str(), "[foo]");
+        assert_eq!(regex.next(), Some(8..11));
+    }
+
+    #[test]
+    fn test_regex_preserve_case() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("color Color COLOR cOLOR");
+        let text = Text::from_buffer(&tb);
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("color", flags, &text).unwrap();
+
+        assert_eq!(regex.replace_all(&arena, "colour").as_str(), "colour colour colour colour");
+
+        regex.set_preserve_case(true);
+        assert_eq!(regex.replace_all(&arena, "colour").as_str(), "colour Colour COLOUR colour");
+        assert_eq!(regex.replace_all(&arena, "éa").as_str(), "éa Éa ÉA éa");
+
+        regex.reset(12);
+        assert_eq!(regex.next(), Some(12..17));
+        assert_eq!(regex.replace(&arena, "colour").as_str(), "COLOUR");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_replace_all_empty_matches() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("axbé");
+        let text = Text::from_buffer(&tb);
+        let mut regex = Regex::new("x*", 0, &text).unwrap();
+
+        let (preview, count) = regex.replace_all_preview(&arena, "-");
+        assert_eq!(preview.as_str(), "-a-b-é-");
+        assert_eq!(count, 4);
+        assert_eq!(regex.replace_all(&arena, "-").as_str(), "-a-b-é-");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_replace_groups() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let tb = text_buffer("2024-01 1999-12");
+        let text = Text::from_buffer(&tb);
+        let mut regex = Regex::new(r"(?P<year>\d+)-(\d+)(x)?", 0, &text).unwrap();
+
+        assert_eq!(
+            regex.replace_all(&arena, "$2/${year}$3${4}${nope}").as_str(),
+            "01/2024${4}${nope} 12/1999${4}${nope}"
+        );
+        assert_eq!(regex.replace_all(&arena, "${1}0").as_str(), "20240 19990");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_unmatched_groups() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let text = text_detached("ab a");
+        let mut regex = Regex::new(r"(?P<x>x)?a(b)?", 0, &text).unwrap();
+
+        assert_eq!(regex.next(), Some(0..2));
+        assert_eq!(regex.group_count(), 2);
+        assert_eq!(regex.group(1), None);
+        assert_eq!(regex.group_by_name("x"), None);
+        assert_eq!(regex.group(2), Some(1..2));
+
+        assert_eq!(regex.next(), Some(3..4));
+        assert_eq!(regex.group(0), Some(3..4));
+        assert_eq!(regex.group(2), None);
+        assert_eq!(regex.group(3), None);
+
+        // Groups that didn't participate expand to nothing, rather than to the text at offset 0.
+        assert_eq!(regex.replace(&arena, "[$1|$2|${x}]").as_str(), "[||]");
+    }
+
+    #[test]
+    fn test_regex_captures_iter() {
+        let text = text_detached("ab a");
+        #[cfg(feature = "regex")]
+        let (pattern, expected) = (
+            r"a(b)?",
+            [
+                Match { range: 0..2, groups: vec![Some(0..2), Some(1..2)] },
+                Match { range: 3..4, groups: vec![Some(3..4), None] },
+            ],
+        );
+        #[cfg(not(feature = "regex"))]
+        let (pattern, expected) = (
+            "a",
+            [
+                Match { range: 0..1, groups: vec![Some(0..1)] },
+                Match { range: 3..4, groups: vec![Some(3..4)] },
+            ],
+        );
+
+        let mut regex = Regex::new(pattern, 0, &text).unwrap();
+        assert_eq!(regex.captures_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(regex.next(), None);
+    }
+
+    #[test]
+    fn test_regex_named_groups() {
+        let tb = text_buffer("released 2024-01");
+        let text = Text::from_buffer(&tb);
+        let mut regex = Regex::new(r"(?P<year>\d{4})-(\d+)", 0, &text).unwrap();
+
+        if cfg!(feature = "regex") {
+            assert_eq!(regex.capture_names().collect::<Vec<_>>(), [None, Some("year"), None]);
+            assert_eq!(regex.next(), Some(9..16));
+            assert_eq!(regex.group_by_name("year"), Some(9..13));
+            assert_eq!(regex.group_by_name("month"), None);
+        } else {
+            assert_eq!(regex.capture_names().count(), 0);
+            assert_eq!(regex.group_by_name("year"), None);
+        }
+    }
+
+    #[test]
+    fn test_regex_whole_word() {
+        let tb = text_buffer("foo food _foo éfoo foo-a.b");
+        let text = Text::from_buffer(&tb);
+
+        let flags = Regex::LITERAL | Regex::WHOLE_WORD;
+        let mut regex = Regex::new("foo", flags, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 20..23]);
+
+        let mut regex = Regex::new("a.b", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(24..27));
+        assert_eq!(regex.next(), None);
+
+        let flags = flags | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("FOO", flags, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 20..23]);
+    }
+
+    #[test]
+    fn test_regex_whole_word_unicode() {
+        // Non-ASCII letters are word characters, too.
+        let tb = text_buffer("café caf écaf caf_ 日本caf Ωcaf");
+        let text = Text::from_buffer(&tb);
+
+        let flags = Regex::LITERAL | Regex::WHOLE_WORD;
+        let mut regex = Regex::new("caf", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(6..9));
+        assert_eq!(regex.next(), None);
+
+        // Matches at the start and end of the text have a non-word neighbor.
+        let mut regex = Regex::new("café", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..5));
+        assert_eq!(regex.next(), None);
+        let mut regex = Regex::new("Ωcaf", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(31..36));
+        assert_eq!(regex.next(), None);
+
+        let flags = flags | Regex::CASE_INSENSITIVE;
+        let mut regex = Regex::new("CAFÉ", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..5));
+        assert_eq!(regex.next(), None);
+    }
+
+    #[test]
+    fn test_regex_multiline_anchors() {
+        let text = text_detached("foo bar\nbar foo\n\nfoo");
+
+        let mut regex = Regex::new("^foo", Regex::MULTILINE, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 17..20]);
+        let mut regex = Regex::new("foo$", Regex::MULTILINE, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [12..15, 17..20]);
+        let mut regex = Regex::new("bar$", Regex::MULTILINE, &text).unwrap();
+        assert_eq!(regex.next(), Some(4..7));
+        assert_eq!(regex.next(), None);
+        let mut regex = Regex::new("^$", Regex::MULTILINE, &text).unwrap();
+        assert_eq!(regex.next(), Some(16..16));
+        assert_eq!(regex.next(), None);
+    }
+
+    #[test]
+    fn test_regex_search_range() {
+        let arena = Arena::new(64 * 1024).unwrap();
+        let text = text_detached("foo foo foo foo");
+
+        for flags in [Regex::LITERAL, Regex::LITERAL | Regex::CASE_INSENSITIVE] {
+            let mut regex = Regex::new("foo", flags, &text).unwrap();
+            regex.set_search_range(4..10);
+            assert_eq!(regex.count_matches(), 1);
+            assert_eq!(regex.next(), Some(4..7));
+            assert_eq!(regex.next(), None);
+
+            regex.reset(0);
+            assert_eq!(regex.next(), Some(4..7));
+            regex.reset(15);
+            assert_eq!(regex.prev(), Some(4..7));
+            assert_eq!(regex.prev(), None);
+
+            assert_eq!(regex.replace_all(&arena, "x").as_str(), "foo x foo foo");
+
+            regex.set_search_range(4..11);
+            assert_eq!(regex.by_ref().collect::<Vec<_>>(), [4..7, 8..11]);
+            regex.set_search_range(0..usize::MAX);
+            assert_eq!(regex.count_matches(), 4);
+        }
+    }
+
+    #[test]
+    fn test_regex_next_wrapping() {
+        let text = text_detached("foo bar foo");
+        let mut regex = Regex::new("foo", Regex::LITERAL, &text).unwrap();
+        regex.reset(4);
+        assert_eq!(regex.next_wrapping(), Some((8..11, false)));
+        assert_eq!(regex.next_wrapping(), Some((0..3, true)));
+        assert_eq!(regex.next_wrapping(), Some((8..11, false)));
+
+        let mut regex = Regex::new("xyz", Regex::LITERAL, &text).unwrap();
+        regex.reset(4);
+        assert_eq!(regex.next_wrapping(), None);
+        assert_eq!(regex.next_wrapping(), None);
+
+        let mut regex = Regex::new("bar", Regex::LITERAL, &text).unwrap();
+        assert_eq!(regex.next_wrapping(), Some((4..7, false)));
+        assert_eq!(regex.next_wrapping(), Some((4..7, true)));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_search_range_groups() {
+        let text = text_detached("foo fxo fyo");
+        let mut regex = Regex::new("f(.)o", 0, &text).unwrap();
+        regex.set_search_range(4..11);
+        assert_eq!(regex.next(), Some(4..7));
+        assert_eq!(regex.group(1), Some(5..6));
+    }
+
+    #[test]
+    fn test_regex_is_full_match() {
+        let text = text_detached("Foo");
+        let regex = Regex::new("Foo", Regex::LITERAL, &text).unwrap();
+        assert!(regex.is_full_match());
+        let regex = Regex::new("Fo", Regex::LITERAL, &text).unwrap();
+        assert!(!regex.is_full_match());
+        let regex = Regex::new("foo", Regex::LITERAL, &text).unwrap();
+        assert!(!regex.is_full_match());
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+        let regex = Regex::new("FOO", flags, &text).unwrap();
+        assert!(regex.is_full_match());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_is_full_match_alternation() {
+        // The leftmost match is "1", but "12" covers the entire text.
+        let text = text_detached("12");
+        let regex = Regex::new(r"1|12", 0, &text).unwrap();
+        assert!(regex.is_full_match());
+        let regex = Regex::new(r"\d", Regex::MULTILINE, &text).unwrap();
+        assert!(!regex.is_full_match());
+    }
+
+    #[test]
+    fn test_regex_options() {
+        let flags = Regex::CASE_INSENSITIVE | Regex::LITERAL | Regex::WHOLE_WORD;
+        let options = RegexOptions {
+            case_insensitive: true,
+            literal: true,
+            whole_word: true,
+            ..Default::default()
+        };
+        assert_eq!(RegexOptions::from_flags(flags), options);
+        assert_eq!(RegexOptions::from_flags(0), RegexOptions::default());
+
+        let tb = text_buffer("Foo food foo");
+        let text = Text::from_buffer(&tb);
+        let mut regex = Regex::with_options("foo", &options, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 9..12]);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_validate_pattern() {
+        assert_eq!(Regex::validate_pattern(r"a(b)\d+", 0), Ok(()));
+        assert_eq!(Regex::validate_pattern("a(b", Regex::LITERAL), Ok(()));
+        assert_eq!(Regex::validate_pattern("a(b", 0), Err((1, "unclosed group".to_string())));
+        assert_eq!(
+            Regex::validate_pattern("^(a", Regex::MULTILINE),
+            Err((1, "unclosed group".to_string()))
+        );
+        // The offset is in bytes, even though the regex crate reports it in chars.
+        assert_eq!(
+            Regex::validate_pattern(r"ü\p{Foo}", 0),
+            Err((2, "Unicode property not found".to_string()))
+        );
+        // No caret for multi-line patterns.
+        assert_eq!(Regex::validate_pattern("a\nb(", 0), Err((0, "unclosed group".to_string())));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_empty_matches_multibyte() {
+        let text = text_detached("é\n日本\nü");
+
+        let mut regex = Regex::new("^", Regex::MULTILINE, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..0, 3..3, 10..10]);
+        assert_eq!(regex.count_matches(), 3);
+        regex.reset(text.content.len());
+        assert_eq!(regex.prev(), Some(10..10));
+        assert_eq!(regex.prev(), Some(3..3));
+
+        let mut regex = Regex::new("", 0, &text).unwrap();
+        let matches = regex.by_ref().collect::<Vec<_>>();
+        assert_eq!(matches.len(), text.content.chars().count() + 1);
+        assert!(matches.iter().all(|m| text.content.is_char_boundary(m.start)));
+    }
+
+    /// A document split into chunks of `.1` bytes, to test reading and searching across chunk boundaries.
+    struct ChunkedDoc<'a>(&'a [u8], usize);
+
+    impl ReadableDocument for ChunkedDoc<'_> {
+        fn read_forward(&self, off: usize) -> &[u8] {
+            let off = off.min(self.0.len());
+            let end = (off / self.1 + 1) * self.1;
+            &self.0[off..end.min(self.0.len())]
+        }
+
+        fn read_backward(&self, off: usize) -> &[u8] {
+            let off = off.min(self.0.len());
+            let beg = off.saturating_sub(1) / self.1 * self.1;
+            &self.0[beg..off]
+        }
+    }
+
+    #[test]
+    fn test_text_read_document_split_chars() {
+        let read = |bytes: &[u8], chunk_size| {
+            let mut content = String::new();
+            Text::read_document(&mut content, &ChunkedDoc(bytes, chunk_size), &mut Vec::new());
+            content
+        };
+
+        // The chunk boundaries fall inside the 3-byte characters.
+        let text = "a日本語b€";
+        for chunk_size in 1..=text.len() {
+            assert_eq!(read(text.as_bytes(), chunk_size), text, "chunks of {chunk_size}");
+        }
+
+        // Invalid and truncated sequences are still replaced, no matter where the chunks end.
+        for chunk_size in 1..=6 {
+            assert_eq!(read(b"a\xE6\x97b\xFFc\xE6", chunk_size), "a\u{FFFD}b\u{FFFD}c\u{FFFD}");
+        }
+
+        // The replacements are recorded with their offsets in the document.
+        let span = |new, old| ChangedSpan { new, old };
+        for chunk_size in 1..=6 {
+            let mut replacements = Vec::new();
+            let doc = ChunkedDoc(b"a\xE6\x97b\xFFc\xE6", chunk_size);
+            Text::read_document(&mut String::new(), &doc, &mut replacements);
+            assert_eq!(replacements, [span(1..4, 1..3), span(5..8, 4..5), span(9..12, 6..7)]);
+        }
+
+        // A truncated sequence is followed by a complete one, split across the chunks.
+        let bytes = b"a\xF0\x90\x80\xF0\x9F\x98\x80b\xE6\x97\xE6\x97\xA5";
+        for chunk_size in 1..=bytes.len() {
+            let mut replacements = Vec::new();
+            let mut content = String::new();
+            Text::read_document(&mut content, &ChunkedDoc(bytes, chunk_size), &mut replacements);
+            assert_eq!(content, String::from_utf8_lossy(bytes), "chunks of {chunk_size}");
+            assert_eq!(replacements, [span(9..12, 9..11)], "chunks of {chunk_size}");
+        }
+    }
+
+    #[test]
+    fn test_text_content_to_source() {
+        let mut tb = TextBuffer::new(true).unwrap();
+        tb.write_raw(b"a\xFFb\xE2\x82c");
+        let text = Text::from_buffer(&tb);
+        assert_eq!(*text.content, "a\u{FFFD}b\u{FFFD}c");
+
+        assert_eq!(text.content_to_source(0), 0);
+        assert_eq!(text.content_to_source(2), 1);
+        assert_eq!(text.content_to_source(4), 2);
+        assert_eq!(text.content_to_source(8), 5);
+        assert_eq!(text.content_to_source(9), 6);
+        assert_eq!(text.original_range(5..9), 3..6);
+
+        // Valid UTF-8 maps to itself, and so do replacements of the same length.
+        let mut tb = TextBuffer::new(true).unwrap();
+        tb.write_raw(b"a\xF0\x90\x80b");
+        let text = Text::from_buffer(&tb);
+        assert_eq!(text.content_to_source(4), 4);
+        assert_eq!(text_detached("añb").content_to_source(3), 3);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_chunked_regex() {
+        let mut content = String::new();
+        for i in 0..5000 {
+            content.push_str(&format!("line {i} foo ünïcödé foobar\n"));
+        }
+        let text = text_detached(&content);
+
+        let patterns = [
+            "foo",
+            r"\bfoo\b",
+            "^line 99",
+            "bar$",
+            r"foobar\nline \d+",
+            r"99\d foo[\s\S]*?line 10",
+            "^",
+        ];
+        for pattern in patterns {
+            let options = RegexOptions { multi_line: true, ..Default::default() };
+            let mut regex = Regex::with_options(pattern, &options, &text).unwrap();
+            let expected: Vec<_> = regex.by_ref().collect();
+
+            for chunk_size in [1000, 4096, usize::MAX] {
+                let doc = ChunkedDoc(content.as_bytes(), chunk_size);
+                let mut chunked = ChunkedRegex::with_options(pattern, &options).unwrap();
+                let actual: Vec<_> = std::iter::from_fn(|| chunked.next(&doc)).collect();
+                assert_eq!(actual, expected, "{pattern} in chunks of {chunk_size}");
+            }
+        }
+
+        // Resetting to the middle of a line still sees the preceding text.
+        let doc = ChunkedDoc(content.as_bytes(), 1000);
+        let mut chunked = ChunkedRegex::with_options(r"\bfoo", &RegexOptions::default()).unwrap();
+        chunked.reset(content.len() - 6);
+        assert_eq!(chunked.next(&doc), None);
+        chunked.reset(content.len() - 10);
+        assert_eq!(chunked.next(&doc), Some(content.len() - 7..content.len() - 4));
+        chunked.reset(content.len() + 10);
+        assert_eq!(chunked.next(&doc), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_search_stream() {
+        let content = "foo bar\n".repeat(20000);
+        let doc = ChunkedDoc(content.as_bytes(), 1000);
+
+        // Matches in the overlap of two windows are only reported once.
+        let mut matches = Vec::new();
+        search_stream(&doc, "^foo", Regex::MULTILINE, |range| matches.push(range)).unwrap();
+        assert_eq!(matches.len(), 20000);
+        assert!(matches.windows(2).all(|w| w[1].start == w[0].start + 8));
+
+        let mut count = 0;
+        search_stream(&doc, r"bar\nfoo", 0, |_| count += 1).unwrap();
+        assert_eq!(count, 19999);
+
+        assert!(search_stream(&doc, "(", 0, |_| {}).is_err());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_dot_all() {
+        let text = text_detached("<a\nb>\n<c>");
+
+        let mut regex = Regex::new("<.*?>", 0, &text).unwrap();
+        assert_eq!(regex.next(), Some(6..9));
+        assert_eq!(regex.next(), None);
+
+        let mut regex = Regex::new("<.*?>", Regex::DOT_ALL, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..5, 6..9]);
+
+        let flags = Regex::DOT_ALL | Regex::MULTILINE;
+        let mut regex = Regex::new("^<.*>$", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..9));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_crlf() {
+        let text = text_detached("ab\r\ncd\r\n");
+
+        let mut regex = Regex::new("^(.*)$", Regex::MULTILINE, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.group(1), Some(0..3));
+
+        // With CRLF the `\r` is part of the line break, but the offsets still refer to the text.
+        let flags = Regex::MULTILINE | Regex::CRLF;
+        let mut regex = Regex::new("^(.*)$", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..2));
+        assert_eq!(regex.group(1), Some(0..2));
+        assert_eq!(regex.next(), Some(4..6));
+        assert_eq!(regex.next(), Some(8..8));
+        assert_eq!(regex.next(), None);
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn test_regex_lite_multiline_literal() {
+        let tb = text_buffer("^foo$ foo");
+        let text = Text::from_buffer(&tb);
+
+        // Anchors are only recognized without LITERAL.
+        let flags = Regex::MULTILINE | Regex::LITERAL;
+        let mut regex = Regex::new("^foo$", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..5));
+        assert_eq!(regex.next(), None);
+        let mut regex = Regex::new("^foo", 0, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..4));
+        assert_eq!(regex.next(), None);
+
+        // `$` also matches before a CRLF.
+        let text = text_detached("foo\r\nfoo");
+        let mut regex = Regex::new("foo$", Regex::MULTILINE, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [0..3, 5..8]);
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn test_regex_lite_case_insensitive() {
+        let tb = text_buffer("İx ix Straße STRASSE");
+        let text = Text::from_buffer(&tb);
+        let flags = Regex::LITERAL | Regex::CASE_INSENSITIVE;
+
+        // "İ" lowercases to "i̇", which is longer and mustn't be split.
+        let mut regex = Regex::new("İX", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.next(), None);
+        let mut regex = Regex::new("ix", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(4..6));
+        assert_eq!(regex.next(), None);
+        let mut regex = Regex::new("i", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(4..5));
+        assert_eq!(regex.next(), None);
+
+        let mut regex = Regex::new("STRAßE", flags, &text).unwrap();
+        assert_eq!(regex.next(), Some(7..14));
+        assert_eq!(regex.next(), None);
+        let mut regex = Regex::new("e", flags, &text).unwrap();
+        assert_eq!(regex.by_ref().collect::<Vec<_>>(), [13..14, 21..22]);
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn test_regex_lite_multi() {
+        let text = text_detached("fn main() {\n    let x = if y { 1 } else { 2 };\n}");
+        let flags = Regex::WHOLE_WORD;
+        let mut regex = Regex::new_multi(&["let", "if", "else", "fn"], flags, &text).unwrap();
+
+        let mut matches = Vec::new();
+        while let Some(range) = regex.next() {
+            matches.push((range, regex.pattern_index()));
+        }
+        assert_eq!(matches, [(0..2, 3), (16..19, 0), (24..26, 1), (35..39, 2)]);
+
+        // The next match of each pattern is cached, which must not survive moving backwards.
+        regex.reset(20);
+        assert_eq!(regex.next(), Some(24..26));
+        regex.reset(17);
+        assert_eq!(regex.next(), Some(24..26));
+        regex.reset(0);
+        assert_eq!(regex.next(), Some(0..2));
+        assert_eq!(regex.next(), Some(16..19));
+
+        // Matches at the same offset prefer the earlier pattern.
+        let text = text_detached("foobar");
+        let mut regex = Regex::new_multi(&["bar", "foo", "foobar"], 0, &text).unwrap();
+        assert_eq!(regex.next(), Some(0..3));
+        assert_eq!(regex.pattern_index(), 1);
+        assert_eq!(regex.next(), Some(3..6));
+        assert_eq!(regex.pattern_index(), 0);
+        assert_eq!(regex.prev(), Some(0..3));
+        assert_eq!(regex.pattern_index(), 1);
+        assert_eq!(regex.match_at(0), Some(0..3));
+        assert!(regex.is_full_match());
+        assert_eq!(regex.count_matches(), 2);
+
+        let mut regex = Regex::new_multi(&[], 0, &text).unwrap();
+        assert_eq!(regex.next(), None);
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn test_regex_lite_multi_whole_word() {
+        let text = text_detached("cat category dogma dog cat_x catcat cat");
+        let mut regex = Regex::new_multi(&["cat", "dog"], 0, &text).unwrap();
+        regex.set_whole_word(0, true);
+
+        let mut matches = Vec::new();
+        while let Some(range) = regex.next() {
+            matches.push((range, regex.pattern_index()));
+        }
+        assert_eq!(matches, [(0..3, 0), (13..16, 1), (19..22, 1), (36..39, 0)]);
+        assert_eq!(regex.prev(), Some(36..39));
+        assert_eq!(regex.prev(), Some(19..22));
+        assert_eq!(regex.match_at(4), None);
+        assert_eq!(regex.match_at(13), Some(13..16));
+
+        // Changing the options drops the cached matches.
+        regex.reset(0);
+        assert_eq!(regex.next(), Some(0..3));
+        regex.set_whole_word(0, false);
+        assert_eq!(regex.next(), Some(4..7));
+
+        // And the other way around.
+        let mut regex = Regex::new_multi(&["cat", "dog"], Regex::WHOLE_WORD, &text).unwrap();
+        regex.set_whole_word(0, false);
+        assert_eq!(regex.count_matches(), 7);
+        assert_eq!(regex.find_all(10)[..3], [0..3, 4..7, 19..22]);
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn test_regex_lite_literal_index() {
+        let text = text_detached("größe groß grün gr\ngroß\n");
+        let index = Rc::new(build_literal_index(&text).unwrap());
+        let matches = |regex: &mut Regex| regex.by_ref().collect::<Vec<_>>();
+
+        // The index must not change the results, including the filters and search ranges.
+        for flags in [0, Regex::WHOLE_WORD] {
+            let mut plain = Regex::new("groß", flags, &text).unwrap();
+            let mut indexed = Regex::new("groß", flags, &text).unwrap();
+            indexed.set_literal_index(Rc::clone(&index));
+            assert_eq!(matches(&mut indexed), matches(&mut plain));
+        }
+        let mut regex = Regex::new("gr", 0, &text).unwrap();
+        regex.set_literal_index(Rc::clone(&index));
+        regex.set_search_range(1..18);
+        assert_eq!(matches(&mut regex), [8..10, 14..16]);
+        let mut regex = Regex::new("groß$", Regex::MULTILINE, &text).unwrap();
+        regex.set_literal_index(Rc::clone(&index));
+        assert_eq!(regex.next(), Some(23..28));
+        assert_eq!(regex.next(), None);
+
+        let mut regex = Regex::new_multi(&["grün", "ß"], 0, &text).unwrap();
+        regex.set_literal_index(Rc::clone(&index));
+        assert_eq!(matches(&mut regex), [4..6, 11..13, 14..19, 26..28]);
+
+        // An index of another text is ignored.
+        let other = text_detached("größe groß grün gr\ngroß\nß");
+        regex.set_literal_index(Rc::new(build_literal_index(&other).unwrap()));
+        assert_eq!(regex.count_matches(), 4);
+
+        // Large texts aren't indexed.
+        let large = text_detached(&" ".repeat(LITERAL_INDEX_MAX_LEN + 1));
+        assert!(build_literal_index(&large).is_none());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_prev_captures() {
+        let tb = text_buffer("bar baz");
+        let text = Text::from_buffer(&tb);
+        let mut regex = Regex::new("ba(r|z)", 0, &text).unwrap();
+        regex.reset(7);
+        assert_eq!(regex.prev(), Some(4..7));
+        assert_eq!(regex.group(1), Some(6..7));
+        assert_eq!(regex.prev(), Some(0..3));
+        assert_eq!(regex.group(1), Some(2..3));
+
+        // Empty matches must not get stuck at the current position.
+        let mut regex = Regex::new("", 0, &text).unwrap();
+        regex.reset(2);
+        assert_eq!(regex.prev(), Some(1..1));
+        assert_eq!(regex.prev(), Some(0..0));
+        assert_eq!(regex.prev(), None);
+    }
+
+    #[test]
+    fn test_text_refresh_range() {
+        let mut tb = text_buffer("hello wörld");
+        let mut text = Text::from_buffer(&tb).detach();
+
+        // The buffer is modified in between, so the `Text` only borrows it for the refresh.
+        fn refresh(text: Text<'static>, tb: &TextBuffer, range: Range<usize>) -> Text<'static> {
+            let mut text = text.attach(tb);
+            text.refresh_range(range);
+            text.detach()
+        }
+
+        // Insertion
+        tb.cursor_move_to_offset(6);
+        tb.write_raw("big ".as_bytes());
+        text = refresh(text, &tb, 6..6);
+        assert_eq!(text.content.as_str(), "hello big wörld");
+
+        // Replacement with a range that isn't on a char boundary.
+        tb.cursor_move_to_offset(11);
+        tb.selection_update_offset(13);
+        tb.write_raw(b"o");
+        text = refresh(text, &tb, 12..13);
+        assert_eq!(text.content.as_str(), "hello big world");
+
+        // Invalid UTF-8 shifts the offsets and results in a full refresh.
+        tb.write_raw(b"\xFF");
+        text = refresh(text, &tb, 12..12);
+        assert_eq!(text.content.as_str(), "hello big wo\u{FFFD}rld");
+        tb.write_raw(b"!");
+        text = refresh(text, &tb, 15..15);
+        assert_eq!(text.content.as_str(), "hello big wo\u{FFFD}!rld");
+    }
+
+    #[test]
+    fn test_text_lines() {
+        let text = text_detached("a\r\nbb\n\nc\r");
+        assert_eq!(text.lines().collect::<Vec<_>>(), [(0, "a"), (3, "bb"), (6, ""), (7, "c\r")]);
+
+        let text = text_detached("x\n");
+        assert_eq!(text.lines().collect::<Vec<_>>(), [(0, "x")]);
+
+        let text = text_detached("");
+        assert_eq!(text.lines().count(), 0);
+    }
+
+    #[test]
+    fn test_text_offset_to_line_col() {
+        let text = text_detached("ab\n\tö😀x\r\n\nend\n");
+        let cases = [
+            (0, (0, 0)),
+            (2, (0, 2)),
+            (3, (1, 0)),
+            (4, (1, 1)),
+            (6, (1, 2)),
+            (7, (1, 2)),
+            (10, (1, 3)),
+            (11, (1, 4)),
+            (13, (2, 0)),
+            (16, (3, 2)),
+            // The trailing newline terminates the last line.
+            (18, (3, 3)),
+            (100, (3, 3)),
+        ];
+        for (offset, expected) in cases {
+            assert_eq!(text.offset_to_line_col(offset), expected, "offset {offset}");
+        }
+
+        let text = text_detached("");
+        assert_eq!(text.offset_to_line_col(5), (0, 0));
+    }
+
+    #[test]
+    fn test_text_final_newline() {
+        let mut text = text_detached("ab\r\n");
+        assert_eq!(text.lines().collect::<Vec<_>>(), [(0, "ab")]);
+        assert_eq!(text.line_count(), 1);
+        assert_eq!(text.offset_to_line_col(4), (0, 2));
+        assert_eq!(text.offset_to_line_col(2), (0, 2));
+
+        text.set_final_newline(FinalNewline::StartsLine);
+        assert_eq!(text.lines().collect::<Vec<_>>(), [(0, "ab"), (4, "")]);
+        assert_eq!(text.line_count(), 2);
+        assert_eq!(text.offset_to_line_col(4), (1, 0));
+        assert_eq!(text.offset_to_line_col(2), (0, 2));
+
+        for s in ["", "a", "a\nb", "a\n\n", "\n"] {
+            let mut text = text_detached(s);
+            for mode in [FinalNewline::Terminates, FinalNewline::StartsLine] {
+                text.set_final_newline(mode);
+                let lines: Vec<_> = text.lines().collect();
+                assert_eq!(text.line_count(), lines.len(), "{s:?} {mode:?}");
+                // The end of the text is on the last line, if there is one.
+                let (line, _) = text.offset_to_line_col(s.len());
+                assert_eq!(line, lines.len().saturating_sub(1), "{s:?} {mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_text_offset_to_grapheme_col() {
+        let text = text_detached("e\u{301}x\n👍🏽🇩🇪!");
+        let cases = [
+            (0, (0, 0)),
+            (1, (0, 1)),
+            (3, (0, 1)),
+            (4, (0, 2)),
+            (5, (1, 0)),
+            (9, (1, 1)),
+            (13, (1, 1)),
+            (21, (1, 2)),
+            (22, (1, 3)),
+            (100, (1, 3)),
+        ];
+        for (offset, expected) in cases {
+            assert_eq!(text.offset_to_grapheme_col(offset), expected, "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn test_text_word_at() {
+        let text = text_detached("let größe_2 = a.b;");
+        let cases = [
+            (0, Some(0..3)),
+            (2, Some(0..3)),
+            (3, None),
+            (4, Some(4..13)),
+            (6, Some(4..13)),
+            (7, None),
+            (12, Some(4..13)),
+            (13, None),
+            (16, Some(16..17)),
+            (17, None),
+            (18, Some(18..19)),
+            (19, None),
+            (100, None),
+        ];
+        for (offset, expected) in cases {
+            assert_eq!(text.word_at(offset), expected, "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn test_encoding_name_aliases() {
+        let input = "\u{FEFF}a€".as_bytes();
+        for (source, target, expected) in [
+            ("utf8", "UTF_16le", "\u{FEFF}a€".encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            ("utf-8 bom", "Windows1252", b"a\x80".to_vec()),
+            ("UTF8_BOM", "utf-8-bom", input.to_vec()),
+        ] {
+            assert_eq!(
+                convert_chunked(source, target, input, 1024),
+                expected,
+                "{source} -> {target}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_unsupported_encoding() {
+        let mut pivot = [MaybeUninit::uninit(); 64];
+        assert_eq!(
+            Converter::new(&mut pivot, "UTF-8", "EBCDIC").err(),
+            Some(apperr::Error::new_icu(IcuError::UnsupportedEncoding.code()))
+        );
+    }
+
+    #[test]
+    fn test_apperr_format() {
+        struct Code(u32);
+        impl std::fmt::Display for Code {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                apperr_format(f, self.0)
+            }
+        }
+
+        assert_eq!(Code(IcuError::InvalidPattern.code()).to_string(), "Invalid search pattern");
+        assert_eq!(
+            Code(IcuError::UnsupportedEncoding.code()).to_string(),
+            "Unsupported encoding conversion"
+        );
+        assert_eq!(Code(IcuError::UnknownProperty.code()).to_string(), "Unknown Unicode property");
+        assert_eq!(Code(0x10300).to_string(), "ICU Error 0x010300");
+    }
+}
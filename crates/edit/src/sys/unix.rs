@@ -366,13 +366,6 @@ pub fn file_id(file: Option<&File>, path: &Path) -> apperr::Result<FileId> {
     }
 }
 
-unsafe fn load_library(name: *const c_char) -> apperr::Result<NonNull<c_void>> {
-    unsafe {
-        NonNull::new(libc::dlopen(name, libc::RTLD_LAZY))
-            .ok_or_else(|| errno_to_apperr(libc::ENOENT))
-    }
-}
-
 /// Loads a function from a dynamic library.
 ///
 /// # Safety
@@ -1207,7 +1207,7 @@ impl Tui {
                 match &node.content {
                     NodeContent::Text(content) => {
                         result.push_repeat(' ', depth * 2);
-                        _ = write!(result, "  text:         \"{}\"\r\n", &content.text);
+                        _ = write!(result, "  text:         \"{}\"\r\n", content.text);
                     }
                     NodeContent::Textarea(content) => {
                         let tb = content.buffer.borrow();
@@ -0,0 +1,567 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Generates the tables for the edit crate's `icu` module via `--icu-tables`:
+//! The legacy multi-byte encodings from glibc's charmaps, the ASCII transliterations
+//! from glibc's locales and the NFC and case folding data from the UCD.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::io::Write as IoWrite;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, bail};
+
+use crate::extract_range;
+
+/// Maps byte sequences to code points.
+type Charmap = HashMap<Vec<u8>, u32>;
+/// Maps code points to their transliteration alternatives, in order of preference.
+type Translits = HashMap<u32, Vec<Vec<u32>>>;
+
+pub fn main(mut args: pico_args::Arguments, localedata: &Path) -> anyhow::Result<()> {
+    let arg_input = args.free_from_os_str(|s| -> Result<PathBuf, &'static str> { Ok(s.into()) })?;
+    let arg_remaining = args.finish();
+    if !arg_remaining.is_empty() {
+        bail!("unrecognized arguments: {:?}", arg_remaining);
+    }
+
+    let input = std::fs::read_to_string(arg_input)?;
+    let doc = roxmltree::Document::parse(&input)?;
+    let charmaps = localedata.join("charmaps");
+    let date = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let mut buf = String::new();
+
+    generate_gb18030(&mut buf, &date, &read_charmap(&charmaps.join("GB18030"), true)?)?;
+    buf.push('\n');
+    generate_shift_jis(&mut buf, &date, &read_charmap(&charmaps.join("WINDOWS-31J"), false)?)?;
+    buf.push('\n');
+    generate_euc_kr(&mut buf, &date, &read_charmap(&charmaps.join("CP949"), false)?)?;
+    buf.push('\n');
+    generate_transliterations(&mut buf, &date, &localedata.join("locales"))?;
+    buf.push('\n');
+    generate_normalization(&mut buf, &date, &doc)?;
+    buf.push('\n');
+    generate_case_folding(&mut buf, &date, &doc)?;
+
+    std::io::stdout().write_all(buf.as_bytes())?;
+    Ok(())
+}
+
+/// Parses an uncompressed glibc charmap, as found in `localedata/charmaps` of its source tree.
+///
+/// If `private_use` is set, the commented out `<Private Use>` mappings take precedence.
+/// glibc's GB18030 charmap comments out the GB18030-2005 mappings to the PUA in favor of
+/// the GB18030-2022 ones, some of which are outside the BMP.
+fn read_charmap(path: &Path, private_use: bool) -> anyhow::Result<Charmap> {
+    let input =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    // The comments in the charmaps aren't necessarily valid UTF-8, but the mappings are ASCII.
+    let input = String::from_utf8_lossy(&input);
+    let mut map = Charmap::new();
+    let mut overrides = Vec::new();
+
+    for line in input.lines() {
+        if let Some(line) = line.strip_prefix("% ") {
+            if private_use
+                && line.trim_end().ends_with("<Private Use>")
+                && let Some((bytes, cp)) = parse_charmap_line(line)
+            {
+                overrides.push((bytes, cp));
+            }
+            continue;
+        }
+
+        // Irreversible mappings are only used for decoding, which is exactly what we need.
+        let line = line.strip_prefix("%IRREVERSIBLE%").unwrap_or(line);
+        if let Some((bytes, cp)) = parse_charmap_line(line) {
+            map.entry(bytes).or_insert(cp);
+        }
+    }
+
+    if map.is_empty() {
+        bail!("no mappings found in {}", path.display());
+    }
+
+    map.extend(overrides);
+    Ok(map)
+}
+
+/// Parses a line of the form `<U4E02>     /x81/x40         <CJK>`.
+/// Ranges of the form `<U00020000>..<U00020003> /x95/x32/x82/x36` are skipped,
+/// because they only occur outside the BMP, which is handled algorithmically.
+fn parse_charmap_line(line: &str) -> Option<(Vec<u8>, u32)> {
+    let (cp, rest) = line.strip_prefix("<U")?.split_once('>')?;
+    let cp = u32::from_str_radix(cp, 16).ok()?;
+    let seq = rest.split_whitespace().next()?.strip_prefix("/x")?;
+    let bytes = seq.split("/x").map(|b| u8::from_str_radix(b, 16).ok()).collect::<Option<_>>()?;
+    Some((bytes, cp))
+}
+
+fn lookup_u16(map: &Charmap, bytes: &[u8]) -> anyhow::Result<u16> {
+    match map.get(bytes) {
+        Some(&cp) => u16::try_from(cp)
+            .with_context(|| format!("U+{cp:04X} for {bytes:02x?} is outside the BMP")),
+        None => Ok(0),
+    }
+}
+
+fn generate_gb18030(buf: &mut String, date: &str, map: &Charmap) -> anyhow::Result<()> {
+    let mut two_byte = Vec::with_capacity(126 * 190);
+    for lead in 0x81..=0xfe {
+        for trail in (0x40..=0x7e).chain(0x80..=0xfe) {
+            let cp = lookup_u16(map, &[lead, trail])?;
+            if cp == 0 {
+                bail!("GB18030 code {lead:02x}{trail:02x} is unmapped");
+            }
+            two_byte.push(cp);
+        }
+    }
+
+    // The BMP part of the four-byte codes maps linearly to code points in runs.
+    // Whenever the distance between pointer and code point changes, a new run starts.
+    let mut four_byte: Vec<(u32, u32)> = map
+        .iter()
+        .filter(|&(bytes, &cp)| bytes.len() == 4 && cp <= 0xffff)
+        .map(|(b, &cp)| {
+            let b = b.iter().map(|&b| b as u32).collect::<Vec<_>>();
+            let pointer =
+                (((b[0] - 0x81) * 10 + (b[1] - 0x30)) * 126 + (b[2] - 0x81)) * 10 + (b[3] - 0x30);
+            (pointer, cp)
+        })
+        .collect();
+    four_byte.sort_unstable();
+
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for (pointer, cp) in four_byte {
+        if ranges.last().is_none_or(|&(p, c)| cp - pointer != c - p) {
+            ranges.push((pointer, cp));
+        }
+    }
+
+    _ = writeln!(
+        buf,
+        "// BEGIN: Generated by grapheme-table-gen on {date}, from glibc's GB18030 charmap including its GB18030-2005 private use mappings, with --icu-tables"
+    );
+    write_table(
+        buf,
+        "\
+/// Maps GB18030 two-byte codes to Unicode, indexed by `(lead - 0x81) * 190 + (trail - offset)`,
+/// where `offset` is 0x40 for trail bytes below 0x7F and 0x41 otherwise.
+",
+        "GB18030_TWO_BYTE",
+        "u16",
+        &two_byte,
+        16,
+        |buf, cp| _ = write!(buf, "0x{cp:04x}"),
+    );
+    buf.push('\n');
+    write_table(
+        buf,
+        "\
+/// The ranges of GB18030 four-byte codes that map linearly to the BMP, as `(pointer, code point)`.
+",
+        "GB18030_RANGES",
+        "(u32, u16)",
+        &ranges,
+        6,
+        |buf, (pointer, cp)| _ = write!(buf, "({pointer}, 0x{cp:04x})"),
+    );
+    buf.push_str("// END: GB18030\n");
+    Ok(())
+}
+
+fn generate_shift_jis(buf: &mut String, date: &str, map: &Charmap) -> anyhow::Result<()> {
+    // The JIS X 0208 mappings instead of the Microsoft ones, just like WHATWG.
+    const OVERRIDES: [(u16, u16); 3] = [(0x8160, 0x301c), (0x8161, 0x2016), (0x817c, 0x2212)];
+
+    let mut two_byte = Vec::with_capacity(60 * 188);
+    for lead in (0x81..=0x9f).chain(0xe0..=0xfc) {
+        for trail in (0x40..=0x7e).chain(0x80..=0xfc) {
+            let code = u16::from_be_bytes([lead, trail]);
+            two_byte.push(match OVERRIDES.iter().find(|&&(c, _)| c == code) {
+                Some(&(_, cp)) => cp,
+                None => lookup_u16(map, &[lead, trail])?,
+            });
+        }
+    }
+
+    _ = writeln!(
+        buf,
+        "// BEGIN: Generated by grapheme-table-gen on {date}, from glibc's WINDOWS-31J charmap with the JIS X 0208 mappings for 0x8160, 0x8161 and 0x817C, with --icu-tables"
+    );
+    write_table(
+        buf,
+        "\
+/// Maps Shift-JIS two-byte codes to Unicode, indexed by `(lead - lead_offset) * 188 + (trail - offset)`,
+/// where `lead_offset` is 0x81 for lead bytes below 0xA0 and 0xC1 otherwise, and `offset` is
+/// 0x40 for trail bytes below 0x7F and 0x41 otherwise. Unmapped codes are 0.
+",
+        "SHIFT_JIS_TWO_BYTE",
+        "u16",
+        &two_byte,
+        16,
+        |buf, cp| _ = write!(buf, "0x{cp:04x}"),
+    );
+    buf.push_str("// END: Shift-JIS\n");
+    Ok(())
+}
+
+fn generate_euc_kr(buf: &mut String, date: &str, map: &Charmap) -> anyhow::Result<()> {
+    let mut two_byte = Vec::with_capacity(126 * 190);
+    for lead in 0x81..=0xfe {
+        for trail in 0x41..=0xfe {
+            two_byte.push(lookup_u16(map, &[lead, trail])?);
+        }
+    }
+
+    _ = writeln!(
+        buf,
+        "// BEGIN: Generated by grapheme-table-gen on {date}, from glibc's CP949 charmap (KS X 1001 plus the UHC extensions), with --icu-tables"
+    );
+    write_table(
+        buf,
+        "\
+/// Maps EUC-KR two-byte codes to Unicode, indexed by `(lead - 0x81) * 190 + (trail - 0x41)`.
+/// Unmapped codes are 0.
+",
+        "EUC_KR_TWO_BYTE",
+        "u16",
+        &two_byte,
+        16,
+        |buf, cp| _ = write!(buf, "0x{cp:04x}"),
+    );
+    buf.push_str("// END: EUC-KR\n");
+    Ok(())
+}
+
+fn generate_transliterations(buf: &mut String, date: &str, locales: &Path) -> anyhow::Result<()> {
+    // In order of precedence. `translit_neutral` includes `translit_compat` itself, after its own.
+    const FILES: [&str; 3] = ["translit_combining", "translit_neutral", "translit_compat"];
+
+    let mut alternatives = Translits::new();
+    for name in FILES {
+        let path = locales.join(name);
+        let input = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        for line in input.lines() {
+            if let Some((cp, alts)) = parse_translit_line(line) {
+                alternatives.entry(cp).or_insert(alts);
+            }
+        }
+    }
+
+    if alternatives.is_empty() {
+        bail!("no transliterations found in {}", locales.display());
+    }
+
+    let mut transliterations: Vec<(u32, u8)> = alternatives
+        .keys()
+        .filter(|&&cp| cp >= 0x80)
+        .filter_map(|&cp| Some((cp, resolve_translit(&alternatives, cp, 0)?)))
+        .collect();
+    transliterations.sort_unstable();
+
+    _ = writeln!(
+        buf,
+        "// BEGIN: Generated by grapheme-table-gen on {date}, from glibc's {} locales, with --icu-tables",
+        FILES.join(", ")
+    );
+    write_table(
+        buf,
+        "\
+/// The chars that glibc transliterates into a single printable ASCII char, sorted by char.
+/// Characters that would turn into several ASCII ones, like \"æ\" or \"…\", are omitted.
+",
+        "TRANSLITERATIONS",
+        "(char, u8)",
+        &transliterations,
+        4,
+        |buf, &(cp, ascii)| {
+            _ = write!(buf, "('\\u{{{cp:x}}}', b'{}')", (ascii as char).escape_default())
+        },
+    );
+    buf.push_str("// END: Transliterations\n");
+    Ok(())
+}
+
+/// Parses a line of the form `<U00C0> <U0041>` or `<U2033> "<U2032><U2032>";"<U00B4><U00B4>"`
+/// into the char and its alternatives, in order of preference.
+fn parse_translit_line(line: &str) -> Option<(u32, Vec<Vec<u32>>)> {
+    let line = line.split('%').next()?;
+    let (cp, rest) = line.strip_prefix("<U")?.split_once('>')?;
+    let cp = u32::from_str_radix(cp, 16).ok()?;
+    let alts = rest
+        .trim()
+        .split(';')
+        .map(|alt| {
+            alt.trim()
+                .trim_matches('"')
+                .split_terminator('>')
+                .map(|u| u.strip_prefix("<U").and_then(|u| u32::from_str_radix(u, 16).ok()))
+                .collect::<Option<Vec<_>>>()
+        })
+        .collect::<Option<_>>()?;
+    Some((cp, alts))
+}
+
+/// Returns the first alternative for `cp` that is a single printable ASCII char.
+/// Alternatives that are a single non-ASCII char are transliterated in turn,
+/// like U+2032 PRIME, which glibc maps to U+00B4 ACUTE ACCENT and that in turn to `'`.
+fn resolve_translit(alternatives: &Translits, cp: u32, depth: usize) -> Option<u8> {
+    if depth > 4 {
+        return None;
+    }
+    alternatives.get(&cp)?.iter().find_map(|alt| match alt[..] {
+        [c @ 0x20..=0x7e] => Some(c as u8),
+        [c] if c >= 0x80 => resolve_translit(alternatives, c, depth + 1),
+        _ => None,
+    })
+}
+
+fn generate_normalization(
+    buf: &mut String,
+    date: &str,
+    doc: &roxmltree::Document,
+) -> anyhow::Result<()> {
+    let (description, repertoire) = ucd_repertoire(doc)?;
+    let mut ccc = vec![0u8; 0x110000];
+    let mut decompositions = Vec::new();
+    let mut exclusions = HashSet::new();
+
+    for group in repertoire.children().filter(|n| n.is_element()) {
+        for char in group.children().filter(|n| n.is_element()) {
+            let attr = |name| char.attribute(name).or_else(|| group.attribute(name));
+            let range = extract_range(&char);
+
+            let class: u8 = attr("ccc").unwrap_or("0").parse()?;
+            ccc[range.clone()].fill(class);
+
+            if attr("Comp_Ex") == Some("Y") {
+                exclusions.extend(range.clone().map(|cp| cp as u32));
+            }
+
+            // Hangul syllables are decomposed algorithmically. "#" refers to the char itself.
+            let dm = attr("dm").unwrap_or("#");
+            if attr("dt") != Some("can") || dm == "#" || (0xac00..=0xd7a3).contains(range.start()) {
+                continue;
+            }
+
+            let dm = dm
+                .split(' ')
+                .map(|cp| u32::from_str_radix(cp, 16))
+                .collect::<Result<Vec<_>, _>>()?;
+            let (first, second) = match dm[..] {
+                [first] => (first, 0),
+                [first, second] => (first, second),
+                _ => bail!("unexpected canonical decomposition for U+{:04X}", range.start()),
+            };
+            for cp in range {
+                decompositions.push((cp as u32, first, second));
+            }
+        }
+    }
+
+    decompositions.sort_unstable();
+
+    let mut compositions: Vec<_> = decompositions
+        .iter()
+        .filter(|&&(cp, _, second)| second != 0 && !exclusions.contains(&cp))
+        .map(|&(cp, first, second)| (first, second, cp))
+        .collect();
+    compositions.sort_unstable();
+
+    let mut classes: Vec<(u32, u32, u8)> = Vec::new();
+    for (cp, &class) in ccc.iter().enumerate() {
+        let cp = cp as u32;
+        match classes.last_mut() {
+            _ if class == 0 => {}
+            Some((_, last, c)) if *last + 1 == cp && *c == class => *last = cp,
+            _ => classes.push((cp, cp, class)),
+        }
+    }
+
+    let mut starters: Vec<u32> = compositions
+        .iter()
+        .map(|&(_, second, _)| second)
+        .filter(|&second| ccc[second as usize] == 0)
+        .chain(
+            decompositions
+                .iter()
+                .filter(|&&(cp, first, _)| ccc[cp as usize] == 0 && ccc[first as usize] != 0)
+                .map(|&(cp, _, _)| cp),
+        )
+        .collect();
+    starters.sort_unstable();
+    starters.dedup();
+
+    _ = writeln!(
+        buf,
+        "// BEGIN: Generated by grapheme-table-gen on {date}, from {description}, with --icu-tables"
+    );
+    write_table(
+        buf,
+        "\
+/// The canonical decompositions as `(char, first, second)`, sorted by `char`. `second` is 0
+/// for singleton decompositions. Hangul syllables are decomposed algorithmically instead.
+",
+        "CANONICAL_DECOMPOSITIONS",
+        "(u32, u32, u32)",
+        &decompositions,
+        4,
+        |buf, (cp, first, second)| _ = write!(buf, "(0x{cp:04x}, 0x{first:04x}, 0x{second:04x})"),
+    );
+    buf.push('\n');
+    write_table(
+        buf,
+        "\
+/// The canonical compositions as `(first, second, composite)`, sorted by `first` and `second`.
+/// These are the canonical decompositions of two chars, minus the composition exclusions.
+",
+        "CANONICAL_COMPOSITIONS",
+        "(u32, u32, u32)",
+        &compositions,
+        4,
+        |buf, (first, second, cp)| _ = write!(buf, "(0x{first:04x}, 0x{second:04x}, 0x{cp:04x})"),
+    );
+    buf.push('\n');
+    write_table(
+        buf,
+        "\
+/// The non-zero canonical combining classes as `(first, last, class)` ranges, sorted by `first`.
+",
+        "CANONICAL_COMBINING_CLASSES",
+        "(u32, u32, u8)",
+        &classes,
+        4,
+        |buf, (first, last, class)| _ = write!(buf, "(0x{first:04x}, 0x{last:04x}, {class})"),
+    );
+    buf.push('\n');
+    write_table(
+        buf,
+        "\
+/// The chars with a combining class of 0 that still interact with the preceding char during
+/// normalization: Either they compose with it, or they decompose into non-starters.
+/// Hangul jamo are handled algorithmically instead.
+",
+        "COMBINING_STARTERS",
+        "u32",
+        &starters,
+        8,
+        |buf, cp| _ = write!(buf, "0x{cp:04x}"),
+    );
+    buf.push_str("// END: Normalization\n");
+    Ok(())
+}
+
+fn generate_case_folding(
+    buf: &mut String,
+    date: &str,
+    doc: &roxmltree::Document,
+) -> anyhow::Result<()> {
+    let (description, repertoire) = ucd_repertoire(doc)?;
+    let mut exceptions = Vec::new();
+
+    for group in repertoire.children().filter(|n| n.is_element()) {
+        for char in group.children().filter(|n| n.is_element()) {
+            let attr = |name| char.attribute(name).or_else(|| group.attribute(name));
+            // Cherokee folds to uppercase and is handled algorithmically instead.
+            if attr("sc") == Some("Cher") {
+                continue;
+            }
+
+            // `cf` is the full case folding from CaseFolding.txt and `lc` the full lowercase
+            // mapping, which is what `char::to_lowercase` returns. "#" refers to the char itself.
+            let (cf, lc) = (attr("cf").unwrap_or("#"), attr("lc").unwrap_or("#"));
+            if cf == lc {
+                continue;
+            }
+
+            for cp in extract_range(&char) {
+                let resolve = |mapping: &str| -> anyhow::Result<String> {
+                    if mapping == "#" {
+                        return char::from_u32(cp as u32).map(String::from).context("invalid char");
+                    }
+                    mapping
+                        .split(' ')
+                        .map(|c| u32::from_str_radix(c, 16).ok().and_then(char::from_u32))
+                        .collect::<Option<_>>()
+                        .with_context(|| format!("invalid mapping for U+{cp:04X}: {mapping}"))
+                };
+                let folded = resolve(cf)?;
+                if folded != resolve(lc)? {
+                    exceptions.push((cp as u32, folded));
+                }
+            }
+        }
+    }
+
+    exceptions.sort_unstable();
+
+    _ = writeln!(
+        buf,
+        "// BEGIN: Generated by grapheme-table-gen on {date}, from {description}, with --icu-tables"
+    );
+    write_table(
+        buf,
+        "\
+/// All characters (except for Cherokee) whose case folding differs from [`char::to_lowercase`].
+",
+        "CASE_FOLDING_EXCEPTIONS",
+        "(char, &str)",
+        &exceptions,
+        4,
+        |buf, (cp, folded)| {
+            _ = write!(buf, "('\\u{{{cp:x}}}', \"");
+            for c in folded.chars() {
+                match c {
+                    ' '..='~' if c != '"' && c != '\\' => buf.push(c),
+                    _ => _ = write!(buf, "\\u{{{:x}}}", c as u32),
+                }
+            }
+            buf.push_str("\")");
+        },
+    );
+    buf.push_str("// END: Case folding\n");
+    Ok(())
+}
+
+/// Returns the description of the UCD, such as "Unicode 16.0.0", and its repertoire element.
+fn ucd_repertoire<'a, 'input>(
+    doc: &'a roxmltree::Document<'input>,
+) -> anyhow::Result<(&'a str, roxmltree::Node<'a, 'input>)> {
+    let ns = "http://www.unicode.org/ns/2003/ucd/1.0";
+    let root = doc.root_element();
+    let description = root
+        .children()
+        .find(|n| n.has_tag_name((ns, "description")))
+        .context("missing ucd description")?;
+    let repertoire = root
+        .children()
+        .find(|n| n.has_tag_name((ns, "repertoire")))
+        .context("missing ucd repertoire")?;
+    Ok((description.text().unwrap_or_default(), repertoire))
+}
+
+fn write_table<T>(
+    buf: &mut String,
+    doc: &str,
+    name: &str,
+    ty: &str,
+    values: &[T],
+    per_line: usize,
+    mut write_value: impl FnMut(&mut String, &T),
+) {
+    buf.push_str(doc);
+    _ = writeln!(buf, "#[rustfmt::skip]\npub static {name}: [{ty}; {}] = [", values.len());
+    for line in values.chunks(per_line) {
+        buf.push_str("   ");
+        for value in line {
+            buf.push(' ');
+            write_value(buf, value);
+            buf.push(',');
+        }
+        buf.push('\n');
+    }
+    buf.push_str("];\n");
+}
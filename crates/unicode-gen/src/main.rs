@@ -1,6 +1,7 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+mod icu;
 mod rules;
 
 use std::collections::HashMap;
@@ -247,6 +248,9 @@ Usage: grapheme-table-gen [options...] <ucd.nounihan.grouped.xml>
                         Expose tab and linefeed as grapheme cluster properties
   --no-ambiguous        Treat all ambiguous characters as narrow
   --line-breaks         Store and expose line break information
+  --icu-tables=<dir>    Generate the tables of the edit crate's icu module instead.
+                        <dir> is glibc's localedata directory with uncompressed charmaps
+                        and its locales
 
 Download ucd.nounihan.grouped.xml at:
   https://www.unicode.org/Public/UCD/latest/ucdxml/ucd.nounihan.grouped.zip
@@ -259,6 +263,10 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
+    if let Some(localedata) = args.opt_value_from_str::<_, PathBuf>("--icu-tables")? {
+        return icu::main(args, &localedata);
+    }
+
     let mut out = Output {
         arg_lang: args.value_from_fn("--lang", |arg| match arg {
             "c" => Ok(Language::C),